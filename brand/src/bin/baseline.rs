@@ -8,7 +8,7 @@ use std::io::Write;
 use std::os::unix::fs::DirBuilderExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use common::*;
 use helios_build_utils::*;
@@ -38,7 +38,7 @@ fn profile_link(root: &Path, name: &str, target: &str) -> Result<()> {
         f.push("etc");
         f.push("svc");
         f.push("profile");
-        f.push(&format!("{name}.xml"));
+        f.push(format!("{name}.xml"));
         f
     };
 
@@ -48,10 +48,232 @@ fn profile_link(root: &Path, name: &str, target: &str) -> Result<()> {
     Ok(())
 }
 
+/**
+ * Paths within the "/usr", "/lib", and "/sbin" trees are generally not shipped
+ * in the baseline archive.  They are either replicated from the global zone at
+ * install time, or provided by an OS archive.  A few parts of "/lib" are
+ * specific to the zone and are shipped in the baseline instead.
+ */
+const BASELINE_LIB: &[&str] = &["lib/svc/seed", "lib/svc/manifest"];
+
+fn os_path(p: &Path) -> bool {
+    p.starts_with("usr")
+        || p.starts_with("sbin")
+        || (p.starts_with("lib")
+            && !BASELINE_LIB.iter().any(|b| p.starts_with(b)))
+}
+
+/**
+ * Is this directory an ancestor of content shipped in the baseline; e.g.,
+ * "lib/svc"?  Such directories must appear in both the OS and the baseline
+ * archive.
+ */
+fn baseline_ancestor(p: &Path) -> bool {
+    BASELINE_LIB.iter().any(|b| Path::new(b).starts_with(p))
+}
+
+/**
+ * The entries destined for one output archive, keyed by their path relative to
+ * the image root.  They are grouped by type so that they can be written in an
+ * order that allows for extraction in a single pass.
+ */
+#[derive(Default)]
+struct ArchiveEntries {
+    dirs: Vec<(PathBuf, tar::Header)>,
+    files: BTreeMap<PathBuf, tar::Header>,
+    hardlinks: BTreeMap<PathBuf, tar::Header>,
+    symlinks: BTreeMap<PathBuf, tar::Header>,
+}
+
+impl ArchiveEntries {
+    /**
+     * Split off the entries that belong in an OS archive, leaving the rest for
+     * the baseline archive.
+     */
+    fn split_os(self) -> Result<(ArchiveEntries, ArchiveEntries)> {
+        let mut base = ArchiveEntries::default();
+        let mut os = ArchiveEntries::default();
+
+        for (p, h) in self.dirs {
+            if os_path(&p) {
+                if baseline_ancestor(&p) {
+                    base.dirs.push((p.clone(), h.clone()));
+                }
+                os.dirs.push((p, h));
+            } else {
+                base.dirs.push((p, h));
+            }
+        }
+
+        for (p, h) in self.hardlinks {
+            /*
+             * A hard link can only be extracted if the file it refers to is in
+             * the same archive.
+             */
+            let target = h.link_name()?.ok_or_else(|| anyhow!("no target"))?;
+            let target = tree::unprefix(Path::new("root"), &target)?;
+            if os_path(&p) != os_path(&target) {
+                bail!("hardlink {p:?} -> {target:?} crosses OS archive");
+            }
+
+            let ae = if os_path(&p) { &mut os } else { &mut base };
+            ae.hardlinks.insert(p, h);
+        }
+
+        for (p, h) in self.files {
+            let ae = if os_path(&p) { &mut os } else { &mut base };
+            ae.files.insert(p, h);
+        }
+
+        for (p, h) in self.symlinks {
+            let ae = if os_path(&p) { &mut os } else { &mut base };
+            ae.symlinks.insert(p, h);
+        }
+
+        Ok((base, os))
+    }
+
+    /**
+     * Write these entries, with contents drawn from the image at "root", into
+     * a new compressed archive of the specified type.  If a timestamp is
+     * provided, it replaces the modification time of every entry so that the
+     * archive depends only on the contents of the image.
+     */
+    fn write(
+        &self,
+        root: &Path,
+        archive_type: metadata::ArchiveType,
+        provenance: &metadata::Provenance,
+        epoch: Option<u64>,
+        compression: compress::Compression,
+        out: &Path,
+    ) -> Result<()> {
+        println!("creating archive {out:?} ({compression})...");
+
+        maybe_unlink(out)?;
+        let f = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(out)?;
+        let mut tar = tar::Builder::new(compression.encoder(f)?);
+        let stamp = |h: &tar::Header| {
+            let mut h = h.clone();
+            if let Some(epoch) = epoch {
+                h.set_mtime(epoch);
+                h.set_cksum();
+            }
+            h
+        };
+
+        /*
+         * Record every entry we are about to write in the metadata manifest,
+         * along with a digest of the contents of each regular file, so that
+         * the brand can detect a damaged archive at install time.
+         */
+        println!("computing file digests...");
+        let mut mb = metadata::MetadataBuilder::new(archive_type);
+        mb.with_manifest();
+        mb.provenance(provenance.clone())?;
+        for (p, h) in self
+            .dirs
+            .iter()
+            .map(|(p, h)| (p, h))
+            .chain(self.hardlinks.iter())
+            .chain(self.symlinks.iter())
+        {
+            let key =
+                p.to_str().ok_or_else(|| anyhow!("path {p:?} not UTF-8"))?;
+            mb.entry(key, metadata::ManifestEntry::from_header(h, None)?)?;
+        }
+        for (p, h) in self.files.iter() {
+            let mut fullpath = root.to_path_buf();
+            fullpath.push(p);
+            let digest =
+                metadata::sha256_digest(std::fs::File::open(&fullpath)?)?;
+            let key =
+                p.to_str().ok_or_else(|| anyhow!("path {p:?} not UTF-8"))?;
+            mb.entry(
+                key,
+                metadata::ManifestEntry::from_header(h, Some(digest))?,
+            )?;
+        }
+
+        /*
+         * Insert our metadata record as a regular file entry at the top of the
+         * archive.  This file will not be extracted into the file system, but
+         * will be read when inspecting the image to see if we understand the
+         * format.
+         */
+        let md = mb.build()?;
+        match epoch {
+            Some(epoch) => md.append_to_tar_with_mtime(&mut tar, epoch)?,
+            None => md.append_to_tar(&mut tar)?,
+        }
+
+        /*
+         * Directories come first, in the order we found them, so that each one
+         * is created before anything within it.
+         */
+        for (_, h) in self.dirs.iter() {
+            tar.append(&stamp(h), std::io::empty())?;
+        }
+
+        /*
+         * Once all directories have been included in the archive, we can then
+         * include files as we can be sure the directory in which they reside
+         * has been properly created.
+         */
+        println!("finishing archive...");
+        for (p, h) in self.files.iter() {
+            let mut fullpath = root.to_path_buf();
+            fullpath.push(p);
+            let f = std::fs::File::open(&fullpath)?;
+            tar.append(&stamp(h), f)?;
+        }
+        /*
+         * After files comes hardlinks, as these links refer to existing files
+         * we have already unpacked and need to exist at link(2) time.
+         */
+        for (_, h) in self.hardlinks.iter() {
+            tar.append(&stamp(h), std::io::empty())?;
+        }
+        /*
+         * Finally, include any symbolic links in the archive.
+         */
+        for (_, h) in self.symlinks.iter() {
+            tar.append(&stamp(h), std::io::empty())?;
+        }
+
+        let mut f = tar.into_inner()?.finish()?;
+        f.flush()?;
+
+        Ok(())
+    }
+}
+
 fn main() -> Result<()> {
     let mut opts = getopts::Options::new();
     opts.optopt("R", "", "target image", "PATH");
     opts.optflag("w", "", "wait for IPS repositories to be contactable");
+    opts.optflag(
+        "O",
+        "",
+        "also create an OS archive of /usr, /lib, and /sbin",
+    );
+    opts.optopt(
+        "T",
+        "",
+        "use this timestamp for all archive entries (default: \
+        SOURCE_DATE_EPOCH, if set)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "C",
+        "",
+        "archive compression; e.g., gzip:9 (default), zstd:19, xz, or none",
+        "CODEC[:LEVEL]",
+    );
 
     let mat = opts.parse(std::env::args().skip(1))?;
 
@@ -60,6 +282,28 @@ fn main() -> Result<()> {
     }
 
     let src = mat.opt_str("R").map(PathBuf::from);
+    let os_mode = mat.opt_present("O");
+
+    /*
+     * If we have been given a timestamp, either explicitly or through the
+     * environment, produce reproducible archives: every entry will carry that
+     * timestamp in place of the modification time in the image.
+     */
+    let epoch = match mat.opt_str("T") {
+        Some(t) => Some(
+            t.parse::<u64>()
+                .map_err(|_| anyhow!("invalid timestamp {t:?}"))?,
+        ),
+        None => metadata::source_date_epoch()?,
+    };
+    if let Some(epoch) = epoch {
+        println!("reproducible archives with timestamp {epoch}");
+    }
+
+    let compression = match mat.opt_str("C") {
+        Some(c) => c.parse::<compress::Compression>()?,
+        None => Default::default(),
+    };
 
     if mat.opt_present("w") {
         /*
@@ -110,7 +354,7 @@ fn main() -> Result<()> {
 
     let incorp = packages
         .iter()
-        .filter(|p| p.name() == "consolidation/osnet/osnet-incorporation")
+        .filter(|p| p.name() == pkg::OSNET_INCORPORATION)
         .collect::<Vec<_>>();
     if incorp.len() != 1 {
         bail!(
@@ -174,8 +418,7 @@ fn main() -> Result<()> {
             let contents = packages
                 .iter()
                 .filter(|p| {
-                    p.name() != "entire"
-                        && p.name() != "consolidation/osnet/osnet-incorporation"
+                    p.name() != "entire" && p.name() != pkg::OSNET_INCORPORATION
                 })
                 .map(|p| {
                     Ok((p.clone(), pkg::pkg_contents(Some(src), Some(p))?))
@@ -284,18 +527,38 @@ fn main() -> Result<()> {
         },
     )?;
 
-    /*
-     * Tell IPS that we do not wish to include files under /usr, /sbin, or most
-     * of /lib, in the resultant image:
-     */
-    println!("adding properties...");
-    for pat in &["usr/", "sbin/", "lib/(?!svc/seed|svc/manifest)"] {
-        pkg::pkg_add_property_value(im, "exclude-patterns", pat)?;
+    if !os_mode {
+        /*
+         * Tell IPS that we do not wish to include files under /usr, /sbin, or
+         * most of /lib, in the resultant image:
+         */
+        println!("adding properties...");
+        for pat in &["usr/", "sbin/", "lib/(?!svc/seed|svc/manifest)"] {
+            pkg::pkg_add_property_value(im, "exclude-patterns", pat)?;
+        }
     }
 
     println!("installing packages...");
     pkg::pkg_exact_install(im, to_install.as_slice())?;
 
+    /*
+     * Record where the contents of the image came from, so that the brand can
+     * check that the baseline matches the system on which it is used.
+     */
+    println!("recording provenance...");
+    let provenance = metadata::Provenance {
+        incorporation: incorp[0].to_string(),
+        packages: pkg::pkg_list(im)?.iter().map(|p| p.to_string()).collect(),
+        publishers: pkg::pkg_publishers(im)?
+            .into_iter()
+            .map(|(name, origin)| metadata::Publisher { name, origin })
+            .collect(),
+        generated: match epoch {
+            Some(epoch) => epoch,
+            None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        },
+    };
+
     println!("seeding SMF database...");
     let repodb = {
         let mut f = root.clone();
@@ -396,11 +659,24 @@ fn main() -> Result<()> {
     };
     let group = unix::Group::load(groupf)?;
 
-    let out_tar = {
+    /*
+     * The archive file names reflect the compression in use.  Remove any
+     * archives left over from a previous run with a different compression,
+     * so that the brand does not find a stale one.
+     */
+    let archive_path = |name: &str, codec: compress::Codec| {
         let mut f = dir.clone();
-        f.push("files.tar.gz");
+        f.push(format!("{name}.tar{}", codec.extension()));
         f
     };
+    for &codec in compress::Codec::all() {
+        maybe_unlink(&archive_path("files", codec))?;
+        maybe_unlink(&archive_path("os", codec))?;
+        maybe_unlink(&signature::signature_path(archive_path("files", codec)))?;
+        maybe_unlink(&signature::signature_path(archive_path("os", codec)))?;
+    }
+    let out_tar = archive_path("files", compression.codec());
+    let out_os = archive_path("os", compression.codec());
     let out_gzonly = {
         let mut f = dir.clone();
         f.push("gzonly.txt");
@@ -506,26 +782,7 @@ fn main() -> Result<()> {
         );
     }
 
-    println!("creating archive...");
-
-    maybe_unlink(&out_tar)?;
-    let f = std::fs::OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(&out_tar)?;
-    let gzw = flate2::write::GzEncoder::new(f, flate2::Compression::best());
-    let mut tar = tar::Builder::new(gzw);
-
-    /*
-     * Insert our metadata record as a regular file entry at the top of the
-     * archive.  This file will not be extracted into the file system, but will
-     * be read when inspecting the image to see if we understand the format.
-     */
-    metadata::MetadataBuilder::new(metadata::ArchiveType::Baseline)
-        .build()?
-        .append_to_tar(&mut tar)?;
-
+    println!("assessing image contents...");
     let mut found: BTreeMap<PathBuf, EntryType> = Default::default();
     let mut walk = walkdir::WalkDir::new(&root).min_depth(1).into_iter();
     while let Some(ent) = walk
@@ -556,9 +813,7 @@ fn main() -> Result<()> {
         }
     }
 
-    let mut tar_hardlinks: BTreeMap<PathBuf, tar::Header> = Default::default();
-    let mut tar_symlinks: BTreeMap<PathBuf, tar::Header> = Default::default();
-    let mut tar_files: BTreeMap<PathBuf, tar::Header> = Default::default();
+    let mut entries = ArchiveEntries::default();
     println!("missing from packaging:");
     for (p, et) in found.iter() {
         /*
@@ -629,7 +884,7 @@ fn main() -> Result<()> {
                     h.set_mode(pi.mode);
                     h.set_cksum();
 
-                    tar.append(&h, std::io::empty())?;
+                    entries.dirs.push((p.clone(), h));
                 }
                 EntryType::File => {
                     let mut h = tar::Header::new_ustar();
@@ -641,7 +896,7 @@ fn main() -> Result<()> {
                     h.set_path(&archivepath)?;
                     h.set_mode(pi.mode);
                     h.set_cksum();
-                    tar_files.insert(p.clone(), h);
+                    entries.files.insert(p.clone(), h);
                 }
                 EntryType::Link(target) => {
                     let mut h = tar::Header::new_ustar();
@@ -654,7 +909,7 @@ fn main() -> Result<()> {
                     h.set_link_name(target)?;
                     h.set_path(&archivepath)?;
                     h.set_cksum();
-                    tar_symlinks.insert(p.clone(), h);
+                    entries.symlinks.insert(p.clone(), h);
                 }
                 EntryType::Hardlink(target) => {
                     /*
@@ -676,10 +931,12 @@ fn main() -> Result<()> {
                     h.set_path(&archivepath)?;
                     h.set_mode(0); /* XXX? */
                     h.set_size(0); /* XXX? */
+                    h.set_username("root")?;
                     h.set_uid(0); /* XXX? */
+                    h.set_groupname("root")?;
                     h.set_gid(0); /* XXX? */
                     h.set_cksum();
-                    tar_hardlinks.insert(p.clone(), h);
+                    entries.hardlinks.insert(p.clone(), h);
                 }
             }
         } else {
@@ -702,7 +959,7 @@ fn main() -> Result<()> {
                     h.set_path(&archivepath)?;
                     h.set_cksum();
 
-                    tar.append(&h, std::io::empty())?;
+                    entries.dirs.push((p.clone(), h));
                 }
                 EntryType::File => {
                     let mut h = tar::Header::new_ustar();
@@ -713,7 +970,7 @@ fn main() -> Result<()> {
                     h.set_gid(group.lookup_by_name("sys")?);
                     h.set_path(&archivepath)?;
                     h.set_cksum();
-                    tar_files.insert(p.clone(), h);
+                    entries.files.insert(p.clone(), h);
                 }
                 EntryType::Link(target) => {
                     let mut h = tar::Header::new_ustar();
@@ -726,7 +983,7 @@ fn main() -> Result<()> {
                     h.set_link_name(target)?;
                     h.set_path(&archivepath)?;
                     h.set_cksum();
-                    tar_symlinks.insert(p.clone(), h);
+                    entries.symlinks.insert(p.clone(), h);
                 }
                 x => bail!("unexpected file type found {:?}, {:?}", p, x),
             }
@@ -736,12 +993,7 @@ fn main() -> Result<()> {
     let mut header = false;
     let mut fail = false;
     for (p, _i) in packaged.iter() {
-        if p.starts_with("usr")
-            || p.starts_with("sbin")
-            || (p.starts_with("lib")
-                && !(p.starts_with("lib/svc/seed")
-                    || p.starts_with("lib/svc/manifest")))
-        {
+        if !os_mode && os_path(p) {
             /*
              * Ignore these trees.  They will come from the ramdisk at zone
              * install time.  We asked IPS not to include them in the baseline
@@ -769,35 +1021,34 @@ fn main() -> Result<()> {
         bail!("cannot proceed due to issues with the proto area");
     }
 
-    /*
-     * Once all directories have been included in the archive, we can then
-     * include files as we can be sure the directory in which they reside has
-     * been properly created.
-     */
-    println!("finishing archive...");
-    for (p, h) in tar_files.iter() {
-        let mut fullpath = root.clone();
-        fullpath.push(p);
-        let f = std::fs::File::open(&fullpath)?;
-        tar.append(h, f)?;
-    }
-    /*
-     * After files comes hardlinks, as these links refer to existing files we
-     * have already unpacked and need to exist at link(2) time.
-     */
-    for (_, h) in tar_hardlinks.iter() {
-        tar.append(h, std::io::empty())?;
+    if os_mode {
+        let (base, os) = entries.split_os()?;
+        os.write(
+            &root,
+            metadata::ArchiveType::Os,
+            &provenance,
+            epoch,
+            compression,
+            &out_os,
+        )?;
+        base.write(
+            &root,
+            metadata::ArchiveType::Baseline,
+            &provenance,
+            epoch,
+            compression,
+            &out_tar,
+        )?;
+    } else {
+        entries.write(
+            &root,
+            metadata::ArchiveType::Baseline,
+            &provenance,
+            epoch,
+            compression,
+            &out_tar,
+        )?;
     }
-    /*
-     * Finally, include any symbolic links in the archive.
-     */
-    for (_, h) in tar_symlinks.iter() {
-        tar.append(h, std::io::empty())?;
-    }
-
-    let gzw = tar.into_inner()?;
-    let mut f = gzw.finish()?;
-    f.flush()?;
 
     println!("creating gzonly manifest...");
     maybe_unlink(&out_gzonly)?;
@@ -825,3 +1076,122 @@ fn main() -> Result<()> {
     println!("ok");
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(entry_type: tar::EntryType, link: Option<&str>) -> tar::Header {
+        let mut h = tar::Header::new_ustar();
+        h.set_entry_type(entry_type);
+        if let Some(link) = link {
+            h.set_link_name(link).unwrap();
+        }
+        h
+    }
+
+    fn entries(
+        dirs: &[&str],
+        files: &[&str],
+        symlinks: &[&str],
+        hardlinks: &[(&str, &str)],
+    ) -> ArchiveEntries {
+        let mut ae = ArchiveEntries::default();
+        for p in dirs {
+            ae.dirs
+                .push((p.into(), header(tar::EntryType::Directory, None)));
+        }
+        for p in files {
+            ae.files
+                .insert(p.into(), header(tar::EntryType::Regular, None));
+        }
+        for p in symlinks {
+            ae.symlinks.insert(
+                p.into(),
+                header(tar::EntryType::Symlink, Some("target")),
+            );
+        }
+        for (p, target) in hardlinks {
+            ae.hardlinks.insert(
+                p.into(),
+                header(tar::EntryType::Link, Some(&format!("root/{target}"))),
+            );
+        }
+        ae
+    }
+
+    #[test]
+    fn split_os() {
+        let (base, os) = entries(
+            &[
+                "etc",
+                "lib",
+                "lib/svc",
+                "lib/svc/manifest",
+                "lib/svc/seed",
+                "lib/svc/method",
+                "sbin",
+                "usr",
+                "usr/bin",
+            ],
+            &[
+                "etc/motd",
+                "lib/libc.so.1",
+                "lib/svc/seed/global.db",
+                "usr/bin/ls",
+            ],
+            &["etc/issue", "lib/svc/manifest/link.xml", "sbin/sh"],
+            &[("etc/motd.old", "etc/motd"), ("usr/bin/dir", "usr/bin/ls")],
+        )
+        .split_os()
+        .unwrap();
+
+        let dirs = |ae: &ArchiveEntries| {
+            ae.dirs
+                .iter()
+                .map(|(p, _)| p.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let keys = |m: &BTreeMap<PathBuf, tar::Header>| {
+            m.keys()
+                .map(|p| p.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        /*
+         * The directories above the parts of "/lib" that the baseline ships
+         * are in both archives.
+         */
+        assert_eq!(
+            dirs(&base),
+            ["etc", "lib", "lib/svc", "lib/svc/manifest", "lib/svc/seed"],
+        );
+        assert_eq!(
+            dirs(&os),
+            ["lib", "lib/svc", "lib/svc/method", "sbin", "usr", "usr/bin"],
+        );
+
+        assert_eq!(keys(&base.files), ["etc/motd", "lib/svc/seed/global.db"]);
+        assert_eq!(keys(&os.files), ["lib/libc.so.1", "usr/bin/ls"]);
+        assert_eq!(
+            keys(&base.symlinks),
+            ["etc/issue", "lib/svc/manifest/link.xml"],
+        );
+        assert_eq!(keys(&os.symlinks), ["sbin/sh"]);
+        assert_eq!(keys(&base.hardlinks), ["etc/motd.old"]);
+        assert_eq!(keys(&os.hardlinks), ["usr/bin/dir"]);
+
+        /*
+         * A hard link cannot refer to a file in the other archive, in either
+         * direction.
+         */
+        for link in [("usr/bin/motd", "etc/motd"), ("etc/ls", "usr/bin/ls")] {
+            let e = entries(&[], &[], &[], &[link])
+                .split_os()
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(e.contains("crosses OS archive"), "{e}");
+        }
+    }
+}
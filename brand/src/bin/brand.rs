@@ -3,8 +3,8 @@
  */
 
 use std::io::{Read, Write};
-use std::os::unix::fs::DirBuilderExt;
-use std::path::PathBuf;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Instant;
 
@@ -13,6 +13,20 @@ use anyhow::{anyhow, bail, Result};
 use helios_build_utils::*;
 use helios_omicron_brand::*;
 
+/**
+ * The exit status with which a brand install hook reports that the zone was
+ * not installed, and has been left as it was.  See zoneadm(8).
+ */
+const ZONE_SUBPROC_NOTCOMPLETE: i32 = 254;
+
+const ROOT_DIR: &str = "root";
+/**
+ * The zone root is assembled here during install, and only renamed to "root"
+ * once it is complete.  Anything found at this path was left behind by an
+ * install or uninstall that did not finish, and may be discarded.
+ */
+const STAGING_DIR: &str = "root.staging";
+
 #[allow(unused)]
 mod ids {
     pub const ROOT: u32 = 0;
@@ -76,13 +90,7 @@ struct Stuff {
 
 impl Stuff {
     fn zoneroot(&self) -> PathBuf {
-        self.otherdir("root")
-    }
-
-    fn zonerootpath(&self, names: &[&str]) -> PathBuf {
-        let mut dir = self.zoneroot();
-        names.iter().for_each(|name| dir.push(name));
-        dir
+        self.otherdir(ROOT_DIR)
     }
 
     fn otherdir(&self, name: &str) -> PathBuf {
@@ -91,29 +99,51 @@ impl Stuff {
         dir
     }
 
-    fn baseline(&self, name: &str) -> Result<PathBuf> {
-        const DIRS: &[&str] = &[
-            "/var/run/brand/omicron1/baseline",
-            "/usr/lib/brand/omicron1/baseline",
-        ];
+    /**
+     * Locate a baseline archive, which may be compressed in any supported
+     * format; e.g., "files.tar.gz" or "files.tar.zst" for "files".
+     */
+    fn baseline_archive(&self, name: &str) -> Result<PathBuf> {
+        baseline_any(
+            &compress::Codec::all()
+                .iter()
+                .map(|c| format!("{name}.tar{}", c.extension()))
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+fn baseline_any(names: &[String]) -> Result<PathBuf> {
+    const DIRS: &[&str] = &[
+        "/var/run/brand/omicron1/baseline",
+        "/usr/lib/brand/omicron1/baseline",
+    ];
 
-        for &dir in DIRS {
+    for &dir in DIRS {
+        for name in names {
             let mut p = PathBuf::from(dir);
             p.push(name);
             if p.exists() {
                 return Ok(p);
             }
         }
-
-        bail!("could not locate {:?} in any baseline directory", name);
     }
+
+    bail!(
+        "could not locate {} in any baseline directory",
+        names
+            .iter()
+            .map(|n| format!("{n:?}"))
+            .collect::<Vec<_>>()
+            .join(" or "),
+    );
 }
 
 fn debug_from_env() -> bool {
-    match std::env::var("DEBUG_OMICRON_BRAND") {
-        Ok(val) if val == "1" || val == "true" || val == "yes" => true,
-        _ => false,
-    }
+    matches!(
+        std::env::var("DEBUG_OMICRON_BRAND").as_deref(),
+        Ok("1" | "true" | "yes")
+    )
 }
 
 fn mkstuff(m: &getopts::Matches) -> Result<Stuff> {
@@ -198,112 +228,512 @@ fn cmd_verify_cfg(args: &mut dyn Iterator<Item = &String>) -> Result<()> {
     Ok(())
 }
 
-fn cmd_install(
-    s: Stuff,
-    args: &mut dyn Iterator<Item = &String>,
-) -> Result<()> {
-    let mut opts = getopts::Options::new();
-    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
-    let mat = opts.parse(args)?;
+/**
+ * Compare the OS incorporation recorded in the baseline archive with the one
+ * for the software that the zone will run: either the OS archive, if one was
+ * provided, or the running system.  Returns a description of the problem if
+ * they do not match.
+ */
+fn baseline_mismatch(
+    baseline: &metadata::Metadata,
+    os: Option<&metadata::Metadata>,
+) -> Result<Option<String>> {
+    let Some(bp) = baseline.provenance() else {
+        return Ok(Some("baseline archive does not record provenance".into()));
+    };
 
-    println!(
-        "INFO: omicron: installing zone {} @ {:?}...",
-        s.zone, s.zonepath,
-    );
+    let (what, current) = if let Some(os) = os {
+        let Some(op) = os.provenance() else {
+            return Ok(Some("OS archive does not record provenance".into()));
+        };
+        ("OS archive", ips::Package::parse_fmri(&op.incorporation)?)
+    } else {
+        let incorp = pkg::pkg_list(pkg::ROOT_IMAGE)?
+            .into_iter()
+            .filter(|p| p.name() == pkg::OSNET_INCORPORATION)
+            .collect::<Vec<_>>();
+        let [incorp] = incorp.as_slice() else {
+            bail!("could not find single osnet-incorporation: {incorp:?}");
+        };
+        ("running system", incorp.clone())
+    };
 
     /*
-     * We need to create the "root" directory within the zonepath as part of
-     * installation.
+     * The publisher may legitimately differ, but the version and timestamp
+     * must match exactly.
      */
-    let root = s.zoneroot();
-    std::fs::DirBuilder::new().mode(0o755).create(&root)?;
-    unix::lchown(&root, ROOT, ROOT)?;
+    let recorded = ips::Package::parse_fmri(&bp.incorporation)?;
+    if recorded.version() == current.version()
+        && recorded.date() == current.date()
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "baseline archive was generated for {recorded}, but the {what} has \
+        {current}; the baseline archive should be regenerated"
+    )))
+}
+
+/**
+ * Apply the BASELINE_POLICY to any mismatch between the baseline archive and
+ * the software the zone will run.  Returns a warning to print if the install
+ * should go ahead regardless.
+ */
+fn check_baseline(
+    policy: defaults::Policy,
+    baseline: &metadata::Metadata,
+    os: Option<&metadata::Metadata>,
+) -> Result<Option<String>> {
+    use defaults::Policy;
+
+    if policy == Policy::Off {
+        return Ok(None);
+    }
+
+    let problem = match baseline_mismatch(baseline, os) {
+        Ok(None) => return Ok(None),
+        Ok(Some(problem)) => problem,
+        Err(e) => format!("could not check baseline archive: {e}"),
+    };
 
-    for repl in ["usr", "lib", "sbin"] {
+    if policy == Policy::Enforce {
+        bail!("{problem}");
+    }
+    Ok(Some(problem))
+}
+
+/**
+ * Open the shared store for replicating into a zone root, if one is
+ * configured and it is on the same file system as the zone root.  The store
+ * is locked against collection until it is dropped.
+ */
+fn open_store(root: &Path) -> Result<Option<store::Store>> {
+    let Some(st) = store::Store::from_defaults(store::Lock::Shared)? else {
+        return Ok(None);
+    };
+
+    if st.dev()? != std::fs::metadata(root)?.dev() {
+        println!(
+            "WARNING: omicron: store {:?} is not on the same file system \
+            as the zone root; copying files instead",
+            st.path(),
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(st))
+}
+
+/**
+ * Populate /usr, /lib, and /sbin in the zone root from the global zone,
+ * leaving out anything that should not be present in a non-global zone.
+ */
+fn replicate_global(s: &Stuff, root: &Path) -> Result<()> {
+    let rules = replicate::Rules::from_defaults()?;
+    let exclude = replication_exclude()?;
+
+    let mut store = open_store(root)?;
+
+    for repl in replica::TREES {
         let tree = format!("/{repl}");
         println!("INFO: omicron: replicating {tree} tree...");
 
-        let dir = s.zonerootpath(&[repl]);
+        let dir = root.join(repl);
         std::fs::DirBuilder::new().mode(0o755).create(&dir)?;
         unix::lchown(&dir, ROOT, SYS)?;
 
+        let prefix = format!("/system/{repl}");
         let start = Instant::now();
-        let copyq::CopyStats { files, bytes } =
-            tree::replicate(&tree, &dir, &format!("/system/{repl}"))?;
+        let copyq::CopyStats {
+            files,
+            bytes,
+            links,
+            saved,
+            pruned,
+        } = tree::replicate(
+            &tree,
+            &dir,
+            &prefix,
+            &rules,
+            &exclude,
+            store.as_mut(),
+        )?;
         let msec = Instant::now().saturating_duration_since(start).as_millis();
 
         println!(
             "INFO: omicron: replicated {tree}: \
-            {files} files, {bytes}, bytes, {msec} msec"
+            {files} files, {bytes}, bytes, {msec} msec; \
+            {links} hard links saved {saved} bytes; \
+            {pruned} global-only objects pruned"
         );
-    }
 
-    {
         /*
-         * For now, just remove all manifests from the global zone and use the
-         * ones from the baseline package.  This will match the preseed database
-         * exactly, and no stragglers will slip through.
+         * Check the ownership, permissions, and modification times of the
+         * replicas before anything else is unpacked over them.
          */
-        println!("INFO: omicron: pruning SMF manifests...");
-        let manifest = s.zonerootpath(&["lib", "svc", "manifest"]);
-        std::fs::remove_dir_all(manifest)?;
+        let mismatches = tree::verify(&tree, &dir, &prefix, &rules, &exclude)?;
+        let shown = if s.debug { mismatches.len() } else { 10 };
+        for m in mismatches.iter().take(shown) {
+            println!("WARNING: omicron: replica {m}");
+        }
+        if !mismatches.is_empty() {
+            println!(
+                "WARNING: omicron: {} replicated objects in {tree} do not \
+                match the global zone",
+                mismatches.len(),
+            );
+        }
+    }
+
+    if let Some(st) = &store {
+        st.save_refs(&s.zone, &s.zonepath)?;
     }
 
+    Ok(())
+}
+
+/**
+ * Determine what to leave out when replicating the global zone, according to
+ * the list of global-zone only files that comes with the baseline archive.
+ */
+fn replication_exclude() -> Result<tree::Exclude> {
+    replica::exclude(
+        Path::new("/"),
+        &baseline_any(&[replica::GZONLY.to_string()])?,
+    )
+}
+
+/**
+ * Determine what replicate_global() would produce in a zone root, given the
+ * current contents of the global zone.
+ */
+fn replication_wants() -> Result<replica::Wants> {
+    replica::wants(
+        Path::new("/"),
+        replica::TREES,
+        &replicate::Rules::from_defaults()?,
+        &replication_exclude()?,
+    )
+}
+
+/**
+ * Populate a new zone root from the OS archive, or the global zone, and then
+ * the baseline archive and any layers.  If the global zone was replicated,
+ * returns a record of the replicated objects, which is taken before anything
+ * else is unpacked.  Also returns a manifest of the finished zone root.
+ */
+fn populate(
+    s: &Stuff,
+    root: &Path,
+    os: Option<(&String, Box<dyn image::Image>)>,
+    baseline: &mut unpack::Unpack,
+    layers: &mut [(&String, Box<dyn image::Image>)],
+    order: &[usize],
+) -> Result<(Option<replica::Record>, manifest::Manifest)> {
     /*
-     * Remove any files that the baseline says are global-zone only.
+     * Each archive is applied to a plan as it is unpacked, so that we know
+     * which archive provided each path for the manifest.
      */
-    println!("INFO: omicron: pruning global-only files...");
-    for l in std::fs::read_to_string(s.baseline("gzonly.txt")?)?.lines() {
-        let mut rm = root.clone();
-        let rel = PathBuf::from(l);
-        if rel.is_absolute() {
-            bail!("absolute path in baseline remove list: {rel:?}");
-        }
-        rm.push(rel);
+    let mut plan = plan::Plan::new();
 
-        if let Err(e) = std::fs::remove_file(&rm) {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                bail!("removing {rm:?}: {e:?}");
-            }
-        } else if s.debug {
-            println!("removed GZ-only file: {rm:?}");
-        }
-    }
+    let replicated = if let Some((path, mut os)) = os {
+        println!("INFO: omicron: unpacking OS archive {path:?}...");
+        let stats = os.unpack(root, Some(&mut plan))?;
+        println!("INFO: omicron: unpacked OS archive: {stats}");
+        None
+    } else {
+        replicate_global(s, root)?;
+        let wants = replication_wants()?;
+        Some((replica::Record::scan(root, &wants)?, wants))
+    };
 
     /*
      * Unpack the baseline archive into the zone root, which will establish the
      * contents of /etc, /var, and /root, and /lib/svc/seed/nonglobal.db:
      */
     println!("INFO: omicron: unpacking baseline archive...");
-    let mut baseline = unpack::Unpack::load(s.baseline("files.tar.gz")?)?;
-    if !baseline.metadata().is_baseline() {
-        bail!("archive is not a baseline archive");
-    }
-    baseline.unpack(&root)?;
+    let stats = baseline.unpack(root, Some(&mut plan))?;
+    println!("INFO: omicron: unpacked baseline archive: {stats}");
 
     /*
      * Unpack any additional archives that were passed on the command line:
      */
-    for extra in mat.free {
-        println!("INFO: omicron: unpacking image {extra:?}...");
-        let mut extra = unpack::Unpack::load(&extra)?;
-        if !extra.metadata().is_layer() {
-            bail!("image is not a layer");
+    for &i in order {
+        let (extra, layer) = &mut layers[i];
+        match layer.metadata().layer_info() {
+            Some(li) => {
+                println!("INFO: omicron: unpacking image {extra:?} ({li})...")
+            }
+            None => println!("INFO: omicron: unpacking image {extra:?}..."),
         }
-        extra.unpack(&root)?;
+        let stats = layer.unpack(root, Some(&mut plan))?;
+        println!("INFO: omicron: unpacked image {extra:?}: {stats}");
     }
 
     /*
      * Copy in configuration files from the global zone.
      */
+    #[allow(clippy::single_element_loop)]
     for cf in ["default/init"] {
         let src = format!("/etc/{cf}");
         println!("INFO: omicron: copying {src}...");
-        let dst = s.zonerootpath(&["etc", cf]);
+        let dst = root.join("etc").join(cf);
         std::fs::remove_file(&dst).ok();
         std::fs::copy(src, dst)?;
     }
 
+    println!("INFO: omicron: writing manifest...");
+    let (archives, origins) = manifest::from_plan(&plan);
+    let manifest = manifest::Manifest::build(
+        root,
+        archives,
+        &origins,
+        replicated.as_ref().map(|(r, w)| (r, w)),
+    )?;
+
+    Ok((replicated.map(|(r, _)| r), manifest))
+}
+
+/**
+ * Forget the files in the shared store that a zone used, if a store is
+ * configured, and remove any files that no zone uses any longer.
+ */
+fn release_store(s: &Stuff) -> Result<()> {
+    let Some(store) = store::Store::from_defaults(store::Lock::Exclusive)?
+    else {
+        return Ok(());
+    };
+
+    store.remove_refs(&s.zone)?;
+    let stats = store.gc()?;
+    println!("INFO: omicron: collected store {:?}: {stats}", store.path());
+    Ok(())
+}
+
+/**
+ * Remove the record of replicated objects and the manifest of the zone root,
+ * if there are any.
+ */
+fn remove_record(zonepath: &Path) -> Result<()> {
+    for name in [replica::RECORD, manifest::MANIFEST] {
+        let path = zonepath.join(name);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => bail!("removing {path:?}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Remove a staging directory left behind by an install or uninstall that did
+ * not finish.
+ */
+fn remove_staging(zonepath: &Path) -> Result<()> {
+    let staging = zonepath.join(STAGING_DIR);
+    if std::fs::symlink_metadata(&staging).is_ok() {
+        println!("INFO: omicron: removing incomplete zone root {staging:?}...");
+        std::fs::remove_dir_all(&staging)
+            .map_err(|e| anyhow!("removing {staging:?}: {e}"))?;
+    }
+    Ok(())
+}
+
+enum Installed {
+    Complete,
+    /**
+     * The install failed, and the zonepath was left as it was.
+     */
+    RolledBack(anyhow::Error),
+}
+
+/**
+ * Assemble a zone root in a staging directory within the zonepath, which is
+ * only renamed to "root" once every step has succeeded.  If anything fails,
+ * we remove the staging directory, and any record or manifest, so that the
+ * zonepath is left as it was.  If that cleaning up fails as well, the
+ * original failure is returned as an error.
+ */
+fn install_root<F>(zonepath: &Path, populate: F) -> Result<Installed>
+where
+    F: FnOnce(&Path) -> Result<(Option<replica::Record>, manifest::Manifest)>,
+{
+    let root = zonepath.join(ROOT_DIR);
+    if std::fs::symlink_metadata(&root).is_ok() {
+        bail!("zone root {root:?} already exists");
+    }
+    remove_staging(zonepath)?;
+    remove_record(zonepath)?;
+
+    let staging = zonepath.join(STAGING_DIR);
+    std::fs::DirBuilder::new().mode(0o755).create(&staging)?;
+    unix::lchown(&staging, ROOT, ROOT)?;
+
+    /*
+     * The record of replicated objects and the manifest are written last, and
+     * atomically, so that they are never left behind incomplete.  If they
+     * cannot be written, or the staging directory cannot be renamed, the
+     * install fails like any other step and they are removed again.
+     */
+    let res = populate(&staging).and_then(|(record, manifest)| {
+        manifest.store(zonepath.join(manifest::MANIFEST))?;
+        if let Some(record) = record {
+            record.store(zonepath.join(replica::RECORD))?;
+        }
+        std::fs::rename(&staging, &root)
+            .map_err(|e| anyhow!("renaming {staging:?} to {root:?}: {e}"))
+    });
+    let Err(e) = res else {
+        return Ok(Installed::Complete);
+    };
+
+    println!("INFO: omicron: install failed; removing {staging:?}...");
+    let cleanup = std::fs::remove_dir_all(&staging)
+        .map_err(|re| anyhow!("could not remove {staging:?}: {re}"))
+        .and_then(|()| remove_record(zonepath));
+    if let Err(re) = cleanup {
+        /*
+         * The zonepath is not clean, so report the failure in the usual way
+         * and let zoneadm(8) mark the zone incomplete.  A forced uninstall
+         * will try again to remove the staging directory and the records.
+         */
+        println!("WARNING: omicron: {re}");
+        return Err(e);
+    }
+
+    Ok(Installed::RolledBack(e))
+}
+
+/**
+ * Remove the zone root, and whatever an install or uninstall that did not
+ * finish left behind.
+ *
+ * The zone root is first moved aside to the staging path, so that if we are
+ * interrupted part way through removing it, what remains is treated as the
+ * leftovers of an incomplete install and removed by the next install or
+ * uninstall.  A zone that failed to install may have only the staging
+ * directory.
+ */
+fn remove_root(zonepath: &Path) -> Result<()> {
+    let root = zonepath.join(ROOT_DIR);
+    remove_staging(zonepath)?;
+    if std::fs::symlink_metadata(&root).is_ok() {
+        let staging = zonepath.join(STAGING_DIR);
+        std::fs::rename(&root, &staging)
+            .map_err(|e| anyhow!("renaming {root:?} to {staging:?}: {e}"))?;
+        std::fs::remove_dir_all(&staging)
+            .map_err(|e| anyhow!("removing {staging:?}: {e}"))?;
+    }
+    remove_record(zonepath)
+}
+
+fn cmd_install(
+    s: Stuff,
+    args: &mut dyn Iterator<Item = &String>,
+) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    opts.optflag("n", "", "report what would be installed, then stop");
+    opts.optflag("j", "", "report the plan as JSON");
+    let mat = opts.parse(args)?;
+    let dry_run = mat.opt_present("n");
+
+    if !dry_run {
+        println!(
+            "INFO: omicron: installing zone {} @ {:?}...",
+            s.zone, s.zonepath,
+        );
+    }
+
+    /*
+     * Load the metadata for any additional archives that were passed on the
+     * command line before we start, so that we can refuse the install early
+     * if the layers are unsuitable.  Layers may declare requirements on one
+     * another, which determine the order in which they are applied.
+     */
+    let mut os = None;
+    let mut layers = Vec::new();
+    for extra in mat.free.iter() {
+        let u = image::open(extra)?;
+        if u.metadata().is_os() {
+            /*
+             * An OS archive provides the contents of /usr, /lib, and /sbin in
+             * place of the files we would otherwise replicate from the global
+             * zone.
+             */
+            if os.is_some() {
+                bail!("only one OS archive may be provided");
+            }
+            os = Some((extra, u));
+        } else if u.metadata().is_layer() {
+            layers.push((extra, u));
+        } else {
+            bail!("image {extra:?} is not a layer or an OS archive");
+        }
+    }
+    let order = metadata::layer_order(
+        &layers
+            .iter()
+            .map(|(path, u)| (path.as_str(), u.metadata()))
+            .collect::<Vec<_>>(),
+    )?;
+
+    let mut baseline = unpack::Unpack::load(s.baseline_archive("files")?)?;
+    if !baseline.metadata().is_baseline() {
+        bail!("archive is not a baseline archive");
+    }
+    let policy = defaults::DefaultsFile::from_path(defaults::BRAND_DEFAULTS)?
+        .get_policy("BASELINE_POLICY")?
+        .unwrap_or(defaults::Policy::Warn);
+    if let Some(problem) = check_baseline(
+        policy,
+        baseline.metadata(),
+        os.as_ref().map(|(_, u)| u.metadata()),
+    )? {
+        println!("WARNING: omicron: {problem}");
+    }
+
+    if dry_run {
+        /*
+         * Walk each archive in the order in which it would be unpacked, and
+         * report what it would do to the zone root.
+         */
+        let mut plan = plan::Plan::new();
+        if let Some((_, os)) = &mut os {
+            os.plan(&mut plan)?;
+        }
+        baseline.plan(&mut plan)?;
+        for i in order {
+            layers[i].1.plan(&mut plan)?;
+        }
+
+        if mat.opt_present("j") {
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+        } else {
+            print!("{plan}");
+        }
+
+        /*
+         * Nothing has been installed, so zoneadm(8) should return the zone to
+         * the configured state rather than marking it installed.
+         */
+        std::process::exit(ZONE_SUBPROC_NOTCOMPLETE);
+    }
+
+    let res = install_root(&s.zonepath, |staging| {
+        populate(&s, staging, os, &mut baseline, &mut layers, &order)
+    })?;
+    if let Installed::RolledBack(e) = res {
+        if let Err(se) = release_store(&s) {
+            println!("WARNING: omicron: could not release store files: {se}");
+        }
+
+        eprintln!("Error: {e:?}");
+        std::process::exit(ZONE_SUBPROC_NOTCOMPLETE);
+    }
+
     println!("INFO: omicron: install complete, probably!");
 
     Ok(())
@@ -328,9 +758,171 @@ fn cmd_uninstall(
      * XXX It would seem it is our responsibility to destroy the dataset or
      * zonepath directory.
      */
+    remove_root(&s.zonepath)?;
+    release_store(&s)?;
+
+    Ok(())
+}
+
+fn cmd_gc(args: &mut dyn Iterator<Item = &String>) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    let mat = opts.parse(args)?;
+
+    if !mat.free.is_empty() {
+        bail!("unexpected arguments {:?}", mat.free);
+    }
+
+    let Some(store) = store::Store::from_defaults(store::Lock::Exclusive)?
+    else {
+        bail!(
+            "no store is configured; see REPLICATE_STORE in {}",
+            defaults::BRAND_DEFAULTS,
+        );
+    };
+
+    let stats = store.gc()?;
+    println!("INFO: omicron: collected store {:?}: {stats}", store.path());
+    Ok(())
+}
+
+fn cmd_resync(s: Stuff, args: &mut dyn Iterator<Item = &String>) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    let mat = opts.parse(args)?;
+
+    if !mat.free.is_empty() {
+        bail!("unexpected arguments {:?}", mat.free);
+    }
+
+    let path = s.otherdir(replica::RECORD);
+    let Some(record) = replica::Record::load(&path)? else {
+        bail!(
+            "zone {} has no record of replicated files; zones installed from \
+            an OS archive, or before records were kept, must be reinstalled",
+            s.zone,
+        );
+    };
     let root = s.zoneroot();
-    if root.exists() {
-        std::fs::remove_dir_all(&root)?;
+    if !root.is_dir() {
+        bail!("zone root {root:?} does not exist");
+    }
+
+    println!(
+        "INFO: omicron: resyncing zone {} from the global zone...",
+        s.zone
+    );
+
+    /*
+     * The store, if there is one, stays locked until the resync is finished
+     * and the zone's use of it is recorded, so that no file is collected
+     * between being found in the store and being linked into the zone root.
+     */
+    let mut store = open_store(&root)?;
+
+    let start = Instant::now();
+    let wants = replication_wants()?;
+    let (record, stats) =
+        replica::resync(&root, &record, &wants, store.as_mut())?;
+    record.store(&path)?;
+    if let Some(st) = &store {
+        st.save_refs(&s.zone, &s.zonepath)?;
+    }
+
+    /*
+     * Bring the manifest up to date as well.  Paths that an archive provided
+     * are left alone by the resync, and keep their origin.
+     */
+    let mpath = s.otherdir(manifest::MANIFEST);
+    if let Some(old) = manifest::Manifest::load(&mpath)? {
+        let m = manifest::Manifest::build(
+            &root,
+            old.archives.clone(),
+            &old.origins(),
+            Some((&record, &wants)),
+        )?;
+        m.store(&mpath)?;
+    }
+    let msec = Instant::now().saturating_duration_since(start).as_millis();
+
+    println!(
+        "INFO: omicron: resynced zone {}: {stats}, {msec} msec",
+        s.zone
+    );
+
+    Ok(())
+}
+
+/**
+ * Compare the zone root with what we expect to find there, and report any
+ * differences.  The exit status is non-zero if there are any.
+ */
+fn cmd_verify(s: Stuff, args: &mut dyn Iterator<Item = &String>) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    opts.optflag("j", "json", "report as JSON");
+    let mat = opts.parse(args)?;
+
+    if !mat.free.is_empty() {
+        bail!("unexpected arguments {:?}", mat.free);
+    }
+
+    let report = drift::check(
+        &s.zonepath,
+        Path::new("/"),
+        &replicate::Rules::from_defaults()?,
+    )?;
+
+    if mat.opt_present("j") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+
+    if !report.drift.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/**
+ * Report what replicating the global zone would do with a path, and which
+ * rule made that choice.  The path is resolved as the walk would reach it, so
+ * a path beneath a skipped or excluded directory is reported as such.
+ */
+fn explain(path: &str) -> Result<()> {
+    let p = Path::new(path);
+    if !p.is_absolute() {
+        bail!("path {path:?} must be absolute");
+    }
+
+    let Some(tree) = replica::TREES
+        .iter()
+        .map(|t| PathBuf::from(format!("/{t}")))
+        .find(|t| p.starts_with(t))
+    else {
+        println!("{path}: not within a replicated tree");
+        return Ok(());
+    };
+
+    let rules = replicate::Rules::from_defaults()?;
+    let exclude = replication_exclude()?;
+    match tree::explain(&tree, p, &rules, &exclude)? {
+        tree::Explanation::Excluded(a) if a == p => {
+            println!("{path}: excluded (global-zone only)");
+        }
+        tree::Explanation::Excluded(a) => {
+            println!("{path}: excluded (global-zone only), by ancestor {a:?}");
+        }
+        tree::Explanation::Skipped(a, rule) => {
+            println!("{path}: skipped by ancestor {a:?}, by rule {rule}");
+        }
+        tree::Explanation::Action(action, Some(rule)) => {
+            println!("{path}: {action}, by rule {rule}");
+        }
+        tree::Explanation::Action(action, None) => {
+            println!("{path}: {action}, as no rule matches");
+        }
     }
 
     Ok(())
@@ -345,6 +937,7 @@ fn main() -> Result<()> {
     opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
     opts.optopt("z", "", "zone name", "NAME");
     opts.optopt("R", "", "zone path", "DIR");
+    opts.optopt("", "explain", "show how a path would be replicated", "PATH");
 
     /*
      * XXX Take this camera.  I want to document everything!
@@ -365,6 +958,10 @@ fn main() -> Result<()> {
 
     let mat = opts.parse(std::env::args().skip(1))?;
 
+    if let Some(path) = mat.opt_str("explain") {
+        return explain(&path);
+    }
+
     let mut args = mat.free.iter();
     match args.next().map(|x| x.as_str()) {
         Some("verify_cfg") => cmd_verify_cfg(&mut args),
@@ -372,6 +969,9 @@ fn main() -> Result<()> {
         Some("query") => cmd_query(mkstuff(&mat)?, &mut args),
         Some("install") => cmd_install(mkstuff(&mat)?, &mut args),
         Some("uninstall") => cmd_uninstall(mkstuff(&mat)?, &mut args),
+        Some("resync") => cmd_resync(mkstuff(&mat)?, &mut args),
+        Some("verify") => cmd_verify(mkstuff(&mat)?, &mut args),
+        Some("gc") => cmd_gc(&mut args),
         Some("prestatechange") => cmd_prestate(mkstuff(&mat)?, &mut args),
         Some("poststatechange") => cmd_poststate(mkstuff(&mat)?, &mut args),
         other => {
@@ -379,3 +979,183 @@ fn main() -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use defaults::Policy;
+    use metadata::{ArchiveType, MetadataBuilder, Provenance};
+
+    fn archive(
+        archive_type: ArchiveType,
+        incorporation: Option<String>,
+    ) -> metadata::Metadata {
+        let mut mb = MetadataBuilder::new(archive_type);
+        if let Some(incorporation) = incorporation {
+            mb.provenance(Provenance {
+                incorporation,
+                packages: Vec::new(),
+                publishers: Vec::new(),
+                generated: 0,
+            })
+            .unwrap();
+        }
+        mb.build().unwrap()
+    }
+
+    #[test]
+    fn baseline_policy() {
+        let matching = |p: &str| {
+            format!(
+                "pkg://{p}/consolidation/osnet/\
+                osnet-incorporation@0.5.11-2.0.22000:20240101T000000Z"
+            )
+        };
+        let fmri = |version: &str, date: &str| {
+            format!(
+                "pkg://helios-dev/consolidation/osnet/\
+                osnet-incorporation@{version}:{date}"
+            )
+        };
+
+        let baseline =
+            archive(ArchiveType::Baseline, Some(matching("helios-dev")));
+        let os = archive(ArchiveType::Os, Some(matching("other-publisher")));
+        let newer = archive(
+            ArchiveType::Os,
+            Some(fmri("0.5.11-2.0.22001", "20240101T000000Z")),
+        );
+        let rebuilt = archive(
+            ArchiveType::Os,
+            Some(fmri("0.5.11-2.0.22000", "20240201T000000Z")),
+        );
+        let bare = archive(ArchiveType::Os, None);
+        let unrecorded = archive(ArchiveType::Baseline, None);
+
+        /*
+         * Only the version and timestamp need match, not the publisher.
+         */
+        assert!(baseline_mismatch(&baseline, Some(&os)).unwrap().is_none());
+        for other in [&newer, &rebuilt] {
+            let problem =
+                baseline_mismatch(&baseline, Some(other)).unwrap().unwrap();
+            assert!(problem.contains("the OS archive has"), "{problem}");
+        }
+        assert!(baseline_mismatch(&baseline, Some(&bare))
+            .unwrap()
+            .unwrap()
+            .starts_with("OS archive does not record"));
+        assert!(baseline_mismatch(&unrecorded, Some(&os))
+            .unwrap()
+            .unwrap()
+            .starts_with("baseline archive does not record"));
+
+        /*
+         * A mismatch is ignored, reported, or fatal according to the policy,
+         * and a match is never a problem.
+         */
+        for policy in [Policy::Off, Policy::Warn, Policy::Enforce] {
+            let res = check_baseline(policy, &baseline, Some(&os));
+            assert!(res.unwrap().is_none());
+        }
+        let check = |policy| check_baseline(policy, &baseline, Some(&newer));
+        assert!(check(Policy::Off).unwrap().is_none());
+        assert!(check(Policy::Warn).unwrap().is_some());
+        let e = check(Policy::Enforce).unwrap_err().to_string();
+        assert!(e.contains("should be regenerated"), "{e}");
+        let res = check_baseline(Policy::Enforce, &unrecorded, Some(&os));
+        assert!(res.is_err());
+    }
+
+    fn contents(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn install_rollback() {
+        let zonepath = tempfile::tempdir().unwrap();
+        let zp = zonepath.path();
+
+        /*
+         * A step that fails after writing into the zone root.
+         */
+        let res = install_root(zp, |staging| {
+            std::fs::create_dir(staging.join("etc")).unwrap();
+            std::fs::write(staging.join("etc/motd"), "hello").unwrap();
+            bail!("layer is faulty");
+        })
+        .unwrap();
+        assert!(
+            matches!(res, Installed::RolledBack(e) if e.to_string() == "layer is faulty")
+        );
+        assert!(contents(zp).is_empty());
+
+        /*
+         * A failure once the record and the manifest have been written, as
+         * the staging directory cannot be renamed over a directory that
+         * appeared in the meantime.
+         */
+        let res = install_root(zp, |staging| {
+            std::fs::write(staging.join("motd"), "hello").unwrap();
+            std::fs::create_dir_all(zp.join("root/in-the-way")).unwrap();
+            Ok((Some(Default::default()), Default::default()))
+        })
+        .unwrap();
+        assert!(matches!(res, Installed::RolledBack(_)));
+        assert_eq!(contents(zp), ["root"]);
+        std::fs::remove_dir_all(zp.join("root")).unwrap();
+
+        /*
+         * An install that succeeds leaves only the zone root and its records,
+         * and an install over an existing root is refused.
+         */
+        let res = install_root(zp, |staging| {
+            std::fs::write(staging.join("motd"), "hello").unwrap();
+            Ok((Some(Default::default()), Default::default()))
+        })
+        .unwrap();
+        assert!(matches!(res, Installed::Complete));
+        assert_eq!(contents(zp), ["manifest.json", "replicated.json", "root"],);
+        assert_eq!(contents(&zp.join("root")), ["motd"]);
+        assert!(install_root(zp, |_| unreachable!()).is_err());
+    }
+
+    #[test]
+    fn uninstall_incomplete() {
+        let zonepath = tempfile::tempdir().unwrap();
+        let zp = zonepath.path();
+        let partial = |dir: &str| {
+            std::fs::create_dir_all(zp.join(dir).join("usr/lib")).unwrap();
+            std::fs::write(zp.join(dir).join("usr/lib/libc.so.1"), "").unwrap();
+        };
+
+        /*
+         * An install that was interrupted leaves only the staging directory,
+         * and perhaps a record.
+         */
+        partial("root.staging");
+        std::fs::write(zp.join("replicated.json"), "{}").unwrap();
+        remove_root(zp).unwrap();
+        assert!(contents(zp).is_empty());
+
+        /*
+         * An uninstall that was interrupted may leave the staging directory
+         * alongside the zone root.
+         */
+        partial("root");
+        partial("root.staging");
+        std::fs::write(zp.join("manifest.json"), "{}").unwrap();
+        remove_root(zp).unwrap();
+        assert!(contents(zp).is_empty());
+
+        /*
+         * There may be nothing to remove at all.
+         */
+        remove_root(zp).unwrap();
+    }
+}
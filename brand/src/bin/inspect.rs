@@ -10,6 +10,7 @@ use helios_omicron_brand::*;
 fn main() -> Result<()> {
     let image = unpack::Unpack::load(argv(0, "image file path")?)?;
 
+    println!("compression: {}", image.codec());
     println!("metadata: {:?}", image.metadata());
 
     Ok(())
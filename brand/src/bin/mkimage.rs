@@ -252,7 +252,7 @@ fn main() -> Result<()> {
          */
         if rp.starts_with("var/pkg")
             || rp.starts_with("var/sadm")
-            || rp == PathBuf::from("etc/.pwd.lock")
+            || rp == Path::new("etc/.pwd.lock")
         {
             continue;
         }
@@ -0,0 +1,65 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+use helios_omicron_brand::*;
+
+fn main() -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("g", "", "generate a new signing key", "KEYFILE");
+    opts.optopt("k", "", "sign archives with this key", "KEYFILE");
+    opts.optmulti("K", "", "verify archives against public key", "PUBKEY");
+
+    let mat = opts.parse(std::env::args().skip(1))?;
+
+    if let Some(keyfile) = mat.opt_str("g") {
+        if !mat.free.is_empty() {
+            bail!("unexpected arguments {:?}", mat.free);
+        }
+
+        let key = signature::generate_signing_key(&keyfile)?;
+        println!(
+            "public key: {}",
+            signature::public_key_string(&key.verifying_key())
+        );
+        return Ok(());
+    }
+
+    if mat.free.is_empty() {
+        bail!("specify at least one archive to sign or verify");
+    }
+
+    if let Some(keyfile) = mat.opt_str("k") {
+        let key = signature::load_signing_key(&keyfile)?;
+        println!(
+            "public key: {}",
+            signature::public_key_string(&key.verifying_key())
+        );
+
+        for archive in mat.free.iter() {
+            let sig = signature::sign(&key, archive)?;
+            println!("signed {archive:?} -> {sig:?}");
+        }
+    } else if mat.opt_present("K") {
+        let keys = mat
+            .opt_strs("K")
+            .iter()
+            .map(|k| signature::parse_public_key(k))
+            .collect::<Result<Vec<_>>>()?;
+        let v = signature::Verifier::new(signature::Policy::Enforce, keys);
+
+        for archive in mat.free.iter().map(PathBuf::from) {
+            let mut f = std::fs::File::open(&archive)?;
+            v.check(&archive, &mut f)?;
+            println!("verified {archive:?}");
+        }
+    } else {
+        bail!("specify -k to sign or -K to verify");
+    }
+
+    Ok(())
+}
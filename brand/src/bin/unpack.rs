@@ -2,18 +2,79 @@
  * Copyright 2023 Oxide Computer Company
  */
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use std::path::Path;
 
-use common::*;
 use helios_omicron_brand::*;
 
+fn load(image: &str, strict: bool) -> Result<Box<dyn image::Image>> {
+    /*
+     * An archive read from standard input can only be unpacked in a single
+     * pass, so it is always read in strict mode.  Strict mode does not apply
+     * to an image directory or an OCI image layout.
+     */
+    if image == "-" {
+        Ok(Box::new(unpack::Unpack::from_reader(
+            "<stdin>",
+            std::io::stdin(),
+            &signature::Verifier::from_defaults()?,
+        )?))
+    } else if strict && !Path::new(image).is_dir() {
+        Ok(Box::new(unpack::Unpack::load_strict(image)?))
+    } else {
+        image::open(image)
+    }
+}
+
 fn main() -> Result<()> {
-    let mut image = unpack::Unpack::load(argv(0, "image file path")?)?;
-    let outdir = argv(1, "output directory")?;
+    let mut opts = getopts::Options::new();
+    opts.optflag("s", "", "require oxide.json to be the first entry");
+    opts.optflag("n", "", "report what unpacking would do, then stop");
+    opts.optflag("j", "", "report the plan as JSON");
+    opts.optopt("t", "", "threads for writing files (0 for none)", "THREADS");
+
+    let mat = opts.parse(std::env::args().skip(1))?;
+    let strict = mat.opt_present("s");
+
+    if mat.opt_present("n") {
+        /*
+         * Plan the unpacking of each archive in turn, in the order given,
+         * as if they were all unpacked into the same directory.
+         */
+        if mat.free.is_empty() {
+            bail!("usage: unpack -n [-js] IMAGE|-...");
+        }
+
+        let mut plan = plan::Plan::new();
+        for archive in mat.free.iter() {
+            load(archive, strict)?.plan(&mut plan)?;
+        }
+
+        if mat.opt_present("j") {
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+        } else {
+            print!("{plan}");
+        }
+
+        if plan.conflicts() > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let [archive, outdir] = mat.free.as_slice() else {
+        bail!("usage: unpack [-s] [-t THREADS] IMAGE|- OUTPUT_DIRECTORY");
+    };
+
+    let mut image = load(archive, strict)?;
+    if let Some(threads) = mat.opt_str("t") {
+        image.set_threads(threads.parse()?);
+    }
 
     println!("metadata: {:?}", image.metadata());
 
-    image.unpack(&outdir)?;
+    let stats = image.unpack(Path::new(outdir), None)?;
+    println!("unpacked: {stats}");
 
     Ok(())
 }
@@ -0,0 +1,37 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+use anyhow::{bail, Result};
+use std::path::Path;
+
+use helios_build_utils::replicate;
+use helios_omicron_brand::*;
+
+fn main() -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag("j", "json", "report as JSON");
+
+    let mat = opts.parse(std::env::args().skip(1))?;
+
+    let [zonepath] = mat.free.as_slice() else {
+        bail!("usage: verify [-j] ZONEPATH");
+    };
+
+    let report = drift::check(
+        Path::new(zonepath),
+        Path::new("/"),
+        &replicate::Rules::from_defaults()?,
+    )?;
+
+    if mat.opt_present("j") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+
+    if !report.drift.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
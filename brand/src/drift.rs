@@ -0,0 +1,809 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * Check an installed zone root for drift: differences between what is in the
+ * zone root and what we expect to be there.  What we expect is worked out from
+ * three sources, each taking precedence over the one before:
+ *
+ *  - the manifest written at install time, for anything that came from
+ *    neither the global zone nor an archive;
+ *
+ *  - the trees in the global zone, replicated according to the current rules,
+ *    unless the zone was installed from an OS archive;
+ *
+ *  - the archives listed in the manifest, which are planned again from their
+ *    headers, in the order in which they were unpacked.  If any archive can no
+ *    longer be read, the paths that the archives provided are checked against
+ *    the manifest instead.
+ *
+ * Owners are taken from the manifest where it agrees with the archive about
+ * where a path came from, as the names in an archive are resolved against the
+ * zone's own user and group databases when it is unpacked.
+ */
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::image;
+use crate::manifest::{self, Manifest, Origin};
+use crate::plan::Plan;
+use crate::replica;
+use crate::rootdir::{Kind, RootDir, Stat};
+use helios_build_utils::metadata::{self, ArchiveType, EntryKind};
+use helios_build_utils::replicate::Rules;
+use helios_build_utils::tree::{Exclude, Replica};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    Missing,
+    Extra,
+    Modified,
+    Owner,
+    Mode,
+    /**
+     * A symbolic link into one of the trees under "/system" whose target is
+     * no longer in the global zone.
+     */
+    Dangling,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Problem::Missing => "missing",
+            Problem::Extra => "extra",
+            Problem::Modified => "modified",
+            Problem::Owner => "owner",
+            Problem::Mode => "mode",
+            Problem::Dangling => "dangling",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Drift {
+    pub path: String,
+    pub problem: Problem,
+    /**
+     * Where the object we expected came from.
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<Origin>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<9} {}", self.problem.to_string(), self.path)?;
+
+        let notes = self
+            .origin
+            .iter()
+            .map(|o| format!("from {o}"))
+            .chain(self.detail.iter().cloned())
+            .collect::<Vec<_>>();
+        if !notes.is_empty() {
+            write!(f, " ({})", notes.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /**
+     * The number of objects in the zone root that were checked.
+     */
+    pub checked: u64,
+    pub drift: Vec<Drift>,
+    /**
+     * Sources that could not be consulted, and what was used instead.
+     */
+    pub warnings: Vec<String>,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for w in self.warnings.iter() {
+            writeln!(f, "WARNING: {w}")?;
+        }
+        for d in self.drift.iter() {
+            writeln!(f, "{d}")?;
+        }
+        writeln!(
+            f,
+            "{} objects checked, {} differences",
+            self.checked,
+            self.drift.len(),
+        )
+    }
+}
+
+/**
+ * What we expect to find at a path in the zone root.  Whatever is not known
+ * is not checked.
+ */
+struct Expect {
+    origin: Origin,
+    kind: Kind,
+    mode: Option<u32>,
+    owner: Option<(u32, u32)>,
+    sha256: Option<String>,
+    /**
+     * A file in the global zone whose contents a copy should match.
+     */
+    source: Option<PathBuf>,
+    target: Option<PathBuf>,
+}
+
+impl Expect {
+    fn from_entry(e: &manifest::Entry) -> Expect {
+        let link = e.kind == Kind::Symlink;
+        Expect {
+            origin: e.origin.clone(),
+            kind: e.kind,
+            mode: (!link).then_some(e.mode),
+            owner: (!(link && e.origin.is_archive())).then_some((e.uid, e.gid)),
+            sha256: e.sha256.clone(),
+            source: None,
+            target: e.target.clone(),
+        }
+    }
+}
+
+type Expected = BTreeMap<PathBuf, Expect>;
+
+/**
+ * Check the zone root in "zonepath" for drift.  The global zone is rooted at
+ * "gz", and replicated according to "rules".
+ */
+pub fn check(zonepath: &Path, gz: &Path, rules: &Rules) -> Result<Report> {
+    if !zonepath.is_absolute() {
+        bail!("zonepath {zonepath:?} must be absolute");
+    }
+
+    let mpath = zonepath.join(manifest::MANIFEST);
+    let Some(m) = Manifest::load(&mpath)? else {
+        bail!(
+            "there is no manifest at {mpath:?}; the zone may have been \
+            installed by an older version of the brand"
+        );
+    };
+
+    let mut report = Report::default();
+    let mut expect = Expected::new();
+
+    for (p, e) in m.entries.iter() {
+        if e.origin == Origin::Other {
+            expect.insert(p.clone(), Expect::from_entry(e));
+        }
+    }
+
+    if !m
+        .archives
+        .iter()
+        .any(|a| matches!(a.archive_type, ArchiveType::Os))
+    {
+        replicated(&m, gz, rules, &mut expect, &mut report)?;
+    }
+
+    match planned(&m) {
+        Ok((plan, digests)) => archives(&m, &plan, &digests, &mut expect),
+        Err(e) => {
+            report.warnings.push(format!(
+                "{e}; checking the contents of the archives against the \
+                manifest instead"
+            ));
+            for (p, e) in m.entries.iter() {
+                if e.origin.is_archive() {
+                    expect.insert(p.clone(), Expect::from_entry(e));
+                }
+            }
+        }
+    }
+
+    compare(&zonepath.join("root"), gz, &expect, &mut report)?;
+    Ok(report)
+}
+
+/**
+ * Work out what replicating the global zone would produce now.
+ */
+fn replicated(
+    m: &Manifest,
+    gz: &Path,
+    rules: &Rules,
+    expect: &mut Expected,
+    report: &mut Report,
+) -> Result<()> {
+    let exclude = m
+        .archives
+        .iter()
+        .find(|a| matches!(a.archive_type, ArchiveType::Baseline))
+        .ok_or_else(|| anyhow!("no baseline archive in the manifest"))
+        .and_then(|a| {
+            replica::exclude(
+                gz,
+                &Path::new(&a.name).with_file_name(replica::GZONLY),
+            )
+        });
+    let exclude = exclude.unwrap_or_else(|e| {
+        report
+            .warnings
+            .push(format!("{e}; global-zone only files are not left out"));
+        let mut exclude = Exclude::default();
+        exclude.subtree(gz.join(replica::SMF_MANIFESTS));
+        exclude
+    });
+
+    for (p, want) in replica::wants(gz, replica::TREES, rules, &exclude)? {
+        /*
+         * The global zone may change while we look at it.
+         */
+        let Ok(md) = std::fs::symlink_metadata(&want.src) else {
+            continue;
+        };
+
+        let (origin, kind) = match want.replica {
+            Replica::Directory => (Origin::Replicate, Kind::Directory),
+            Replica::Symlink => (Origin::Replicate, Kind::Symlink),
+            Replica::Link(_) => (Origin::ReplicateLink, Kind::Symlink),
+            Replica::Copy => (Origin::ReplicateCopy, Kind::File),
+        };
+        expect.insert(
+            p,
+            Expect {
+                origin,
+                kind,
+                mode: (kind != Kind::Symlink).then_some(md.mode() & 0o7777),
+                owner: Some((md.uid(), md.gid())),
+                sha256: None,
+                source: (kind == Kind::File).then_some(want.src),
+                target: want.target,
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/**
+ * Plan each archive listed in the manifest again, in order.  The layers of an
+ * OCI image layout are planned by opening the layout again.  Returns the plan
+ * and, for each archive that includes one, its manifest of file digests.
+ */
+#[allow(clippy::type_complexity)]
+fn planned(
+    m: &Manifest,
+) -> Result<(
+    Plan,
+    BTreeMap<String, BTreeMap<String, metadata::ManifestEntry>>,
+)> {
+    let mut plan = Plan::new();
+    let mut digests = BTreeMap::new();
+
+    let mut rest = m.archives.as_slice();
+    while let Some(a) = rest.first() {
+        let path = a.image.as_deref().unwrap_or(&a.name);
+        let mut img =
+            image::open(path).map_err(|e| anyhow!("archive {path:?}: {e}"))?;
+        let first = plan.archives().len();
+        img.plan(&mut plan)?;
+
+        /*
+         * The image must still consist of the archives that were unpacked.
+         */
+        let again = &plan.archives()[first..];
+        if again.is_empty()
+            || again.len() > rest.len()
+            || again.iter().zip(rest).any(|(p, u)| p.name != u.name)
+        {
+            bail!("image {path:?} has changed since the zone was installed");
+        }
+        if let Some(e) = again.iter().find_map(|a| a.error.as_ref()) {
+            bail!("archive {path:?}: {e}");
+        }
+        if let Some(mf) = img.metadata().manifest() {
+            for a in again {
+                digests.insert(a.name.clone(), mf.clone());
+            }
+        }
+        rest = &rest[again.len()..];
+    }
+
+    Ok((plan, digests))
+}
+
+fn archives(
+    m: &Manifest,
+    plan: &Plan,
+    digests: &BTreeMap<String, BTreeMap<String, metadata::ManifestEntry>>,
+    expect: &mut Expected,
+) {
+    let (_, origins) = manifest::from_plan(plan);
+
+    for (p, o) in plan.objects() {
+        let Some(origin) = origins.get(p) else {
+            continue;
+        };
+        let kind = match o.kind {
+            EntryKind::Directory => Kind::Directory,
+            EntryKind::File | EntryKind::Hardlink => Kind::File,
+            EntryKind::Symlink => Kind::Symlink,
+            EntryKind::Fifo => Kind::Fifo,
+        };
+        let link = kind == Kind::Symlink;
+
+        /*
+         * The manifest knows the owner, digest, and link target of what this
+         * archive unpacked at install time.
+         */
+        let installed = m
+            .entries
+            .get(p)
+            .filter(|e| &e.origin == origin && e.kind == kind);
+        let header = p
+            .to_str()
+            .and_then(|p| digests.get(&o.archive)?.get(p))
+            .filter(|me| me.kind != EntryKind::Hardlink);
+
+        expect.insert(
+            p.clone(),
+            Expect {
+                origin: origin.clone(),
+                kind,
+                mode: (!link).then_some(o.mode),
+                owner: (!link).then(|| {
+                    installed.map(|e| (e.uid, e.gid)).unwrap_or((o.uid, o.gid))
+                }),
+                sha256: header
+                    .and_then(|me| me.sha256.clone())
+                    .or_else(|| installed.and_then(|e| e.sha256.clone())),
+                source: None,
+                target: if link {
+                    header
+                        .and_then(|me| me.target.as_ref().map(PathBuf::from))
+                        .or_else(|| installed.and_then(|e| e.target.clone()))
+                } else {
+                    None
+                },
+            },
+        );
+    }
+}
+
+/**
+ * Walk the zone root, comparing what we find with what we expect.
+ */
+fn compare(
+    root: &Path,
+    gz: &Path,
+    expect: &Expected,
+    report: &mut Report,
+) -> Result<()> {
+    let mut rd = RootDir::open(root)?;
+    let Some(top) = rd.lstat(Path::new(""))? else {
+        bail!("zone root {root:?} does not exist");
+    };
+
+    let mut seen = BTreeSet::new();
+    let mut walked = BTreeSet::new();
+    walked.insert(PathBuf::new());
+
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for name in rd.read_dir(&dir)? {
+            let p = dir.join(name);
+            let Some(st) = rd.lstat(&p)? else {
+                continue;
+            };
+            seen.insert(p.clone());
+
+            /*
+             * Anything mounted in the zone root of a running zone, such as
+             * the trees under "/system", is not part of the zone root.
+             */
+            if st.dev != top.dev {
+                continue;
+            }
+            report.checked += 1;
+
+            let e = expect.get(&p);
+            let mut drift = |problem, detail: Option<String>| {
+                report.drift.push(Drift {
+                    path: Path::new("/").join(&p).to_string_lossy().into(),
+                    problem,
+                    origin: e.map(|e| e.origin.clone()),
+                    detail,
+                });
+            };
+
+            if st.kind == Kind::Symlink {
+                let target = rd.read_link(&p)?;
+                if let Some(missing) = dangling(gz, &p, &target) {
+                    drift(
+                        Problem::Dangling,
+                        Some(format!("{missing:?} is not in the global zone")),
+                    );
+                    continue;
+                }
+            }
+
+            let Some(e) = e else {
+                drift(Problem::Extra, Some(kind_name(st.kind).into()));
+                continue;
+            };
+
+            for (problem, detail) in differences(&mut rd, &p, &st, e)? {
+                drift(problem, Some(detail));
+            }
+            if st.kind == Kind::Directory && e.kind == Kind::Directory {
+                walked.insert(p.clone());
+                dirs.push(p);
+            }
+        }
+    }
+
+    /*
+     * Only the first path that is missing in each tree is reported, rather
+     * than everything that would have been beneath it.
+     */
+    for (p, e) in expect.iter() {
+        if seen.contains(p)
+            || !walked.contains(p.parent().unwrap_or(Path::new("")))
+        {
+            continue;
+        }
+        report.drift.push(Drift {
+            path: Path::new("/").join(p).to_string_lossy().into(),
+            problem: Problem::Missing,
+            origin: Some(e.origin.clone()),
+            detail: None,
+        });
+    }
+
+    report.drift.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(())
+}
+
+fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Directory => "directory",
+        Kind::File => "file",
+        Kind::Symlink => "symbolic link",
+        Kind::Fifo => "fifo",
+        Kind::Other => "special file",
+    }
+}
+
+fn differences(
+    rd: &mut RootDir,
+    p: &Path,
+    st: &Stat,
+    e: &Expect,
+) -> Result<Vec<(Problem, String)>> {
+    let mut out = Vec::new();
+
+    if st.kind != e.kind {
+        out.push((
+            Problem::Modified,
+            format!("{}, expected {}", kind_name(st.kind), kind_name(e.kind),),
+        ));
+        return Ok(out);
+    }
+
+    if let Some((uid, gid)) = e.owner {
+        if (st.uid, st.gid) != (uid, gid) {
+            out.push((
+                Problem::Owner,
+                format!("owner {}:{}, expected {uid}:{gid}", st.uid, st.gid),
+            ));
+        }
+    }
+    if let Some(mode) = e.mode {
+        if st.mode != mode {
+            out.push((
+                Problem::Mode,
+                format!("mode {:o}, expected {mode:o}", st.mode),
+            ));
+        }
+    }
+
+    match st.kind {
+        Kind::Symlink => {
+            let target = rd.read_link(p)?;
+            if let Some(want) = e.target.as_ref().filter(|t| **t != target) {
+                out.push((
+                    Problem::Modified,
+                    format!("link to {target:?}, expected {want:?}"),
+                ));
+            }
+        }
+        Kind::File => {
+            let want = match (&e.sha256, &e.source) {
+                (Some(d), _) => Some(d.clone()),
+                (None, Some(src)) => std::fs::File::open(src)
+                    .ok()
+                    .map(metadata::sha256_digest)
+                    .transpose()?,
+                (None, None) => None,
+            };
+            if let Some(want) = want {
+                let found = metadata::sha256_digest(rd.open_file(p)?)?;
+                if found != want {
+                    out.push((
+                        Problem::Modified,
+                        format!("digest {found}, expected {want}"),
+                    ));
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(out)
+}
+
+/**
+ * If a symbolic link at "p" leads into one of the replicated trees under
+ * "/system", return the object in the global zone to which it refers, if that
+ * does not exist.  The target is resolved without regard to any symbolic
+ * links along the way, as replicated links never pass through any.
+ */
+fn dangling(gz: &Path, p: &Path, target: &Path) -> Option<PathBuf> {
+    let mut r = p.parent().unwrap_or(Path::new("")).to_path_buf();
+    for c in target.components() {
+        match c {
+            Component::RootDir => r = PathBuf::new(),
+            Component::ParentDir => {
+                r.pop();
+            }
+            Component::Normal(n) => r.push(n),
+            Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+
+    let rel = r.strip_prefix("system").ok()?;
+    if !replica::TREES.iter().any(|t| rel.starts_with(t)) {
+        return None;
+    }
+    let src = gz.join(rel);
+    src.symlink_metadata().is_err().then_some(src)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drift() {
+        let gz = tempfile::tempdir().unwrap();
+        let zonepath = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "link *.so.1\ncopy /**\n").unwrap();
+        let gp = |p: &str| gz.path().join(p);
+        let root = zonepath.path().join("root");
+        let zp = |p: &str| root.join(p);
+
+        for d in ["usr/lib", "lib", "sbin"] {
+            std::fs::create_dir_all(gp(d)).unwrap();
+        }
+        std::fs::create_dir_all(&root).unwrap();
+        for f in ["libc.so.1", "libm.so.1", "a.conf", "b.conf", "c.conf"] {
+            std::fs::write(gp("usr/lib").join(f), f).unwrap();
+        }
+        std::fs::write(zp("motd"), "hello").unwrap();
+
+        let wants = replica::wants(
+            gz.path(),
+            replica::TREES,
+            &rules,
+            &Default::default(),
+        )
+        .unwrap();
+        let (record, _) =
+            replica::resync(&root, &Default::default(), &wants, None).unwrap();
+        Manifest::build(
+            &root,
+            Vec::new(),
+            &Default::default(),
+            Some((&record, &wants)),
+        )
+        .unwrap()
+        .store(zonepath.path().join(manifest::MANIFEST))
+        .unwrap();
+
+        let report = check(zonepath.path(), gz.path(), &rules).unwrap();
+        assert!(report.drift.is_empty(), "{report}");
+
+        /*
+         * Now change a few things in the zone root and the global zone.
+         */
+        std::fs::write(zp("motd"), "goodbye").unwrap();
+        std::fs::write(zp("usr/lib/a.conf"), "changed").unwrap();
+        std::fs::remove_file(zp("usr/lib/b.conf")).unwrap();
+        std::fs::write(zp("usr/lib/extra"), "extra").unwrap();
+        std::os::unix::fs::lchown(zp("usr/lib/c.conf"), Some(1), None).unwrap();
+        std::fs::remove_file(gp("usr/lib/libm.so.1")).unwrap();
+
+        let report = check(zonepath.path(), gz.path(), &rules).unwrap();
+        let found = report
+            .drift
+            .iter()
+            .map(|d| (d.path.as_str(), d.problem))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("/motd", Problem::Modified),
+                ("/usr/lib/a.conf", Problem::Modified),
+                ("/usr/lib/b.conf", Problem::Missing),
+                ("/usr/lib/c.conf", Problem::Owner),
+                ("/usr/lib/extra", Problem::Extra),
+                ("/usr/lib/libm.so.1", Problem::Dangling),
+            ],
+        );
+    }
+
+    fn entry(
+        a: &mut tar::Builder<Vec<u8>>,
+        path: &str,
+        mode: u32,
+        data: Option<&[u8]>,
+    ) {
+        let mut h = tar::Header::new_ustar();
+        h.set_entry_type(match data {
+            Some(_) => tar::EntryType::Regular,
+            None => tar::EntryType::Directory,
+        });
+        h.set_path(path).unwrap();
+        h.set_mode(mode);
+        h.set_uid(0);
+        h.set_gid(0);
+        h.set_mtime(0);
+        h.set_size(data.map_or(0, |d| d.len().try_into().unwrap()));
+        h.set_cksum();
+        a.append(&h, data.unwrap_or_default()).unwrap();
+    }
+
+    /**
+     * Write a blob into an OCI image layout, returning its descriptor.
+     */
+    fn blob(dir: &Path, media_type: &str, data: &[u8]) -> serde_json::Value {
+        let hex = metadata::sha256_digest(data).unwrap();
+        std::fs::write(dir.join("blobs/sha256").join(&hex), data).unwrap();
+        serde_json::json!({
+            "mediaType": media_type,
+            "digest": format!("sha256:{hex}"),
+            "size": data.len(),
+        })
+    }
+
+    #[test]
+    fn drift_archives() {
+        let gz = tempfile::tempdir().unwrap();
+        let zonepath = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "copy /**\n").unwrap();
+        let root = zonepath.path().join("root");
+        let zp = |p: &str| root.join(p);
+
+        for d in replica::TREES {
+            std::fs::create_dir_all(gz.path().join(d)).unwrap();
+        }
+        std::fs::create_dir_all(&root).unwrap();
+
+        /*
+         * A layer archive, and an OCI image layout with a single layer.
+         */
+        let layer = zonepath.path().join("layer.tar");
+        let mut a = tar::Builder::new(Vec::new());
+        entry(
+            &mut a,
+            "oxide.json",
+            0o644,
+            Some(b"{\"v\":\"1\",\"t\":\"layer\"}\n"),
+        );
+        entry(&mut a, "root/etc", 0o755, None);
+        entry(&mut a, "root/etc/motd", 0o644, Some(b"hello\n"));
+        std::fs::write(&layer, a.into_inner().unwrap()).unwrap();
+
+        let oci = zonepath.path().join("oci");
+        std::fs::create_dir_all(oci.join("blobs/sha256")).unwrap();
+        let mut a = tar::Builder::new(Vec::new());
+        entry(&mut a, "opt", 0o755, None);
+        entry(&mut a, "opt/app", 0o755, Some(b"app"));
+        let ldesc = blob(
+            &oci,
+            "application/vnd.oci.image.layer.v1.tar",
+            &a.into_inner().unwrap(),
+        );
+        let mdesc = blob(
+            &oci,
+            "application/vnd.oci.image.manifest.v1+json",
+            &serde_json::to_vec(
+                &serde_json::json!({ "schemaVersion": 2, "layers": [ldesc] }),
+            )
+            .unwrap(),
+        );
+        std::fs::write(
+            oci.join("index.json"),
+            serde_json::to_vec(
+                &serde_json::json!({ "schemaVersion": 2, "manifests": [mdesc] }),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            oci.join("oci-layout"),
+            "{\"imageLayoutVersion\":\"1.0.0\"}",
+        )
+        .unwrap();
+
+        /*
+         * Install the zone much as the brand would.
+         */
+        let wants = replica::wants(
+            gz.path(),
+            replica::TREES,
+            &rules,
+            &Default::default(),
+        )
+        .unwrap();
+        let (record, _) =
+            replica::resync(&root, &Default::default(), &wants, None).unwrap();
+        let mut plan = Plan::new();
+        for img in [&layer, &oci] {
+            image::open(img)
+                .unwrap()
+                .unpack(&root, Some(&mut plan))
+                .unwrap();
+        }
+        let (archives, origins) = manifest::from_plan(&plan);
+        let blob = archives[1].name.clone();
+        assert_eq!(archives[1].image.as_deref(), oci.to_str());
+        Manifest::build(&root, archives, &origins, Some((&record, &wants)))
+            .unwrap()
+            .store(zonepath.path().join(manifest::MANIFEST))
+            .unwrap();
+
+        /*
+         * Both archives are read again, rather than checked against the
+         * manifest.
+         */
+        let report = check(zonepath.path(), gz.path(), &rules).unwrap();
+        assert!(report.drift.is_empty(), "{report}");
+        assert!(
+            !report
+                .warnings
+                .iter()
+                .any(|w| w.contains("against the manifest")),
+            "{report}"
+        );
+
+        std::fs::write(zp("etc/motd"), "changed\n").unwrap();
+        std::fs::set_permissions(
+            zp("opt/app"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o700),
+        )
+        .unwrap();
+
+        let report = check(zonepath.path(), gz.path(), &rules).unwrap();
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checked"], 7);
+        assert_eq!(json["drift"].as_array().unwrap().len(), 2, "{report}");
+        let (motd, app) = (&json["drift"][0], &json["drift"][1]);
+        assert_eq!(motd["path"], "/etc/motd");
+        assert_eq!(motd["problem"], "modified");
+        assert_eq!(motd["origin"]["layer"], layer.to_str().unwrap());
+        assert_eq!(app["path"], "/opt/app");
+        assert_eq!(app["problem"], "mode");
+        assert_eq!(app["origin"]["layer"], blob.as_str());
+        assert_eq!(app["detail"], "mode 700, expected 755");
+    }
+}
@@ -0,0 +1,757 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * An image is anything that can be unpacked into a zone root.  Most images are
+ * archive files, which are handled by Unpack, but developers iterating on the
+ * contents of a layer may also provide:
+ *
+ *  - a directory containing "oxide.json" and a "root/" subtree, arranged just
+ *    like the archive would be, with ownership taken from an optional
+ *    "owners.json" file alongside; or
+ *
+ *  - a local OCI image layout, containing "oci-layout", "index.json" and the
+ *    blobs it refers to, whose layers are applied in order.
+ *
+ * Both are presented through the same Image trait as an archive, so that the
+ * brand and the unpack tool need not care which they were given.
+ */
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{PipeReader, PipeWriter, Read, Seek, Write};
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::plan::Plan;
+use crate::rootdir;
+use crate::signature::{self, Policy};
+use crate::unpack::{Unpack, UnpackStats};
+use helios_build_utils::metadata::{
+    self, ArchiveType, Metadata, MetadataBuilder,
+};
+use helios_build_utils::tree;
+
+/**
+ * The file in an image directory that specifies the ownership, and optionally
+ * the mode, of paths under "root/".
+ */
+pub const OWNERS_FILE: &str = "owners.json";
+
+pub trait Image {
+    fn metadata(&self) -> &Metadata;
+
+    /**
+     * Set the number of worker threads used to write regular files; see
+     * Unpack::set_threads().
+     */
+    fn set_threads(&mut self, threads: usize);
+
+    /**
+     * Apply the image to the plan, as Unpack::plan() does for an archive.
+     */
+    fn plan(&mut self, plan: &mut Plan) -> Result<()>;
+
+    /**
+     * Unpack the image into "outdir", applying each entry to the plan as well
+     * if one is provided; see Unpack::unpack().
+     */
+    fn unpack(
+        &mut self,
+        outdir: &Path,
+        plan: Option<&mut Plan>,
+    ) -> Result<UnpackStats>;
+}
+
+impl Image for Unpack {
+    fn metadata(&self) -> &Metadata {
+        Unpack::metadata(self)
+    }
+
+    fn set_threads(&mut self, threads: usize) {
+        Unpack::set_threads(self, threads)
+    }
+
+    fn plan(&mut self, plan: &mut Plan) -> Result<()> {
+        Unpack::plan(self, plan)
+    }
+
+    fn unpack(
+        &mut self,
+        outdir: &Path,
+        plan: Option<&mut Plan>,
+    ) -> Result<UnpackStats> {
+        Unpack::unpack(self, outdir, plan)
+    }
+}
+
+/**
+ * Open an image, which may be an archive file, an image directory, or an OCI
+ * image layout.  Signatures on archive files are checked according to the
+ * policy in the brand defaults file.
+ */
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Box<dyn Image>> {
+    let path = path.as_ref();
+
+    Ok(if !path.is_dir() {
+        Box::new(Unpack::load(path)?)
+    } else if path.join("oci-layout").exists() {
+        Box::new(OciImage::open(path)?)
+    } else {
+        Box::new(DirImage::open(path)?)
+    })
+}
+
+/**
+ * There is nothing we can check a detached signature against for an image that
+ * is not a single archive file, so such an image can only be used if the
+ * policy would allow an unsigned archive.
+ */
+fn check_unsigned(path: &Path) -> Result<()> {
+    match signature::Verifier::from_defaults()?.policy() {
+        Policy::Off => Ok(()),
+        Policy::Warn => {
+            println!(
+                "WARNING: image {path:?}: cannot check the signature of an \
+                image that is not an archive file"
+            );
+            Ok(())
+        }
+        Policy::Enforce => bail!(
+            "loading image {path:?}: cannot check the signature of an image \
+            that is not an archive file"
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Owner {
+    uid: u64,
+    gid: u64,
+    /**
+     * The mode is written in octal, as for chmod(1); e.g., "0644".
+     */
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Ownership {
+    uid: u64,
+    gid: u64,
+    mode: Option<u32>,
+}
+
+/**
+ * Ownership is listed by path, but applies to the file itself, so we also
+ * record it by inode for files with more than one link.
+ */
+#[derive(Default)]
+struct Owners {
+    paths: BTreeMap<String, Ownership>,
+    inodes: HashMap<(u64, u64), Ownership>,
+}
+
+/**
+ * An image directory.  Each time the image is planned or unpacked, a thread
+ * walks the directory and produces an uncompressed archive from it, which is
+ * then read exactly as if it had come from a file.  The "root/" subtree is
+ * walked in a stable order, so that hard links are always stored as a link to
+ * the same file.
+ *
+ * Paths that are not listed in "owners.json" are owned by root.  Their mode is
+ * taken from the manifest in "oxide.json", if there is one, and otherwise from
+ * the file system.
+ */
+pub struct DirImage {
+    dir: PathBuf,
+    metadata: Metadata,
+    owners: Arc<Owners>,
+    threads: Option<usize>,
+}
+
+impl DirImage {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<DirImage> {
+        let dir = dir.as_ref().to_path_buf();
+        check_unsigned(&dir)?;
+
+        let mdpath = dir.join("oxide.json");
+        let md = std::fs::read_to_string(&mdpath)
+            .map_err(|e| anyhow!("loading image {dir:?}: {mdpath:?}: {e}"))?;
+        let metadata = metadata::parse(&md)
+            .map_err(|e| anyhow!("loading image {dir:?}: {e:?}"))?;
+
+        let root = dir.join("root");
+        if !root.is_dir() {
+            bail!("loading image {dir:?}: no \"root\" directory");
+        }
+
+        let paths: BTreeMap<String, Ownership> = metadata
+            .manifest()
+            .into_iter()
+            .flatten()
+            .map(|(p, me)| {
+                let o = Ownership {
+                    uid: me.uid,
+                    gid: me.gid,
+                    mode: Some(me.mode),
+                };
+                (p.to_string(), o)
+            })
+            .collect();
+        let mut owners = Owners {
+            paths,
+            ..Default::default()
+        };
+
+        let opath = dir.join(OWNERS_FILE);
+        let list: BTreeMap<String, Owner> = match std::fs::read(&opath) {
+            Ok(b) => serde_json::from_slice(&b)
+                .map_err(|e| anyhow!("parsing {opath:?}: {e}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Default::default()
+            }
+            Err(e) => bail!("reading {opath:?}: {e}"),
+        };
+        for (p, o) in list {
+            rootdir::check_relative(Path::new(&p))
+                .map_err(|e| anyhow!("{opath:?}: {e}"))?;
+            let Ok(st) = std::fs::symlink_metadata(root.join(&p)) else {
+                bail!("{opath:?}: {p:?} does not exist in {root:?}");
+            };
+
+            let mode =
+                o.mode
+                    .map(|m| u32::from_str_radix(&m, 8))
+                    .transpose()
+                    .map_err(|e| anyhow!("{opath:?}: mode for {p:?}: {e}"))?;
+            if mode.is_some_and(|m| m > 0o7777) {
+                bail!("{opath:?}: mode for {p:?} is out of range");
+            }
+
+            let prior = owners.paths.get(&p).and_then(|o| o.mode);
+            let own = Ownership {
+                uid: o.uid,
+                gid: o.gid,
+                mode: mode.or(prior),
+            };
+            if st.is_file() && st.nlink() > 1 {
+                let ino = (st.dev(), st.ino());
+                if owners.inodes.insert(ino, own).is_some_and(|o| o != own) {
+                    bail!(
+                        "{opath:?}: {p:?} is a hard link to another listed \
+                        path with different ownership"
+                    );
+                }
+            }
+            owners.paths.insert(p, own);
+        }
+
+        Ok(DirImage {
+            dir,
+            metadata,
+            owners: Arc::new(owners),
+            threads: None,
+        })
+    }
+
+    /**
+     * Start producing an archive from the directory, and open it for reading.
+     */
+    fn archive(&self) -> Result<Unpack> {
+        let (r, w) = std::io::pipe()?;
+
+        let dir = self.dir.clone();
+        let metadata = self.metadata.clone();
+        let owners = Arc::clone(&self.owners);
+        let thread = std::thread::Builder::new()
+            .name("image".into())
+            .spawn(move || write_tree(&dir, &metadata, &owners, w))?;
+
+        let mut u = Unpack::from_stream(
+            self.dir.clone(),
+            Producer {
+                r,
+                thread: Some(thread),
+            },
+        )?;
+        if let Some(threads) = self.threads {
+            u.set_threads(threads);
+        }
+        Ok(u)
+    }
+}
+
+impl Image for DirImage {
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn set_threads(&mut self, threads: usize) {
+        self.threads = Some(threads);
+    }
+
+    fn plan(&mut self, plan: &mut Plan) -> Result<()> {
+        self.archive()?.plan(plan)
+    }
+
+    fn unpack(
+        &mut self,
+        outdir: &Path,
+        plan: Option<&mut Plan>,
+    ) -> Result<UnpackStats> {
+        self.archive()?.unpack(outdir, plan)
+    }
+}
+
+/**
+ * Reads the archive produced by a thread running write_tree().  Once the
+ * archive has been read in full, the thread is joined so that any error it
+ * encountered is reported to the reader, rather than appearing to be the end
+ * of a truncated archive.
+ */
+struct Producer {
+    r: PipeReader,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Read for Producer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.r.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            if let Some(thread) = self.thread.take() {
+                thread
+                    .join()
+                    .map_err(|_| {
+                        std::io::Error::other("image thread panicked")
+                    })?
+                    .map_err(|e| std::io::Error::other(format!("{e:#}")))?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn write_tree(
+    dir: &Path,
+    metadata: &Metadata,
+    owners: &Owners,
+    w: PipeWriter,
+) -> Result<()> {
+    let mut a = tar::Builder::new(&w);
+
+    match append_tree(&mut a, dir, metadata, owners) {
+        Ok(()) => {
+            a.into_inner()?;
+            Ok(())
+        }
+        Err(e) => {
+            /*
+             * Dropping the builder would write the end-of-archive marker,
+             * and the reader would then take what we had written so far to
+             * be the complete archive.  Leave it unfinished instead, so that
+             * the reader reaches the end of the pipe and collects our error.
+             */
+            std::mem::forget(a);
+            Err(e)
+        }
+    }
+}
+
+fn append_tree<W: Write>(
+    a: &mut tar::Builder<W>,
+    dir: &Path,
+    metadata: &Metadata,
+    owners: &Owners,
+) -> Result<()> {
+    let mdpath = dir.join("oxide.json");
+    let mtime = mdpath.symlink_metadata()?.mtime();
+    metadata.append_to_tar_with_mtime(a, mtime.try_into().unwrap_or(0))?;
+
+    let root = dir.join("root");
+    let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut walk = walkdir::WalkDir::new(&root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter();
+    while let Some(ent) = walk.next().transpose()? {
+        let rel = tree::unprefix(&root, ent.path())?;
+        let Some(key) = rel.to_str() else {
+            bail!("path {:?} is not UTF-8", ent.path());
+        };
+        let path = Path::new("root").join(&rel);
+
+        let md = ent.path().symlink_metadata()?;
+        let ft = md.file_type();
+        let linked = if ft.is_file() && md.nlink() > 1 {
+            owners.inodes.get(&(md.dev(), md.ino()))
+        } else {
+            None
+        };
+        let own =
+            linked
+                .or(owners.paths.get(key))
+                .copied()
+                .unwrap_or(Ownership {
+                    uid: 0,
+                    gid: 0,
+                    mode: None,
+                });
+
+        let mut h = tar::Header::new_gnu();
+        h.set_metadata(&md);
+        h.set_uid(own.uid);
+        h.set_gid(own.gid);
+        h.set_mode(own.mode.unwrap_or(md.mode() & 0o7777));
+
+        if ft.is_file() {
+            if md.nlink() > 1 {
+                if let Some(first) = inodes.get(&(md.dev(), md.ino())) {
+                    h.set_entry_type(tar::EntryType::Link);
+                    h.set_size(0);
+                    a.append_link(&mut h, &path, first)?;
+                    continue;
+                }
+                inodes.insert((md.dev(), md.ino()), path.clone());
+            }
+
+            let f = File::open(ent.path())
+                .map_err(|e| anyhow!("opening {:?}: {e}", ent.path()))?;
+            a.append_data(&mut h, &path, f)?;
+        } else if ft.is_symlink() {
+            let target = std::fs::read_link(ent.path())?;
+            h.set_size(0);
+            a.append_link(&mut h, &path, &target)?;
+        } else if ft.is_dir() || ft.is_fifo() {
+            h.set_size(0);
+            a.append_data(&mut h, &path, std::io::empty())?;
+        } else {
+            bail!(
+                "{:?} is not a regular file, directory, symbolic link, or FIFO",
+                ent.path(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciLayout {
+    image_layout_version: String,
+}
+
+#[derive(Deserialize)]
+struct OciIndex {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+    layers: Vec<Descriptor>,
+}
+
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const LAYER_TYPES: &[&str] = &[
+    "application/vnd.oci.image.layer.v1.tar",
+    "application/vnd.docker.image.rootfs.diff.tar",
+];
+
+/**
+ * A local OCI image layout, holding a single image.  The image configuration
+ * is ignored: we apply the layers in order, as if each had been provided as a
+ * layer archive, and treat the image as a whole as a layer without a name.
+ * The digest of every blob we use is checked when the image is opened.
+ */
+pub struct OciImage {
+    metadata: Metadata,
+    layers: Vec<Unpack>,
+}
+
+impl OciImage {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<OciImage> {
+        let dir = dir.as_ref();
+        check_unsigned(dir)?;
+
+        let lpath = dir.join("oci-layout");
+        let layout: OciLayout = serde_json::from_slice(&std::fs::read(&lpath)?)
+            .map_err(|e| anyhow!("parsing {lpath:?}: {e}"))?;
+        if layout.image_layout_version != "1.0.0" {
+            bail!(
+                "image layout {dir:?} has unsupported version {:?}",
+                layout.image_layout_version,
+            );
+        }
+
+        /*
+         * The top-level index may refer to the image manifest directly, or to
+         * another index that does.
+         */
+        let ipath = dir.join("index.json");
+        let mut index: OciIndex =
+            serde_json::from_slice(&std::fs::read(&ipath)?)
+                .map_err(|e| anyhow!("parsing {ipath:?}: {e}"))?;
+        let manifest: OciManifest = loop {
+            let [desc] = index.manifests.as_slice() else {
+                bail!(
+                    "image layout {dir:?} must contain exactly one image, \
+                    not {}",
+                    index.manifests.len(),
+                );
+            };
+
+            let mut data = Vec::new();
+            blob(dir, desc)?.1.read_to_end(&mut data)?;
+            match desc.media_type.as_str() {
+                OCI_INDEX => {
+                    index = serde_json::from_slice(&data).map_err(|e| {
+                        anyhow!("parsing index {}: {e}", desc.digest)
+                    })?;
+                }
+                OCI_MANIFEST => {
+                    break serde_json::from_slice(&data).map_err(|e| {
+                        anyhow!("parsing manifest {}: {e}", desc.digest)
+                    })?;
+                }
+                other => {
+                    bail!("image layout {dir:?}: unexpected media type {other}")
+                }
+            }
+        };
+
+        let metadata = MetadataBuilder::new(ArchiveType::Layer).build()?;
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|desc| {
+                if !LAYER_TYPES.iter().any(|t| desc.media_type.starts_with(t)) {
+                    bail!(
+                        "layer {} has unsupported media type {}",
+                        desc.digest,
+                        desc.media_type,
+                    );
+                }
+                let (path, f) = blob(dir, desc)?;
+                Unpack::load_oci_layer(f, path, dir, metadata.clone())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(OciImage { metadata, layers })
+    }
+}
+
+impl Image for OciImage {
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn set_threads(&mut self, threads: usize) {
+        for l in self.layers.iter_mut() {
+            l.set_threads(threads);
+        }
+    }
+
+    fn plan(&mut self, plan: &mut Plan) -> Result<()> {
+        for l in self.layers.iter_mut() {
+            l.plan(plan)?;
+        }
+        Ok(())
+    }
+
+    fn unpack(
+        &mut self,
+        outdir: &Path,
+        mut plan: Option<&mut Plan>,
+    ) -> Result<UnpackStats> {
+        let mut stats = UnpackStats::default();
+        for l in self.layers.iter_mut() {
+            stats.add(&l.unpack(outdir, plan.as_deref_mut())?);
+        }
+        Ok(stats)
+    }
+}
+
+/**
+ * Locate the blob for a descriptor and check that its size and digest match.
+ * Returns the path of the blob and the file we checked, positioned at the
+ * start, which is what must then be read, so that the blob cannot be replaced
+ * once it has been checked.
+ */
+fn blob(dir: &Path, desc: &Descriptor) -> Result<(PathBuf, File)> {
+    let hex = match desc.digest.split_once(':') {
+        Some(("sha256", hex))
+            if hex.len() == 64
+                && hex
+                    .bytes()
+                    .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+        {
+            hex
+        }
+        _ => bail!("unsupported digest {:?}", desc.digest),
+    };
+
+    let path = dir.join("blobs").join("sha256").join(hex);
+    let mut f =
+        File::open(&path).map_err(|e| anyhow!("opening {path:?}: {e}"))?;
+    let size = f.metadata()?.len();
+    if size != desc.size {
+        bail!("blob {path:?} is {size} bytes, but should be {}", desc.size);
+    }
+    if metadata::sha256_digest(&f)? != hex {
+        bail!("blob {path:?} does not match its digest");
+    }
+    f.rewind()?;
+
+    Ok((path, f))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helios_build_utils::metadata::EntryKind;
+
+    #[test]
+    fn directory_image() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let img = dir.path();
+        let etc = img.join("root/etc");
+        std::fs::create_dir_all(&etc).unwrap();
+        std::fs::set_permissions(&etc, std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        std::fs::write(
+            img.join("oxide.json"),
+            "{\"v\":\"1\",\"t\":\"layer\"}\n",
+        )
+        .unwrap();
+        std::fs::write(etc.join("motd"), "hello\n").unwrap();
+        std::fs::hard_link(etc.join("motd"), etc.join("issue")).unwrap();
+        std::os::unix::fs::symlink("motd", etc.join("run")).unwrap();
+
+        /*
+         * Ownership listed for the second name of a hard link must still
+         * apply to the first name that we walk.
+         */
+        std::fs::write(
+            img.join(OWNERS_FILE),
+            "{\"etc/motd\":{\"uid\":5,\"gid\":7,\"mode\":\"0640\"}}\n",
+        )
+        .unwrap();
+
+        let mut plan = Plan::new();
+        let mut image = open(img).unwrap();
+        assert!(image.metadata().is_layer());
+        image.plan(&mut plan).unwrap();
+
+        let changes = plan.archives()[0]
+            .changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind, c.mode, c.uid, c.gid))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                ("/etc", EntryKind::Directory, 0o755, 0, 0),
+                ("/etc/issue", EntryKind::File, 0o640, 5, 7),
+                ("/etc/motd", EntryKind::Hardlink, 0o640, 5, 7),
+                ("/etc/run", EntryKind::Symlink, 0o777, 0, 0),
+            ],
+        );
+        assert_eq!(plan.conflicts(), 0);
+
+        /*
+         * Each plan or unpack produces the archive afresh, so the image can be
+         * used more than once.
+         */
+        let mut again = Plan::new();
+        image.plan(&mut again).unwrap();
+        assert_eq!(again.archives()[0].changes.len(), 4);
+
+        std::fs::write(
+            img.join(OWNERS_FILE),
+            "{\"etc/nope\":{\"uid\":0,\"gid\":0}}",
+        )
+        .unwrap();
+        assert!(open(img).is_err());
+    }
+
+    #[test]
+    fn oci_blob_replaced() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let img = dir.path();
+        std::fs::create_dir_all(img.join("blobs/sha256")).unwrap();
+
+        let layer = |data: &[u8]| {
+            let mut a = tar::Builder::new(Vec::new());
+            let mut h = tar::Header::new_ustar();
+            h.set_entry_type(tar::EntryType::Regular);
+            h.set_path("motd").unwrap();
+            h.set_mode(0o644);
+            h.set_uid(0);
+            h.set_gid(0);
+            h.set_mtime(0);
+            h.set_size(data.len().try_into().unwrap());
+            h.set_cksum();
+            a.append(&h, data).unwrap();
+            a.into_inner().unwrap()
+        };
+        let put = |media_type: &str, data: &[u8]| {
+            let hex = metadata::sha256_digest(data).unwrap();
+            let path = img.join("blobs/sha256").join(&hex);
+            std::fs::write(&path, data).unwrap();
+            let desc = serde_json::json!({
+                "mediaType": media_type,
+                "digest": format!("sha256:{hex}"),
+                "size": data.len(),
+            });
+            (path, desc)
+        };
+
+        let good = layer(b"good");
+        let (lpath, ldesc) = put(LAYER_TYPES[0], &good);
+        let manifest = serde_json::json!({ "layers": [ldesc] });
+        let (_, mdesc) =
+            put(OCI_MANIFEST, &serde_json::to_vec(&manifest).unwrap());
+        std::fs::write(
+            img.join("index.json"),
+            serde_json::to_vec(&serde_json::json!({ "manifests": [mdesc] }))
+                .unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            img.join("oci-layout"),
+            "{\"imageLayoutVersion\":\"1.0.0\"}",
+        )
+        .unwrap();
+
+        /*
+         * Replace the layer once it has been checked, with something of the
+         * same size.  We must still unpack what we checked.
+         */
+        let mut oci = OciImage::open(img).unwrap();
+        let bad = layer(b"evil");
+        assert_eq!(good.len(), bad.len());
+        let tmp = img.join("replacement");
+        std::fs::write(&tmp, &bad).unwrap();
+        std::fs::rename(&tmp, &lpath).unwrap();
+
+        let root = dir.path().join("zone");
+        oci.unpack(&root, None).unwrap();
+        assert_eq!(std::fs::read(root.join("motd")).unwrap(), b"good");
+    }
+}
@@ -4,6 +4,13 @@
 
 #[allow(clippy::many_single_char_names)]
 pub mod common;
+pub mod drift;
+pub mod image;
+pub mod manifest;
 pub mod pkg;
+pub mod plan;
+pub mod replica;
+pub mod rootdir;
+pub mod signature;
 pub mod unix;
 pub mod unpack;
@@ -0,0 +1,367 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * A manifest of a zone root, kept in the zonepath but outside the zone root.
+ * It lists every object that was in the zone root when the install finished,
+ * with where the object came from, its type, permissions, and ownership, and
+ * the digest of the contents of each regular file or the target of each
+ * symbolic link.  An object may have been replicated from the global zone,
+ * unpacked from the OS or baseline archive or from a layer, or created by the
+ * brand itself.
+ *
+ * Where an archive provided a path, that archive is its origin, even if the
+ * path was replicated first; e.g., a directory created by replication whose
+ * permissions were then set by the baseline archive.
+ */
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::plan::Plan;
+use crate::replica::{Record, Wants};
+use crate::rootdir::{Kind, RootDir, Stat};
+use helios_build_utils::metadata::{self, ArchiveType};
+use helios_build_utils::tree::Replica;
+
+pub const MANIFEST: &str = "manifest.json";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    /**
+     * A directory or symbolic link recreated from the global zone.
+     */
+    Replicate,
+    /**
+     * A file replaced by a symbolic link to the same file under "/system".
+     */
+    ReplicateLink,
+    /**
+     * A file copied from the global zone.
+     */
+    ReplicateCopy,
+    Os,
+    Baseline,
+    /**
+     * A layer, by the name under which it was passed to the install.
+     */
+    Layer(String),
+    /**
+     * Anything else, such as configuration files that the brand copies from
+     * the global zone.
+     */
+    Other,
+}
+
+impl Origin {
+    pub fn is_replicated(&self) -> bool {
+        matches!(
+            self,
+            Origin::Replicate | Origin::ReplicateLink | Origin::ReplicateCopy
+        )
+    }
+
+    pub fn is_archive(&self) -> bool {
+        matches!(self, Origin::Os | Origin::Baseline | Origin::Layer(_))
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Replicate => f.write_str("replicate"),
+            Origin::ReplicateLink => f.write_str("replicate link"),
+            Origin::ReplicateCopy => f.write_str("replicate copy"),
+            Origin::Os => f.write_str("os"),
+            Origin::Baseline => f.write_str("baseline"),
+            Origin::Layer(name) => write!(f, "layer {name:?}"),
+            Origin::Other => f.write_str("other"),
+        }
+    }
+}
+
+/**
+ * The origin of each path, relative to the zone root, that an archive
+ * provided.
+ */
+pub type Origins = BTreeMap<PathBuf, Origin>;
+
+/**
+ * An archive that was unpacked into the zone root.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Unpacked {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub archive_type: ArchiveType,
+    /**
+     * For a layer of an OCI image layout, the layout it belongs to, which must
+     * be opened to read the layer again.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub origin: Origin,
+    pub kind: Kind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /**
+     * The archives unpacked into the zone root, in the order in which they
+     * were unpacked.
+     */
+    pub archives: Vec<Unpacked>,
+    pub entries: BTreeMap<PathBuf, Entry>,
+}
+
+/**
+ * Determine the archives that were unpacked, and the origin of each path
+ * they provided, from a plan to which each archive was applied as it was
+ * unpacked.
+ */
+pub fn from_plan(plan: &Plan) -> (Vec<Unpacked>, Origins) {
+    let archives = plan
+        .archives()
+        .iter()
+        .map(|a| Unpacked {
+            name: a.name.clone(),
+            archive_type: a.archive_type,
+            image: a.image.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let origins = plan
+        .objects()
+        .iter()
+        .filter_map(|(p, o)| {
+            let a = archives.iter().find(|a| a.name == o.archive)?;
+            let origin = match a.archive_type {
+                ArchiveType::Os => Origin::Os,
+                ArchiveType::Baseline => Origin::Baseline,
+                ArchiveType::Layer => Origin::Layer(a.name.clone()),
+            };
+            Some((p.clone(), origin))
+        })
+        .collect();
+
+    (archives, origins)
+}
+
+impl Manifest {
+    /**
+     * List everything in the zone root.  Paths provided by an archive take
+     * their origin from "origins".  Otherwise, a path is replicated if the
+     * record shows that replication created the object that is there now.
+     */
+    pub fn build(
+        root: &Path,
+        archives: Vec<Unpacked>,
+        origins: &Origins,
+        replicated: Option<(&Record, &Wants)>,
+    ) -> Result<Manifest> {
+        let mut root = RootDir::open(root)?;
+        let mut entries = BTreeMap::new();
+
+        /*
+         * Anything mounted in the zone root of a running zone, such as the
+         * trees under "/system", is not part of the zone root.
+         */
+        let Some(top) = root.lstat(Path::new(""))? else {
+            bail!("zone root {:?} does not exist", root.path());
+        };
+
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            for name in root.read_dir(&dir)? {
+                let p = dir.join(name);
+                let Some(st) = root.lstat(&p)? else {
+                    continue;
+                };
+                if st.dev != top.dev {
+                    continue;
+                }
+
+                let origin = match origins.get(&p) {
+                    Some(o) => o.clone(),
+                    None => replica_origin(&mut root, &p, &st, replicated)?,
+                };
+
+                let (sha256, target) = match st.kind {
+                    Kind::Directory => {
+                        dirs.push(p.clone());
+                        (None, None)
+                    }
+                    Kind::File => (
+                        Some(metadata::sha256_digest(root.open_file(&p)?)?),
+                        None,
+                    ),
+                    Kind::Symlink => (None, Some(root.read_link(&p)?)),
+                    Kind::Fifo | Kind::Other => (None, None),
+                };
+
+                entries.insert(
+                    p,
+                    Entry {
+                        origin,
+                        kind: st.kind,
+                        mode: st.mode,
+                        uid: st.uid,
+                        gid: st.gid,
+                        sha256,
+                        target,
+                    },
+                );
+            }
+        }
+
+        Ok(Manifest { archives, entries })
+    }
+
+    /**
+     * The origin of each path that an archive provided, so that the manifest
+     * can be built again once the replicated objects have changed.
+     */
+    pub fn origins(&self) -> Origins {
+        self.entries
+            .iter()
+            .filter(|(_, e)| e.origin.is_archive())
+            .map(|(p, e)| (p.clone(), e.origin.clone()))
+            .collect()
+    }
+
+    /**
+     * Load a manifest.  Returns None if there is no manifest at the path.
+     */
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Manifest>> {
+        let path = path.as_ref();
+        match std::fs::read(path) {
+            Ok(buf) => Ok(Some(
+                serde_json::from_slice(&buf)
+                    .map_err(|e| anyhow!("parsing {path:?}: {e}"))?,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => bail!("reading {path:?}: {e}"),
+        }
+    }
+
+    /**
+     * Store the manifest, replacing any existing manifest at the path only
+     * once the new one has been written completely.
+     */
+    pub fn store<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let Some(dir) = path.parent() else {
+            bail!("manifest path {path:?} has no parent directory");
+        };
+
+        let mut tf = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(&mut tf, self)?;
+        tf.write_all(b"\n")?;
+        tf.as_file().sync_all()?;
+        tf.persist(path)
+            .map_err(|e| anyhow!("writing {path:?}: {}", e.error))?;
+        Ok(())
+    }
+}
+
+fn replica_origin(
+    root: &mut RootDir,
+    p: &Path,
+    st: &Stat,
+    replicated: Option<(&Record, &Wants)>,
+) -> Result<Origin> {
+    let Some((record, wants)) = replicated else {
+        return Ok(Origin::Other);
+    };
+    let (Some(entry), Some(want)) = (record.entries.get(p), wants.get(p))
+    else {
+        return Ok(Origin::Other);
+    };
+    if !entry.owns(root, p, st)? {
+        return Ok(Origin::Other);
+    }
+
+    Ok(match want.replica {
+        Replica::Link(_) => Origin::ReplicateLink,
+        Replica::Copy => Origin::ReplicateCopy,
+        Replica::Directory | Replica::Symlink => Origin::Replicate,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replica;
+    use helios_build_utils::metadata::EntryKind;
+    use helios_build_utils::replicate::Rules;
+    use helios_build_utils::tree::Exclude;
+
+    #[test]
+    fn origins() {
+        let gz = tempfile::tempdir().unwrap();
+        let zone = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "link *.so.1\ncopy /**\n").unwrap();
+        let gp = |p: &str| gz.path().join(p);
+        let zp = |p: &str| zone.path().join(p);
+
+        std::fs::create_dir_all(gp("usr/lib")).unwrap();
+        std::fs::write(gp("usr/lib/libc.so.1"), "libc").unwrap();
+        std::fs::write(gp("usr/lib/a.conf"), "one").unwrap();
+        std::fs::write(gp("usr/lib/b.conf"), "two").unwrap();
+
+        let wants =
+            replica::wants(gz.path(), &["usr"], &rules, &Exclude::default())
+                .unwrap();
+        let (record, _) =
+            replica::resync(zone.path(), &Default::default(), &wants, None)
+                .unwrap();
+
+        /*
+         * Replace one copy as a layer would, and add a file of our own.
+         */
+        let mut plan = Plan::new();
+        plan.begin("layer.tar", ArchiveType::Layer);
+        let b = Path::new("usr/lib/b.conf");
+        plan.entry(b, EntryKind::File, 0o600, (0, 0), None);
+        std::fs::remove_file(zp("usr/lib/b.conf")).unwrap();
+        std::fs::write(zp("usr/lib/b.conf"), "mine").unwrap();
+        std::fs::write(zp("usr/lib/c.conf"), "mine").unwrap();
+
+        let (archives, origins) = from_plan(&plan);
+        let m = Manifest::build(
+            zone.path(),
+            archives,
+            &origins,
+            Some((&record, &wants)),
+        )
+        .unwrap();
+
+        let origin = |p: &str| m.entries[Path::new(p)].origin.clone();
+        assert_eq!(origin("usr/lib"), Origin::Replicate);
+        assert_eq!(origin("usr/lib/libc.so.1"), Origin::ReplicateLink);
+        assert_eq!(origin("usr/lib/a.conf"), Origin::ReplicateCopy);
+        assert_eq!(origin("usr/lib/b.conf"), Origin::Layer("layer.tar".into()));
+        assert_eq!(origin("usr/lib/c.conf"), Origin::Other);
+        assert_eq!(
+            m.entries[b].sha256.as_deref(),
+            Some(metadata::sha256_digest("mine".as_bytes()).unwrap().as_str()),
+        );
+        assert_eq!(m.origins().len(), 1);
+    }
+}
@@ -13,6 +13,8 @@ use helios_build_utils::ips;
 
 pub const ROOT_IMAGE: Option<&Path> = None;
 
+pub const OSNET_INCORPORATION: &str = "consolidation/osnet/osnet-incorporation";
+
 const PKG: &str = "/usr/bin/pkg";
 
 fn pkg<P: AsRef<Path>>(image: Option<P>, subcmd: &str) -> Command {
@@ -147,6 +149,43 @@ pub fn pkg_list<P: AsRef<Path>>(image: Option<P>) -> Result<Vec<ips::Package>> {
     }
 }
 
+/**
+ * List the configured publishers in an image, as (name, origin) pairs.  A
+ * publisher with several origins appears once for each origin.
+ */
+pub fn pkg_publishers<P: AsRef<Path>>(
+    image: Option<P>,
+) -> Result<Vec<(String, String)>> {
+    let res = pkg(image, "publisher")
+        .arg("-H")
+        .arg("-F")
+        .arg("tsv")
+        .output()?;
+
+    if res.status.success() {
+        let stdout = String::from_utf8(res.stdout)?;
+
+        let mut out = Vec::new();
+        for l in stdout.lines() {
+            /*
+             * The columns are: publisher, sticky, syspub, enabled, type,
+             * status, uri, and proxy.
+             */
+            let t = l.split('\t').collect::<Vec<_>>();
+            if t.len() < 7 {
+                bail!("weird publisher line {:?}", t);
+            }
+            out.push((t[0].to_string(), t[6].to_string()));
+        }
+        Ok(out)
+    } else {
+        bail!(
+            "pkg publisher error: {:?}",
+            String::from_utf8_lossy(&res.stderr).trim()
+        );
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[expect(unused)]
 struct FacetDescription {
@@ -0,0 +1,529 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * A dry run of an install.  Each archive is walked in the order in which it
+ * would be unpacked, and applied to a model of the zone root rather than to
+ * the disk, so that we can report which paths each archive creates, replaces,
+ * or removes, and any conflict that would cause the real unpack to fail.
+ *
+ * The model only knows about paths that appear in the archives.  Anything
+ * else, such as the files replicated from the global zone, is assumed to be a
+ * directory if an archive needs it to be one.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use crate::unpack::{spared, Names};
+
+use helios_build_utils::metadata::{ArchiveType, EntryKind};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /**
+     * The path does not exist yet.
+     */
+    Create,
+    /**
+     * The path exists and will be replaced with an object of the same type.
+     */
+    Replace,
+    /**
+     * The path exists and will be replaced with an object of another type.
+     */
+    Retype,
+    /**
+     * The path is an existing directory whose mode or ownership will change.
+     */
+    Update,
+    /**
+     * The path will be removed by a whiteout marker.
+     */
+    Remove,
+    /**
+     * Unpacking the entry would fail.
+     */
+    Conflict,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Action::Create => "create",
+            Action::Replace => "replace",
+            Action::Retype => "retype",
+            Action::Update => "update",
+            Action::Remove => "remove",
+            Action::Conflict => "CONFLICT",
+        })
+    }
+}
+
+/**
+ * An object in the model of the zone root, and the archive that provided it.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct Object {
+    pub archive: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Change {
+    pub path: String,
+    pub action: Action,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous: Option<Object>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Archive {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub archive_type: ArchiveType,
+    /**
+     * For a layer of an OCI image layout, the layout it belongs to.
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    pub changes: Vec<Change>,
+    /**
+     * An error that is not specific to one path, such as a manifest mismatch
+     * or a corrupt archive, would stop the unpack at this point.
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+    archives: Vec<Archive>,
+    #[serde(skip)]
+    tree: BTreeMap<PathBuf, Object>,
+    /**
+     * The paths applied so far from the current archive, which its whiteout
+     * markers do not remove.
+     */
+    #[serde(skip)]
+    applied: BTreeSet<PathBuf>,
+    /**
+     * The user and group databases that the archives have provided so far,
+     * against which owner and group names are resolved.
+     */
+    #[serde(skip)]
+    names: Names,
+}
+
+fn type_name(t: ArchiveType) -> &'static str {
+    match t {
+        ArchiveType::Baseline => "baseline",
+        ArchiveType::Layer => "layer",
+        ArchiveType::Os => "os",
+    }
+}
+
+impl Plan {
+    pub fn new() -> Plan {
+        Default::default()
+    }
+
+    pub(crate) fn names(&mut self) -> &mut Names {
+        &mut self.names
+    }
+
+    pub fn archives(&self) -> &[Archive] {
+        &self.archives
+    }
+
+    /**
+     * The objects in the model, by path relative to the zone root, each with
+     * the archive that last provided it.
+     */
+    pub fn objects(&self) -> &BTreeMap<PathBuf, Object> {
+        &self.tree
+    }
+
+    /**
+     * Start planning the next archive.  Subsequent entries and errors are
+     * recorded against it.
+     */
+    pub fn begin(&mut self, name: &str, archive_type: ArchiveType) {
+        self.applied.clear();
+        self.archives.push(Archive {
+            name: name.to_string(),
+            archive_type,
+            image: None,
+            changes: Default::default(),
+            error: None,
+        });
+    }
+
+    /**
+     * Note that the current archive is one of the layers of an image.
+     */
+    pub fn set_image(&mut self, image: &str) {
+        self.current().image = Some(image.to_string());
+    }
+
+    fn current(&mut self) -> &mut Archive {
+        self.archives.last_mut().expect("begin() not called")
+    }
+
+    pub fn fail(&mut self, error: &str) {
+        self.current().error = Some(error.to_string());
+    }
+
+    /**
+     * Apply an archive entry to the model.  The path, and for a hard link the
+     * existing object, are relative to the zone root.  An entry that would
+     * conflict is recorded but leaves the model unchanged.
+     */
+    pub fn entry(
+        &mut self,
+        rel: &Path,
+        kind: EntryKind,
+        mode: u32,
+        (uid, gid): (u32, u32),
+        link: Option<&Path>,
+    ) {
+        let previous = self.tree.get(rel).cloned();
+        let mut change = Change {
+            path: Path::new("/").join(rel).to_string_lossy().to_string(),
+            action: Action::Create,
+            kind,
+            mode,
+            uid,
+            gid,
+            previous: previous.clone(),
+            reason: None,
+        };
+
+        if let Some(reason) = self.conflict(rel, kind, previous.as_ref(), link)
+        {
+            change.action = Action::Conflict;
+            change.reason = Some(reason);
+            self.current().changes.push(change);
+            return;
+        }
+        self.applied.insert(rel.to_path_buf());
+
+        /*
+         * A hard link shares the mode and ownership of the object it refers
+         * to, if we know about it.
+         */
+        let mut obj = Object {
+            archive: self.current().name.clone(),
+            kind,
+            mode,
+            uid,
+            gid,
+        };
+        if kind == EntryKind::Hardlink {
+            obj.kind = EntryKind::File;
+            if let Some(existing) = link.and_then(|l| self.tree.get(l)) {
+                obj.kind = existing.kind;
+                obj.mode = existing.mode;
+                obj.uid = existing.uid;
+                obj.gid = existing.gid;
+            }
+        }
+
+        change.action = match &previous {
+            None => Action::Create,
+            Some(p) if p.kind == EntryKind::Directory => {
+                if p.mode == mode && p.uid == uid && p.gid == gid {
+                    /*
+                     * The directory is left exactly as it was.
+                     */
+                    return;
+                }
+                Action::Update
+            }
+            Some(p) if p.kind == obj.kind => Action::Replace,
+            Some(_) => Action::Retype,
+        };
+
+        self.tree.insert(rel.to_path_buf(), obj);
+        self.current().changes.push(change);
+    }
+
+    /**
+     * Apply a whiteout marker to the model, removing the path and anything
+     * beneath it, or if "opaque" is set, only the contents of the directory.
+     * Paths applied from the current archive are spared.  Each removed path
+     * that the model knows about is recorded.
+     */
+    pub fn remove(&mut self, rel: &Path, opaque: bool) {
+        let doomed = self
+            .tree
+            .range::<Path, _>((Bound::Included(rel), Bound::Unbounded))
+            .take_while(|(p, _)| p.starts_with(rel))
+            .filter(|(p, _)| !(opaque && p.as_path() == rel))
+            .filter(|(p, _)| !spared(&self.applied, p))
+            .map(|(p, _)| p.clone())
+            .collect::<Vec<_>>();
+
+        for p in doomed {
+            let o = self.tree.remove(&p).unwrap();
+            let change = Change {
+                path: Path::new("/").join(&p).to_string_lossy().to_string(),
+                action: Action::Remove,
+                kind: o.kind,
+                mode: o.mode,
+                uid: o.uid,
+                gid: o.gid,
+                previous: Some(o),
+                reason: None,
+            };
+            self.current().changes.push(change);
+        }
+    }
+
+    /**
+     * Determine whether unpacking would fail for this entry, given the current
+     * state of the model.  These checks mirror the ones made in
+     * Unpack::unpack().
+     */
+    fn conflict(
+        &self,
+        rel: &Path,
+        kind: EntryKind,
+        previous: Option<&Object>,
+        link: Option<&Path>,
+    ) -> Option<String> {
+        /*
+         * Paths are resolved without following symbolic links, so every
+         * component leading up to an entry must be a directory.
+         */
+        let parents = |p: &Path| -> Option<String> {
+            p.ancestors().skip(1).find_map(|a| {
+                let o = self.tree.get(a)?;
+                (o.kind != EntryKind::Directory).then(|| {
+                    format!(
+                        "parent {:?} is a {:?} from {:?}, not a directory",
+                        Path::new("/").join(a),
+                        o.kind,
+                        o.archive,
+                    )
+                })
+            })
+        };
+
+        if let Some(reason) = parents(rel) {
+            return Some(reason);
+        }
+
+        if let Some(p) = previous {
+            let dir = kind == EntryKind::Directory;
+            if dir != (p.kind == EntryKind::Directory) {
+                return Some(format!(
+                    "path is a {:?} from {:?}, not a {}",
+                    p.kind,
+                    p.archive,
+                    if dir { "dir" } else { "file or symlink" },
+                ));
+            }
+        }
+
+        if kind == EntryKind::Hardlink {
+            let existing = link?;
+            if let Some(reason) = parents(existing) {
+                return Some(format!("hard link target: {reason}"));
+            }
+            if let Some(o) = self.tree.get(existing) {
+                if o.kind == EntryKind::Directory {
+                    return Some(format!(
+                        "hard link target {:?} is a directory",
+                        Path::new("/").join(existing),
+                    ));
+                }
+            }
+        }
+
+        None
+    }
+
+    /**
+     * The number of entries, or whole archives, that would fail to unpack.
+     */
+    pub fn conflicts(&self) -> usize {
+        self.archives
+            .iter()
+            .map(|a| {
+                a.changes
+                    .iter()
+                    .filter(|c| c.action == Action::Conflict)
+                    .count()
+                    + usize::from(a.error.is_some())
+            })
+            .sum()
+    }
+}
+
+/**
+ * The text form of the plan lists every change made by each layer.  The
+ * baseline and OS archives create a great many paths, so for those only the
+ * number of paths created is reported, along with any other changes.
+ */
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for a in self.archives.iter() {
+            let summarise = !matches!(a.archive_type, ArchiveType::Layer);
+            let created = a
+                .changes
+                .iter()
+                .filter(|c| c.action == Action::Create)
+                .count();
+
+            write!(f, "archive {:?} ({})", a.name, type_name(a.archive_type))?;
+            if summarise {
+                write!(f, ": {created} paths created")?;
+            }
+            writeln!(f)?;
+
+            for c in a.changes.iter() {
+                if summarise && c.action == Action::Create {
+                    continue;
+                }
+
+                write!(
+                    f,
+                    "    {:<8} {:<9} {}",
+                    c.action.to_string(),
+                    format!("{:?}", c.kind).to_lowercase(),
+                    c.path,
+                )?;
+
+                if let Some(reason) = &c.reason {
+                    writeln!(f, ": {reason}")?;
+                    continue;
+                }
+
+                let mut notes = Vec::new();
+                if let Some(p) = &c.previous {
+                    notes.push(format!("from {:?}", p.archive));
+                    if c.action == Action::Retype {
+                        notes.push(format!(
+                            "was a {}",
+                            format!("{:?}", p.kind).to_lowercase(),
+                        ));
+                    }
+                    if p.mode != c.mode {
+                        notes
+                            .push(format!("mode {:o} -> {:o}", p.mode, c.mode));
+                    }
+                    if p.uid != c.uid || p.gid != c.gid {
+                        notes.push(format!(
+                            "owner {}:{} -> {}:{}",
+                            p.uid, p.gid, c.uid, c.gid,
+                        ));
+                    }
+                } else {
+                    notes.push(format!(
+                        "mode {:o} owner {}:{}",
+                        c.mode, c.uid, c.gid,
+                    ));
+                }
+                writeln!(f, " ({})", notes.join("; "))?;
+            }
+
+            if let Some(e) = &a.error {
+                writeln!(f, "    ERROR: {e}")?;
+            }
+        }
+
+        writeln!(f, "{} conflicts", self.conflicts())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn layers_over_baseline() {
+        let mut p = Plan::new();
+        let root = (0, 0);
+
+        p.begin("files.tar.gz", ArchiveType::Baseline);
+        p.entry(Path::new("etc"), EntryKind::Directory, 0o755, (0, 3), None);
+        p.entry(Path::new("etc/motd"), EntryKind::File, 0o644, root, None);
+        p.entry(Path::new("etc/run"), EntryKind::Symlink, 0o777, root, None);
+
+        p.begin("layer.tar.gz", ArchiveType::Layer);
+        p.entry(Path::new("etc"), EntryKind::Directory, 0o755, (0, 3), None);
+        p.entry(Path::new("etc/motd"), EntryKind::File, 0o600, root, None);
+        p.entry(
+            Path::new("etc/run"),
+            EntryKind::Directory,
+            0o755,
+            root,
+            None,
+        );
+        p.entry(Path::new("etc/motd/x"), EntryKind::File, 0o644, root, None);
+        p.entry(Path::new("etc"), EntryKind::Directory, 0o700, root, None);
+        p.entry(
+            Path::new("etc/issue"),
+            EntryKind::Hardlink,
+            0o644,
+            root,
+            Some(Path::new("etc/motd")),
+        );
+
+        let c = &p.archives()[1].changes;
+        let actions = c.iter().map(|c| c.action).collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                Action::Replace,
+                Action::Conflict,
+                Action::Conflict,
+                Action::Update,
+                Action::Create,
+            ],
+        );
+        assert_eq!(c[0].previous.as_ref().unwrap().mode, 0o644);
+        assert_eq!(c[1].previous.as_ref().unwrap().kind, EntryKind::Symlink);
+        assert!(c[2].reason.as_ref().unwrap().contains("not a directory"));
+        assert_eq!(p.conflicts(), 2);
+
+        /*
+         * Conflicting entries leave the model as it was, and the hard link
+         * takes its attributes from the file it refers to.
+         */
+        let issue = p.tree.get(Path::new("etc/issue")).unwrap();
+        assert_eq!((issue.kind, issue.mode), (EntryKind::File, 0o600));
+        let run = p.tree.get(Path::new("etc/run")).unwrap();
+        assert_eq!(run.kind, EntryKind::Symlink);
+
+        /*
+         * An opaque whiteout removes what earlier archives provided within
+         * the directory, but not what the same archive has already provided.
+         */
+        p.begin("whiteout.tar.gz", ArchiveType::Layer);
+        p.entry(Path::new("etc/new"), EntryKind::File, 0o644, root, None);
+        p.remove(Path::new("etc"), true);
+        let removed = p.archives()[2]
+            .changes
+            .iter()
+            .filter(|c| c.action == Action::Remove)
+            .map(|c| c.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(removed, vec!["/etc/issue", "/etc/motd", "/etc/run"]);
+        assert!(p.tree.contains_key(Path::new("etc/new")));
+    }
+}
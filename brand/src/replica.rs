@@ -0,0 +1,800 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * When a zone is installed without an OS archive, /usr, /lib, and /sbin are
+ * replicated from the global zone.  We keep a record, in the zonepath but
+ * outside the zone root, of each object that replication created, along with
+ * enough about that object to tell later whether it is still the one we
+ * created.  The baseline archive and any layers are unpacked after the record
+ * is taken, and anything they replace no longer matches the record.
+ *
+ * After the global zone is updated, the replicated trees can be brought up to
+ * date without reinstalling the zone.  Only objects that still match the
+ * record are replaced or removed; anything else, whether it came from the
+ * baseline, a layer, or was put there by hand, is left alone.
+ */
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::rootdir::{Kind, RootDir, Stat};
+use helios_build_utils::copyq::{Attrs, CopyQueue};
+use helios_build_utils::defaults::{DefaultsFile, BRAND_DEFAULTS};
+use helios_build_utils::replicate::Rules;
+use helios_build_utils::store::Store;
+use helios_build_utils::tree::{self, Exclude, HardLinks, Replica};
+
+/**
+ * The name of the record file within the zonepath.
+ */
+pub const RECORD: &str = "replicated.json";
+
+/**
+ * The size and modification time of a file, which we use to notice that it
+ * has changed.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+impl From<&Stat> for Stamp {
+    fn from(st: &Stat) -> Self {
+        Stamp {
+            size: st.size,
+            mtime: st.mtime,
+            mtime_nsec: st.mtime_nsec,
+        }
+    }
+}
+
+impl From<&std::fs::Metadata> for Stamp {
+    fn from(md: &std::fs::Metadata) -> Self {
+        Stamp {
+            size: md.size(),
+            mtime: md.mtime(),
+            mtime_nsec: md.mtime_nsec(),
+        }
+    }
+}
+
+/**
+ * The trees that are replicated from the global zone when no OS archive is
+ * provided.
+ */
+pub const TREES: &[&str] = &["usr", "lib", "sbin"];
+
+/**
+ * SMF manifests are left out of the replicated trees in favour of those in
+ * the baseline archive.
+ */
+pub const SMF_MANIFESTS: &str = "lib/svc/manifest";
+
+/**
+ * The list of files that the baseline says are global-zone only, which is
+ * kept alongside the baseline archive.
+ */
+pub const GZONLY: &str = "gzonly.txt";
+
+/**
+ * Determine what to leave out when replicating the global zone, rooted at
+ * "gz".  For now, we leave out all of the SMF manifests from the global zone
+ * and use the ones from the baseline package instead.  This will match the
+ * preseed database exactly, and no stragglers will slip through.  We also
+ * leave out any files listed in "gzonly".
+ */
+pub fn exclude(gz: &Path, gzonly: &Path) -> Result<Exclude> {
+    let mut exclude = Exclude::default();
+    exclude.subtree(gz.join(SMF_MANIFESTS));
+
+    let list = std::fs::read_to_string(gzonly)
+        .map_err(|e| anyhow!("reading {gzonly:?}: {e}"))?;
+    for l in list.lines() {
+        let rel = PathBuf::from(l);
+        if rel.is_absolute() {
+            bail!("absolute path in baseline remove list: {rel:?}");
+        }
+        exclude.object(gz.join(rel));
+    }
+    Ok(exclude)
+}
+
+/**
+ * What replication should produce at a path in the zone root, given the
+ * current contents of the global zone and the replication rules.
+ */
+#[derive(Clone, Debug)]
+pub struct Want {
+    pub replica: Replica,
+    /**
+     * The path of the object in the global zone.
+     */
+    pub src: PathBuf,
+    /**
+     * The target of a symbolic link.
+     */
+    pub target: Option<PathBuf>,
+    /**
+     * The stamp of a file in the global zone that is copied.
+     */
+    pub source: Option<Stamp>,
+}
+
+impl Want {
+    fn kind(&self) -> Kind {
+        match self.replica {
+            Replica::Directory => Kind::Directory,
+            Replica::Symlink | Replica::Link(_) => Kind::Symlink,
+            Replica::Copy => Kind::File,
+        }
+    }
+}
+
+pub type Wants = BTreeMap<PathBuf, Want>;
+
+/**
+ * Determine what replicating each of the trees from the global zone, rooted
+ * at "gz", would produce.  Paths are relative to the zone root.  Excluded
+ * objects are left out, just as replication leaves them out.
+ */
+pub fn wants(
+    gz: &Path,
+    trees: &[&str],
+    rules: &Rules,
+    exclude: &Exclude,
+) -> Result<Wants> {
+    let mut out = Wants::new();
+
+    for t in trees {
+        let src = gz.join(t);
+        tree::replicas(
+            &src,
+            &format!("/system/{t}"),
+            rules,
+            exclude,
+            |ent, replica| {
+                let rel = Path::new(t).join(ent.path().strip_prefix(&src)?);
+
+                let (target, source) = match &replica {
+                    Replica::Symlink => {
+                        (Some(std::fs::read_link(ent.path())?), None)
+                    }
+                    Replica::Link(t) => (Some(PathBuf::from(t)), None),
+                    Replica::Copy => {
+                        (None, Some(Stamp::from(&ent.metadata()?)))
+                    }
+                    Replica::Directory => (None, None),
+                };
+
+                out.insert(
+                    rel,
+                    Want {
+                        replica,
+                        src: ent.path().to_path_buf(),
+                        target,
+                        source,
+                    },
+                );
+                Ok(true)
+            },
+        )?;
+    }
+
+    Ok(out)
+}
+
+/**
+ * An object that replication created in the zone root.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub kind: Kind,
+    pub ino: u64,
+    #[serde(flatten)]
+    pub stamp: Stamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Stamp>,
+}
+
+impl Entry {
+    fn new(st: &Stat, want: &Want) -> Entry {
+        Entry {
+            kind: st.kind,
+            ino: st.ino,
+            stamp: Stamp::from(st),
+            target: want.target.clone(),
+            source: want.source,
+        }
+    }
+
+    /**
+     * Is the object we found in the zone root still the one we created?
+     */
+    pub(crate) fn owns(
+        &self,
+        root: &mut RootDir,
+        p: &Path,
+        st: &Stat,
+    ) -> Result<bool> {
+        if st.kind != self.kind || st.ino != self.ino {
+            return Ok(false);
+        }
+
+        Ok(match self.kind {
+            Kind::Directory => true,
+            Kind::File => Stamp::from(st) == self.stamp,
+            Kind::Symlink => self.target.as_ref() == Some(&root.read_link(p)?),
+            Kind::Fifo | Kind::Other => false,
+        })
+    }
+
+    /**
+     * Does the object we created match what replication would now produce?
+     */
+    fn current(&self, want: &Want) -> bool {
+        match want.replica {
+            Replica::Directory => self.kind == Kind::Directory,
+            Replica::Symlink | Replica::Link(_) => {
+                self.kind == Kind::Symlink && self.target == want.target
+            }
+            Replica::Copy => {
+                self.kind == Kind::File && self.source == want.source
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Record {
+    pub entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Record {
+    /**
+     * Record the objects in a freshly replicated zone root that match what
+     * replication was to produce.
+     */
+    pub fn scan(root: &Path, wants: &Wants) -> Result<Record> {
+        let mut root = RootDir::open(root)?;
+        let found = survey(&mut root, wants.keys())?;
+
+        let mut entries = BTreeMap::new();
+        for (p, want) in wants {
+            let Some(st) = found.get(p).copied().flatten() else {
+                continue;
+            };
+            if st.kind != want.kind() {
+                continue;
+            }
+            if st.kind == Kind::Symlink
+                && want.target.as_ref() != Some(&root.read_link(p)?)
+            {
+                continue;
+            }
+
+            entries.insert(p.clone(), Entry::new(&st, want));
+        }
+
+        Ok(Record { entries })
+    }
+
+    /**
+     * Load a record.  Returns None if there is no record at the path.
+     */
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Record>> {
+        let path = path.as_ref();
+        match std::fs::read(path) {
+            Ok(buf) => Ok(Some(
+                serde_json::from_slice(&buf)
+                    .map_err(|e| anyhow!("parsing {path:?}: {e}"))?,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => bail!("reading {path:?}: {e}"),
+        }
+    }
+
+    /**
+     * Store the record, replacing any existing record at the path only once
+     * the new one has been written completely.
+     */
+    pub fn store<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let Some(dir) = path.parent() else {
+            bail!("record path {path:?} has no parent directory");
+        };
+
+        let mut tf = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(&mut tf, self)?;
+        tf.write_all(b"\n")?;
+        tf.as_file().sync_all()?;
+        tf.persist(path)
+            .map_err(|e| anyhow!("writing {path:?}: {}", e.error))?;
+        Ok(())
+    }
+}
+
+/**
+ * Look up each of a set of paths, in order, skipping any path whose parent
+ * is not a directory that we have already found.  Every parent must appear
+ * in the set before its children, which is true of the paths produced by a
+ * walk of the global zone and of any subset of them that is closed under
+ * taking parents.
+ */
+fn survey<'a>(
+    root: &mut RootDir,
+    paths: impl Iterator<Item = &'a PathBuf>,
+) -> Result<BTreeMap<PathBuf, Option<Stat>>> {
+    let mut found = BTreeMap::new();
+    let mut dirs = BTreeSet::new();
+    dirs.insert(PathBuf::new());
+
+    for p in paths {
+        let st = if dirs.contains(p.parent().unwrap_or(Path::new(""))) {
+            root.lstat(p)?
+        } else {
+            None
+        };
+        if st.is_some_and(|st| st.kind == Kind::Directory) {
+            dirs.insert(p.clone());
+        }
+        found.insert(p.clone(), st);
+    }
+
+    Ok(found)
+}
+
+#[derive(Debug, Default)]
+pub struct ResyncStats {
+    pub added: u64,
+    pub replaced: u64,
+    pub removed: u64,
+    pub unchanged: u64,
+    pub left_alone: u64,
+}
+
+impl std::fmt::Display for ResyncStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} replaced, {} removed, {} unchanged, {} left alone",
+            self.added,
+            self.replaced,
+            self.removed,
+            self.unchanged,
+            self.left_alone,
+        )
+    }
+}
+
+/**
+ * Bring the replicated objects in a zone root up to date with what
+ * replication would now produce.  Objects that do not match the record are
+ * never modified or removed.  If a store is provided, files are copied into
+ * it, if they are not there already, and linked into the zone root from
+ * there, as at install; the store then notes each file the zone uses,
+ * including those left unchanged.  Returns the new record, and a summary of
+ * what was done.
+ */
+pub fn resync(
+    root: &Path,
+    record: &Record,
+    wants: &Wants,
+    mut store: Option<&mut Store>,
+) -> Result<(Record, ResyncStats)> {
+    let mut root = RootDir::open(root)?;
+    let mut stats = ResyncStats::default();
+
+    let paths = record
+        .entries
+        .keys()
+        .chain(wants.keys())
+        .collect::<BTreeSet<_>>();
+    let mut found = survey(&mut root, paths.into_iter())?;
+
+    /*
+     * First, remove the objects we created that replication would no longer
+     * produce, or that must change between a directory and something else.
+     * We walk in reverse so that the contents of a directory are removed
+     * before the directory itself.  A directory that still has something in
+     * it that we did not create is left where it is.
+     */
+    for (p, ent) in record.entries.iter().rev() {
+        let keep = wants.get(p).is_some_and(|w| {
+            (w.replica == Replica::Directory) == (ent.kind == Kind::Directory)
+        });
+        if keep {
+            continue;
+        }
+        let Some(st) = found.get(p).copied().flatten() else {
+            continue;
+        };
+        if !ent.owns(&mut root, p, &st)? {
+            continue;
+        }
+
+        if st.kind == Kind::Directory {
+            if !root.read_dir(p)?.is_empty() {
+                continue;
+            }
+            root.remove_dir(p)?;
+        } else {
+            root.remove_file(p)?;
+        }
+        found.insert(p.clone(), None);
+        stats.removed += 1;
+    }
+
+    /*
+     * Then create or replace objects in the order of the walk, so that each
+     * directory exists before its contents.  If something other than a
+     * directory is in the way of a directory we want, nothing beneath it is
+     * created.
+     */
+    let df = DefaultsFile::from_path(BRAND_DEFAULTS)?;
+    let mut cq = CopyQueue::new(
+        df.get_usize("COPY_THREADS").unwrap_or(8),
+        df.get_usize("COPY_BATCH").unwrap_or(128),
+    )?;
+
+    let mut links = HardLinks::default();
+    let mut stored = Vec::new();
+    let mut entries = BTreeMap::new();
+    let mut created = Vec::new();
+    let mut finish = Vec::new();
+    let mut dirs = BTreeSet::new();
+    dirs.insert(PathBuf::new());
+
+    for (p, want) in wants {
+        if !dirs.contains(p.parent().unwrap_or(Path::new(""))) {
+            stats.left_alone += 1;
+            continue;
+        }
+
+        if let Some(st) = found.get(p).copied().flatten() {
+            let old = record.entries.get(p);
+            let owned = match old {
+                Some(ent) => ent.owns(&mut root, p, &st)?,
+                None => false,
+            };
+
+            if !owned {
+                if st.kind == Kind::Directory {
+                    dirs.insert(p.clone());
+                }
+                stats.left_alone += 1;
+                continue;
+            }
+
+            let old = old.unwrap();
+            if old.current(want) {
+                if st.kind == Kind::Directory {
+                    dirs.insert(p.clone());
+                }
+                if want.replica == Replica::Copy {
+                    let md = want.src.metadata()?;
+                    links.existing(&md, p);
+                    if let Some(store) = store.as_deref_mut() {
+                        store.retain(&Store::key(&md), st.ino)?;
+                    }
+                }
+                entries.insert(p.clone(), old.clone());
+                stats.unchanged += 1;
+                continue;
+            }
+
+            /*
+             * Directories we own always match, and any change between a
+             * directory and something else was dealt with above, so this is
+             * a file or link to be replaced.
+             */
+            root.remove_file(p)?;
+            stats.replaced += 1;
+        } else {
+            stats.added += 1;
+        }
+
+        match &want.replica {
+            Replica::Directory => {
+                root.create_dir(p)?;
+                finish.push((p, Attrs::from(&want.src.symlink_metadata()?)));
+                dirs.insert(p.clone());
+            }
+            Replica::Symlink | Replica::Link(_) => {
+                let attrs = Attrs::from(&want.src.symlink_metadata()?);
+                root.symlink(want.target.as_ref().unwrap(), p)?;
+                root.lchown(p, attrs.uid, attrs.gid)?;
+                root.set_mtime(p, attrs.modified())?;
+            }
+            Replica::Copy => {
+                /*
+                 * As at install, a file with several links in the global
+                 * zone is copied once and then linked at its other names,
+                 * and copies go by way of the store if there is one.
+                 */
+                let md = want.src.metadata()?;
+                if !links.first(&md, p) {
+                    /*
+                     * Linked to the first copy below.
+                     */
+                } else if let Some(store) = store.as_deref_mut() {
+                    let key = Store::key(&md);
+                    match store.lookup(&key)? {
+                        Some(tmp) => {
+                            cq.push_copy(want.src.clone(), tmp.clone());
+                            stored.push((key, tmp, p));
+                        }
+                        None => {
+                            links.push(store.object(&key), p.clone(), md.len());
+                        }
+                    }
+                } else {
+                    let f = root.create_file(p)?;
+                    cq.push_copy_to(want.src.clone(), root.path().join(p), f);
+                }
+            }
+        }
+        created.push(p);
+    }
+
+    let mut cs = cq.join()?;
+
+    if let Some(store) = store.as_deref() {
+        for (key, tmp, p) in stored {
+            let obj = store.commit(&key, &tmp)?;
+            root.link_in(&obj, p)?;
+        }
+    }
+
+    /*
+     * Files are linked either to a copy elsewhere in the zone root or to one
+     * in the store, which is named by an absolute path.
+     */
+    links.link(&mut cs, |first, p| {
+        if first.is_absolute() {
+            root.link_in(first, p)
+        } else {
+            root.hard_link(first, p)
+        }
+    })?;
+
+    /*
+     * As at install, directories we create are given the ownership,
+     * permissions, and modification time of the original only once
+     * everything in them has been created.
+     */
+    for (p, attrs) in finish.iter().rev() {
+        attrs
+            .apply_to(&root.open_dir(p)?)
+            .map_err(|e| anyhow!("{:?}: {e}", root.path().join(p)))?;
+    }
+
+    for p in created {
+        let Some(st) = root.lstat(p)? else {
+            bail!("{:?} disappeared during resync", root.path().join(p));
+        };
+        entries.insert(p.clone(), Entry::new(&st, &wants[p]));
+    }
+
+    Ok((Record { entries }, stats))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn write(p: &Path, data: &str) {
+        std::fs::write(p, data).unwrap();
+    }
+
+    #[test]
+    fn resync_owned_only() {
+        let gz = tempfile::tempdir().unwrap();
+        let zone = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "link *.so.1\ncopy /**\n").unwrap();
+        let w = |gz: &Path| {
+            wants(gz, &["usr"], &rules, &Exclude::default()).unwrap()
+        };
+
+        std::fs::create_dir_all(gz.path().join("usr/lib")).unwrap();
+        write(&gz.path().join("usr/lib/libc.so.1"), "libc");
+        write(&gz.path().join("usr/lib/a.conf"), "one");
+        write(&gz.path().join("usr/lib/b.conf"), "two");
+        write(&gz.path().join("usr/lib/gone"), "gone");
+        symlink("libc.so.1", gz.path().join("usr/lib/libc.so")).unwrap();
+
+        /*
+         * Start with an empty zone root, as though nothing was replicated.
+         */
+        let (record, stats) =
+            resync(zone.path(), &Record::default(), &w(gz.path()), None)
+                .unwrap();
+        assert_eq!(stats.added, 7);
+        let zp = |p: &str| zone.path().join(p);
+        assert_eq!(
+            std::fs::read_link(zp("usr/lib/libc.so.1")).unwrap(),
+            Path::new("../../system/usr/lib/libc.so.1"),
+        );
+        assert_eq!(
+            std::fs::read_to_string(zp("usr/lib/a.conf")).unwrap(),
+            "one"
+        );
+
+        /*
+         * Replace one copy as the baseline would, then update the global
+         * zone.
+         */
+        std::fs::remove_file(zp("usr/lib/b.conf")).unwrap();
+        write(&zp("usr/lib/b.conf"), "mine");
+        write(&gz.path().join("usr/lib/a.conf"), "three!");
+        write(&gz.path().join("usr/lib/b.conf"), "four!");
+        std::fs::remove_file(gz.path().join("usr/lib/gone")).unwrap();
+        write(&gz.path().join("usr/lib/new"), "new");
+
+        let (record, stats) =
+            resync(zone.path(), &record, &w(gz.path()), None).unwrap();
+        assert_eq!(stats.added, 1);
+        assert_eq!(stats.replaced, 1);
+        assert_eq!(stats.removed, 1);
+        assert_eq!(stats.unchanged, 4);
+        assert_eq!(stats.left_alone, 1);
+        assert_eq!(
+            std::fs::read_to_string(zp("usr/lib/a.conf")).unwrap(),
+            "three!",
+        );
+        assert_eq!(
+            std::fs::read_to_string(zp("usr/lib/b.conf")).unwrap(),
+            "mine"
+        );
+        assert!(!zp("usr/lib/gone").exists());
+        assert_eq!(std::fs::read_to_string(zp("usr/lib/new")).unwrap(), "new");
+
+        let (_, stats) =
+            resync(zone.path(), &record, &w(gz.path()), None).unwrap();
+        assert_eq!(stats.added + stats.replaced + stats.removed, 0);
+    }
+
+    #[test]
+    fn resync_hard_links() {
+        let gz = tempfile::tempdir().unwrap();
+        let zone = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "copy /**\n").unwrap();
+        let w = |gz: &Path| {
+            wants(gz, &["usr"], &rules, &Exclude::default()).unwrap()
+        };
+        let gp = |p: &str| gz.path().join(p);
+        let zp = |p: &str| zone.path().join(p);
+        let ino = |p: &str| std::fs::metadata(zp(p)).unwrap().ino();
+
+        std::fs::create_dir_all(gp("usr/bin")).unwrap();
+        write(&gp("usr/bin/a"), "program");
+        std::fs::hard_link(gp("usr/bin/a"), gp("usr/bin/b")).unwrap();
+        write(&gp("usr/bin/c"), "other");
+
+        let (record, stats) =
+            resync(zone.path(), &Record::default(), &w(gz.path()), None)
+                .unwrap();
+        assert_eq!(stats.added, 5);
+        assert_eq!(ino("usr/bin/a"), ino("usr/bin/b"));
+        assert_ne!(ino("usr/bin/a"), ino("usr/bin/c"));
+        assert_eq!(
+            std::fs::read_to_string(zp("usr/bin/b")).unwrap(),
+            "program",
+        );
+
+        /*
+         * A new name for a file that is already replicated is linked to the
+         * existing copy.
+         */
+        std::fs::hard_link(gp("usr/bin/a"), gp("usr/bin/d")).unwrap();
+        let (record, stats) =
+            resync(zone.path(), &record, &w(gz.path()), None).unwrap();
+        assert_eq!(stats.added, 1);
+        assert_eq!(stats.unchanged, 5);
+        assert_eq!(ino("usr/bin/a"), ino("usr/bin/d"));
+
+        /*
+         * When the file changes, it is copied again once and linked at each
+         * of its names.
+         */
+        write(&gp("usr/bin/a"), "program, again");
+        let (record, stats) =
+            resync(zone.path(), &record, &w(gz.path()), None).unwrap();
+        assert_eq!(stats.replaced, 3);
+        assert_eq!(ino("usr/bin/a"), ino("usr/bin/b"));
+        assert_eq!(ino("usr/bin/a"), ino("usr/bin/d"));
+        assert_eq!(
+            std::fs::read_to_string(zp("usr/bin/d")).unwrap(),
+            "program, again",
+        );
+
+        let (_, stats) =
+            resync(zone.path(), &record, &w(gz.path()), None).unwrap();
+        assert_eq!(stats.added + stats.replaced + stats.removed, 0);
+    }
+
+    #[test]
+    fn resync_store() {
+        use helios_build_utils::store::Lock;
+
+        let gz = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let zonepath = dir.path().join("zone");
+        let zone = zonepath.join("root");
+        std::fs::create_dir_all(&zone).unwrap();
+        let sdir = dir.path().join("store");
+        let rules = Rules::parse("test", "copy /**\n").unwrap();
+        let w = |gz: &Path| {
+            wants(gz, &["usr"], &rules, &Exclude::default()).unwrap()
+        };
+        let gp = |p: &str| gz.path().join(p);
+        let zp = |p: &str| zone.join(p);
+        let md = |p: &Path| std::fs::metadata(p).unwrap();
+        let refs = || {
+            let v: serde_json::Value = serde_json::from_slice(
+                &std::fs::read(sdir.join("refs/z")).unwrap(),
+            )
+            .unwrap();
+            v["objects"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|k| k.as_str().unwrap().to_string())
+                .collect::<BTreeSet<_>>()
+        };
+
+        std::fs::create_dir_all(gp("usr/bin")).unwrap();
+        write(&gp("usr/bin/a"), "program");
+        std::fs::hard_link(gp("usr/bin/a"), gp("usr/bin/b")).unwrap();
+        write(&gp("usr/bin/c"), "other");
+        let ka = Store::key(&md(&gp("usr/bin/a")));
+        let kc = Store::key(&md(&gp("usr/bin/c")));
+
+        /*
+         * Each file is copied into the store once, and linked from there at
+         * each of its names in the zone root.
+         */
+        let mut store = Store::open(&sdir, Lock::Shared).unwrap();
+        let (record, stats) =
+            resync(&zone, &Record::default(), &w(gz.path()), Some(&mut store))
+                .unwrap();
+        store.save_refs("z", &zonepath).unwrap();
+        drop(store);
+        assert_eq!(stats.added, 5);
+        let oa = Store::open(&sdir, Lock::Shared).unwrap().object(&ka);
+        assert_eq!(md(&zp("usr/bin/a")).ino(), md(&oa).ino());
+        assert_eq!(md(&zp("usr/bin/b")).ino(), md(&oa).ino());
+        assert_eq!(md(&oa).nlink(), 3);
+        assert_eq!(std::fs::read_to_string(zp("usr/bin/c")).unwrap(), "other",);
+        assert_eq!(refs(), [ka.clone(), kc.clone()].into());
+
+        /*
+         * Files left unchanged are still noted as in use, and a file that
+         * changes is copied into the store under its new name.
+         */
+        std::fs::remove_file(gp("usr/bin/c")).unwrap();
+        write(&gp("usr/bin/c"), "changed");
+        let kc2 = Store::key(&md(&gp("usr/bin/c")));
+        assert_ne!(kc, kc2);
+
+        let mut store = Store::open(&sdir, Lock::Shared).unwrap();
+        let (_, stats) =
+            resync(&zone, &record, &w(gz.path()), Some(&mut store)).unwrap();
+        store.save_refs("z", &zonepath).unwrap();
+        assert_eq!(stats.replaced, 1);
+        assert_eq!(stats.unchanged, 4);
+        assert_eq!(md(&zp("usr/bin/c")).ino(), md(&store.object(&kc2)).ino());
+        assert_eq!(md(&store.object(&kc)).nlink(), 1);
+        assert_eq!(refs(), [ka, kc2].into());
+    }
+}
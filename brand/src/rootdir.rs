@@ -0,0 +1,572 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * File system operations confined to a directory tree, such as a zone root.
+ *
+ * Paths are relative to the root of the tree and may contain only normal
+ * components; i.e., no "..", ".", or leading "/".  Each path is resolved one
+ * component at a time, relative to an open directory, without following
+ * symbolic links.  A symbolic link in the tree, whether it came from an
+ * archive or was already present, can thus never redirect an operation to a
+ * location outside the tree.
+ */
+
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Directory,
+    File,
+    Symlink,
+    Fifo,
+    Other,
+}
+
+/**
+ * The type, permissions, ownership, and identity of an object in the tree.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub kind: Kind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+/**
+ * Check that a path is relative and contains only normal components.  An
+ * empty path refers to the root of the tree.
+ */
+pub fn check_relative(p: &Path) -> Result<()> {
+    if let Some(c) = p.components().find(|c| !matches!(c, Component::Normal(_)))
+    {
+        bail!("path {p:?} contains disallowed component {c:?}");
+    }
+    Ok(())
+}
+
+fn cstr(name: &OsStr) -> Result<CString> {
+    Ok(CString::new(name.as_bytes())?)
+}
+
+fn errno<T>(what: &str, p: &Path) -> Result<T> {
+    let e = std::io::Error::last_os_error();
+    bail!("{what}({p:?}): {e}");
+}
+
+fn open_beneath(dir: RawFd, name: &CString, flags: i32) -> Result<OwnedFd> {
+    let fd = unsafe {
+        libc::openat(dir, name.as_ptr(), flags | libc::O_CLOEXEC, 0o600)
+    };
+    if fd < 0 {
+        bail!(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+pub struct RootDir {
+    path: PathBuf,
+    fd: OwnedFd,
+    /**
+     * Entries in an archive are generally grouped by directory, so we keep
+     * the most recently used parent directory open.
+     */
+    last: Option<(PathBuf, OwnedFd)>,
+}
+
+impl RootDir {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RootDir> {
+        let path = path.as_ref().to_path_buf();
+        if !path.is_absolute() {
+            bail!("root directory {path:?} must be absolute");
+        }
+
+        let fd = open_beneath(
+            libc::AT_FDCWD,
+            &cstr(path.as_os_str())?,
+            libc::O_RDONLY | libc::O_DIRECTORY,
+        )
+        .map_err(|e| anyhow::anyhow!("opening root directory {path:?}: {e}"))?;
+
+        Ok(RootDir {
+            path,
+            fd,
+            last: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * Open a directory within the tree by walking down from the root, one
+     * component at a time, refusing to traverse symbolic links.
+     */
+    fn walk(&self, dir: &Path) -> Result<OwnedFd> {
+        let mut fd = self.fd.try_clone()?;
+        for c in dir.components() {
+            let Component::Normal(name) = c else {
+                bail!("path {dir:?} contains disallowed component {c:?}");
+            };
+            fd = open_beneath(
+                fd.as_raw_fd(),
+                &cstr(name)?,
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+            )
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "{:?} is not a directory within {:?}: {e}",
+                    dir,
+                    self.path,
+                )
+            })?;
+        }
+        Ok(fd)
+    }
+
+    /**
+     * Resolve the parent directory of a path, returning a descriptor for that
+     * directory and the final component of the path.
+     */
+    fn parent(&mut self, p: &Path) -> Result<(RawFd, CString)> {
+        check_relative(p)?;
+        let Some(name) = p.file_name() else {
+            bail!("path {p:?} does not name an object within the tree");
+        };
+        let dir = p.parent().unwrap_or(Path::new(""));
+
+        if !matches!(&self.last, Some((last, _)) if last == dir) {
+            self.last = Some((dir.to_path_buf(), self.walk(dir)?));
+        }
+        Ok((self.last.as_ref().unwrap().1.as_raw_fd(), cstr(name)?))
+    }
+
+    fn full(&self, p: &Path) -> PathBuf {
+        self.path.join(p)
+    }
+
+    /**
+     * Get information about an object, without following a symbolic link.
+     * Returns None if there is nothing at that path.
+     */
+    pub fn lstat(&mut self, p: &Path) -> Result<Option<Stat>> {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        let r = if p.as_os_str().is_empty() {
+            unsafe { libc::fstat(self.fd.as_raw_fd(), &mut st) }
+        } else {
+            let (dir, name) = self.parent(p)?;
+            unsafe {
+                libc::fstatat(
+                    dir,
+                    name.as_ptr(),
+                    &mut st,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            }
+        };
+        if r != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::NotFound {
+                return Ok(None);
+            }
+            bail!("lstat({:?}): {e}", self.full(p));
+        }
+
+        let kind = match st.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => Kind::Directory,
+            libc::S_IFREG => Kind::File,
+            libc::S_IFLNK => Kind::Symlink,
+            libc::S_IFIFO => Kind::Fifo,
+            _ => Kind::Other,
+        };
+
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(Stat {
+            kind,
+            mode: (st.st_mode & 0o7777) as u32,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            dev: st.st_dev as u64,
+            ino: st.st_ino as u64,
+            size: st.st_size as u64,
+            mtime: st.st_mtime as i64,
+            mtime_nsec: st.st_mtime_nsec as i64,
+        }))
+    }
+
+    /**
+     * Remove a file or symbolic link.
+     */
+    pub fn remove_file(&mut self, p: &Path) -> Result<()> {
+        let (dir, name) = self.parent(p)?;
+        if unsafe { libc::unlinkat(dir, name.as_ptr(), 0) } != 0 {
+            return errno("unlink", &self.full(p));
+        }
+        Ok(())
+    }
+
+    /**
+     * Remove an empty directory.
+     */
+    pub fn remove_dir(&mut self, p: &Path) -> Result<()> {
+        let (dir, name) = self.parent(p)?;
+        if unsafe { libc::unlinkat(dir, name.as_ptr(), libc::AT_REMOVEDIR) }
+            != 0
+        {
+            return errno("rmdir", &self.full(p));
+        }
+
+        /*
+         * Our cached parent directory may have been the one we just removed,
+         * or somewhere beneath it.
+         */
+        if matches!(&self.last, Some((last, _)) if last.starts_with(p)) {
+            self.last = None;
+        }
+        Ok(())
+    }
+
+    /**
+     * Remove an object and, if it is a directory, everything beneath it.
+     * Symbolic links are removed, not followed.  It is not an error if there
+     * is nothing at the path.
+     */
+    pub fn remove_all(&mut self, p: &Path) -> Result<()> {
+        match self.lstat(p)? {
+            None => Ok(()),
+            Some(st) if st.kind == Kind::Directory => {
+                for name in self.read_dir(p)? {
+                    self.remove_all(&p.join(name))?;
+                }
+                self.remove_dir(p)
+            }
+            Some(_) => self.remove_file(p),
+        }
+    }
+
+    /**
+     * List the names of the entries in a directory, other than "." and "..".
+     * An empty path lists the root of the tree.
+     */
+    pub fn read_dir(&mut self, p: &Path) -> Result<Vec<OsString>> {
+        let fd = OwnedFd::from(self.open_dir(p)?);
+        let d = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+        if d.is_null() {
+            return errno("fdopendir", &self.full(p));
+        }
+
+        /*
+         * The directory stream now owns the descriptor, and closes it in
+         * closedir().
+         */
+        let _ = fd.into_raw_fd();
+
+        let mut names = Vec::new();
+        loop {
+            let ent = unsafe { libc::readdir(d) };
+            if ent.is_null() {
+                break;
+            }
+            let name = unsafe { CStr::from_ptr((*ent).d_name.as_ptr()) };
+            let name = OsStr::from_bytes(name.to_bytes());
+            if name != "." && name != ".." {
+                names.push(name.to_os_string());
+            }
+        }
+        unsafe { libc::closedir(d) };
+
+        Ok(names)
+    }
+
+    pub fn create_dir(&mut self, p: &Path) -> Result<()> {
+        let (dir, name) = self.parent(p)?;
+        if unsafe { libc::mkdirat(dir, name.as_ptr(), 0o700) } != 0 {
+            return errno("mkdir", &self.full(p));
+        }
+        Ok(())
+    }
+
+    /**
+     * Open an existing directory so that its permissions and ownership can be
+     * updated.  An empty path opens the root of the tree.
+     */
+    pub fn open_dir(&mut self, p: &Path) -> Result<File> {
+        if p.as_os_str().is_empty() {
+            return Ok(File::from(self.fd.try_clone()?));
+        }
+
+        let (dir, name) = self.parent(p)?;
+        let fd = open_beneath(
+            dir,
+            &name,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+        )
+        .map_err(|e| anyhow::anyhow!("open({:?}): {e}", self.full(p)))?;
+        Ok(File::from(fd))
+    }
+
+    /**
+     * Open an existing regular file for reading.
+     */
+    pub fn open_file(&mut self, p: &Path) -> Result<File> {
+        let (dir, name) = self.parent(p)?;
+        let fd = open_beneath(dir, &name, libc::O_RDONLY | libc::O_NOFOLLOW)
+            .map_err(|e| anyhow::anyhow!("open({:?}): {e}", self.full(p)))?;
+        Ok(File::from(fd))
+    }
+
+    /**
+     * Create a new regular file, which must not already exist.  The file is
+     * created readable and writable only by the owner; the caller is expected
+     * to set the final permissions through the returned handle.
+     */
+    pub fn create_file(&mut self, p: &Path) -> Result<File> {
+        let (dir, name) = self.parent(p)?;
+        let fd = open_beneath(
+            dir,
+            &name,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW,
+        )
+        .map_err(|e| anyhow::anyhow!("create({:?}): {e}", self.full(p)))?;
+        Ok(File::from(fd))
+    }
+
+    /**
+     * Create a named pipe, which must not already exist.  As with regular
+     * files, the returned handle may be used to set the final permissions.
+     * Opening the pipe for reading without blocking does not wait for a
+     * writer.
+     */
+    pub fn create_fifo(&mut self, p: &Path) -> Result<File> {
+        let (dir, name) = self.parent(p)?;
+        if unsafe { libc::mkfifoat(dir, name.as_ptr(), 0o600) } != 0 {
+            return errno("mkfifo", &self.full(p));
+        }
+        let fd = open_beneath(
+            dir,
+            &name,
+            libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOFOLLOW,
+        )
+        .map_err(|e| anyhow::anyhow!("open({:?}): {e}", self.full(p)))?;
+        Ok(File::from(fd))
+    }
+
+    /**
+     * Set the modification time of an object, without following a symbolic
+     * link.  The access time is set to the same value.
+     */
+    pub fn set_mtime(&mut self, p: &Path, mtime: SystemTime) -> Result<()> {
+        let (sec, nsec) = match mtime.duration_since(UNIX_EPOCH) {
+            Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+            Err(e) => {
+                let d = e.duration();
+                if d.subsec_nanos() == 0 {
+                    (-(d.as_secs() as i64), 0)
+                } else {
+                    (
+                        -(d.as_secs() as i64) - 1,
+                        1_000_000_000 - d.subsec_nanos(),
+                    )
+                }
+            }
+        };
+        let ts = libc::timespec {
+            tv_sec: sec as libc::time_t,
+            tv_nsec: nsec as _,
+        };
+        let times = [ts, ts];
+
+        let r = if p.as_os_str().is_empty() {
+            unsafe { libc::futimens(self.fd.as_raw_fd(), times.as_ptr()) }
+        } else {
+            let (dir, name) = self.parent(p)?;
+            unsafe {
+                libc::utimensat(
+                    dir,
+                    name.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            }
+        };
+        if r != 0 {
+            return errno("utimensat", &self.full(p));
+        }
+        Ok(())
+    }
+
+    /**
+     * Create a symbolic link.  The link target is not interpreted.
+     */
+    pub fn symlink(&mut self, target: &Path, p: &Path) -> Result<()> {
+        let target = cstr(target.as_os_str())?;
+        let (dir, name) = self.parent(p)?;
+        if unsafe { libc::symlinkat(target.as_ptr(), dir, name.as_ptr()) } != 0
+        {
+            return errno("symlink", &self.full(p));
+        }
+        Ok(())
+    }
+
+    /**
+     * Change the ownership of an object, without following a symbolic link.
+     */
+    pub fn lchown(&mut self, p: &Path, uid: u32, gid: u32) -> Result<()> {
+        let (dir, name) = self.parent(p)?;
+        if unsafe {
+            libc::fchownat(
+                dir,
+                name.as_ptr(),
+                uid,
+                gid,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        } != 0
+        {
+            return errno("lchown", &self.full(p));
+        }
+        Ok(())
+    }
+
+    /**
+     * Read the target of a symbolic link.
+     */
+    pub fn read_link(&mut self, p: &Path) -> Result<PathBuf> {
+        let (dir, name) = self.parent(p)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let r = unsafe {
+            libc::readlinkat(
+                dir,
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if r < 0 {
+            return errno("readlink", &self.full(p));
+        }
+        buf.truncate(r as usize);
+        Ok(PathBuf::from(OsString::from_vec(buf)))
+    }
+
+    /**
+     * Create a hard link to an existing object, both of which are within the
+     * tree.  If the existing object is a symbolic link, the new link refers to
+     * the symbolic link itself rather than to whatever it points at.
+     */
+    pub fn hard_link(&mut self, existing: &Path, p: &Path) -> Result<()> {
+        check_relative(existing)?;
+        let Some(src_name) = existing.file_name() else {
+            bail!("hard link target {existing:?} is the root of the tree");
+        };
+        let src_dir = self.walk(existing.parent().unwrap_or(Path::new("")))?;
+        let src_name = cstr(src_name)?;
+
+        let (dir, name) = self.parent(p)?;
+        let r = unsafe {
+            libc::linkat(
+                src_dir.as_raw_fd(),
+                src_name.as_ptr(),
+                dir,
+                name.as_ptr(),
+                0,
+            )
+        };
+        if r != 0 {
+            return errno("link", &self.full(p));
+        }
+        Ok(())
+    }
+
+    /**
+     * Create a hard link to an existing file outside the tree, such as one in
+     * the shared store, which is named by an absolute path.
+     */
+    pub fn link_in(&mut self, existing: &Path, p: &Path) -> Result<()> {
+        if !existing.is_absolute() {
+            bail!("hard link target {existing:?} must be absolute");
+        }
+        let existing = cstr(existing.as_os_str())?;
+
+        let (dir, name) = self.parent(p)?;
+        let r = unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                existing.as_ptr(),
+                dir,
+                name.as_ptr(),
+                0,
+            )
+        };
+        if r != 0 {
+            return errno("link", &self.full(p));
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Set an extended attribute on an open file or directory.
+ */
+#[cfg(target_os = "linux")]
+pub fn set_xattr(f: &File, name: &str, value: &[u8]) -> Result<()> {
+    let cname = CString::new(name)?;
+    let r = unsafe {
+        libc::fsetxattr(
+            f.as_raw_fd(),
+            cname.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if r != 0 {
+        let e = std::io::Error::last_os_error();
+        bail!("setting extended attribute {name:?}: {e}");
+    }
+    Ok(())
+}
+
+/**
+ * Set an extended attribute on an open file or directory.  On illumos, an
+ * extended attribute is a file in the attribute directory of the object,
+ * which is reached with O_XATTR; see fsattr(7).
+ */
+#[cfg(target_os = "illumos")]
+pub fn set_xattr(f: &File, name: &str, value: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    if name.contains('/') {
+        bail!("invalid extended attribute name {name:?}");
+    }
+    let fd = open_beneath(
+        f.as_raw_fd(),
+        &CString::new(name)?,
+        libc::O_XATTR | libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+    )
+    .map_err(|e| anyhow::anyhow!("setting extended attribute {name:?}: {e}"))?;
+    File::from(fd).write_all(value)?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "illumos")))]
+pub fn set_xattr(_f: &File, name: &str, _value: &[u8]) -> Result<()> {
+    bail!("cannot set extended attribute {name:?} on this system");
+}
@@ -0,0 +1,246 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * Detached ed25519 signatures for image archives.
+ *
+ * A signature is stored next to the archive in a file with the same name and
+ * an additional ".sig" suffix; e.g., "files.tar.gz.sig".  The signed message
+ * is a fixed context string followed by the SHA-256 digest of the complete
+ * archive file, which covers both the "oxide.json" metadata and the contents.
+ */
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub use helios_build_utils::defaults::Policy;
+use helios_build_utils::defaults::{DefaultsFile, BRAND_DEFAULTS};
+use helios_build_utils::metadata::hex_string;
+
+const CONTEXT: &[u8] = b"helios-omicron1 image signature v1\n";
+
+/**
+ * The contents of a detached signature file.
+ */
+#[derive(Debug, Deserialize, Serialize)]
+struct SignatureFile {
+    v: String,
+    k: String,
+    s: String,
+}
+
+fn parse_hex<const N: usize>(s: &str) -> Result<[u8; N]> {
+    let s = s.trim();
+    if s.len() != N * 2 || !s.is_ascii() {
+        bail!("expected {} hexadecimal digits, got {s:?}", N * 2);
+    }
+
+    let mut out = [0u8; N];
+    for (i, o) in out.iter_mut().enumerate() {
+        *o = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("invalid hexadecimal string {s:?}"))?;
+    }
+    Ok(out)
+}
+
+pub fn parse_public_key(s: &str) -> Result<VerifyingKey> {
+    Ok(VerifyingKey::from_bytes(&parse_hex(s)?)?)
+}
+
+pub fn public_key_string(key: &VerifyingKey) -> String {
+    hex_string(key.as_bytes())
+}
+
+/**
+ * Load a signing key from a file containing the hex-encoded 32 byte secret.
+ */
+pub fn load_signing_key<P: AsRef<Path>>(path: P) -> Result<SigningKey> {
+    let path = path.as_ref();
+    let s = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("reading key {path:?}: {e}"))?;
+    Ok(SigningKey::from_bytes(&parse_hex(&s)?))
+}
+
+/**
+ * Generate a new signing key from the system random source and store it in a
+ * file readable only by the owner.
+ */
+pub fn generate_signing_key<P: AsRef<Path>>(path: P) -> Result<SigningKey> {
+    let mut secret = [0u8; 32];
+    File::open("/dev/urandom")?.read_exact(&mut secret)?;
+    let key = SigningKey::from_bytes(&secret);
+
+    let mut f = std::fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(path.as_ref())?;
+    writeln!(f, "{}", hex_string(&secret))?;
+    f.flush()?;
+    f.sync_all()?;
+
+    Ok(key)
+}
+
+pub fn signature_path<P: AsRef<Path>>(archive: P) -> PathBuf {
+    let mut p = archive.as_ref().as_os_str().to_owned();
+    p.push(".sig");
+    PathBuf::from(p)
+}
+
+fn message<R: Read + Seek>(f: &mut R) -> Result<Vec<u8>> {
+    f.rewind()?;
+    let mut hasher = Sha256::new();
+    std::io::copy(f, &mut hasher)?;
+    f.rewind()?;
+
+    let mut msg = CONTEXT.to_vec();
+    msg.extend_from_slice(&hasher.finalize());
+    Ok(msg)
+}
+
+/**
+ * Sign an archive file, writing the detached signature next to it.  Returns
+ * the path of the signature file.
+ */
+pub fn sign<P: AsRef<Path>>(key: &SigningKey, archive: P) -> Result<PathBuf> {
+    let archive = archive.as_ref();
+    let msg = message(&mut File::open(archive)?)?;
+
+    let sf = SignatureFile {
+        v: "1".into(),
+        k: public_key_string(&key.verifying_key()),
+        s: hex_string(&key.sign(&msg).to_bytes()),
+    };
+
+    let path = signature_path(archive);
+    let mut b = serde_json::to_vec(&sf)?;
+    b.push(b'\n');
+    std::fs::write(&path, b)?;
+
+    Ok(path)
+}
+
+/**
+ * Checks archive signatures against a set of trusted public keys, according
+ * to the configured policy.
+ */
+pub struct Verifier {
+    policy: Policy,
+    keys: Vec<VerifyingKey>,
+}
+
+impl Verifier {
+    pub fn new(policy: Policy, keys: Vec<VerifyingKey>) -> Verifier {
+        Verifier { policy, keys }
+    }
+
+    /**
+     * Determine the policy and trusted keys from the brand defaults file.  If
+     * no policy is set there, signatures are not checked.
+     */
+    pub fn from_defaults() -> Result<Verifier> {
+        let df = DefaultsFile::from_path(BRAND_DEFAULTS)?;
+
+        let policy = df.get_policy("SIGNATURE_POLICY")?.unwrap_or(Policy::Off);
+        let keys = df
+            .get_str("SIGNATURE_KEYS")
+            .unwrap_or("")
+            .split_whitespace()
+            .map(parse_public_key)
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| anyhow!("SIGNATURE_KEYS in {BRAND_DEFAULTS}: {e}"))?;
+
+        Ok(Verifier::new(policy, keys))
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    fn verify(&self, archive: &Path, f: &mut File) -> Result<()> {
+        let sigpath = signature_path(archive);
+        let sf: SignatureFile = match std::fs::read(&sigpath) {
+            Ok(b) => serde_json::from_slice(&b)
+                .map_err(|e| anyhow!("parsing {sigpath:?}: {e}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                bail!("no signature file {sigpath:?}");
+            }
+            Err(e) => bail!("reading {sigpath:?}: {e}"),
+        };
+        if sf.v != "1" {
+            bail!("unexpected signature version {} in {sigpath:?}", sf.v);
+        }
+
+        let key = parse_public_key(&sf.k)?;
+        if !self.keys.contains(&key) {
+            bail!("signed by untrusted key {}", sf.k);
+        }
+
+        let sig = Signature::from_bytes(&parse_hex(&sf.s)?);
+        key.verify_strict(&message(f)?, &sig)
+            .map_err(|_| anyhow!("signature in {sigpath:?} is not valid"))
+    }
+
+    /**
+     * Check the signature for an archive.  The archive contents are read from
+     * the open file, which the caller should then use for extraction so that
+     * the file we checked is the one we unpack.
+     */
+    pub fn check(&self, archive: &Path, f: &mut File) -> Result<()> {
+        match self.policy {
+            Policy::Off => Ok(()),
+            Policy::Warn => {
+                if let Err(e) = self.verify(archive, f) {
+                    println!("WARNING: archive {archive:?}: {e}");
+                }
+                Ok(())
+            }
+            Policy::Enforce => self
+                .verify(archive, f)
+                .map_err(|e| anyhow!("signature verification failed: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let archive = dir.path().join("image.tar.gz");
+        std::fs::write(&archive, b"not really an archive").unwrap();
+
+        let key = generate_signing_key(dir.path().join("key")).unwrap();
+        let other = SigningKey::from_bytes(&[7u8; 32]);
+        sign(&key, &archive).unwrap();
+
+        let mut f = File::open(&archive).unwrap();
+        let trusted = Verifier::new(Policy::Enforce, vec![key.verifying_key()]);
+        trusted.check(&archive, &mut f).expect("trusted signature");
+
+        let untrusted =
+            Verifier::new(Policy::Enforce, vec![other.verifying_key()]);
+        assert!(untrusted.check(&archive, &mut f).is_err());
+
+        /*
+         * Modifying the archive after signing must invalidate the signature.
+         */
+        std::fs::write(&archive, b"not really an archive!").unwrap();
+        let mut f = File::open(&archive).unwrap();
+        assert!(trusted.check(&archive, &mut f).is_err());
+
+        let warn = Verifier::new(Policy::Warn, vec![key.verifying_key()]);
+        warn.check(&archive, &mut f).expect("only a warning");
+    }
+}
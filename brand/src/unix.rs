@@ -62,10 +62,11 @@ impl Database {
         path: P,
         nfields: usize,
     ) -> Result<Database> {
-        let name = name.to_string();
-        let path = path.as_ref();
-        let data = std::fs::read_to_string(path)?;
+        Database::parse(name, &std::fs::read_to_string(path.as_ref())?, nfields)
+    }
 
+    fn parse(name: &str, data: &str, nfields: usize) -> Result<Database> {
+        let name = name.to_string();
         let entries = data
             .lines()
             .enumerate()
@@ -118,15 +119,22 @@ impl Database {
     }
 }
 
+#[derive(Debug)]
 pub struct Group {
     database: Database,
 }
 
 impl Group {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Passwd> {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Group> {
         let database = Database::load("group", path, 4)?;
 
-        Ok(Passwd { database })
+        Ok(Group { database })
+    }
+
+    pub fn parse(data: &str) -> Result<Group> {
+        let database = Database::parse("group", data, 4)?;
+
+        Ok(Group { database })
     }
 
     pub fn store<P: AsRef<Path>>(&self, path: P) -> Result<()> {
@@ -142,12 +150,13 @@ impl Group {
             .collect::<Vec<_>>();
         match matches.len() {
             1 => Ok(matches[0].fields[2].parse()?),
-            0 => bail!("could not find user {:?}", n),
-            c => bail!("found {} matches for user {:?}", c, n),
+            0 => bail!("could not find group {:?}", n),
+            c => bail!("found {} matches for group {:?}", c, n),
         }
     }
 }
 
+#[derive(Debug)]
 pub struct Passwd {
     database: Database,
 }
@@ -159,6 +168,12 @@ impl Passwd {
         Ok(Passwd { database })
     }
 
+    pub fn parse(data: &str) -> Result<Passwd> {
+        let database = Database::parse("passwd", data, 7)?;
+
+        Ok(Passwd { database })
+    }
+
     pub fn store<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         self.database.store(path)
     }
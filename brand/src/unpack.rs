@@ -3,72 +3,736 @@
  */
 
 use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::io::{Read, Seek};
+use std::ops::Bound;
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::plan::Plan;
+use crate::rootdir::{self, Kind, RootDir};
+use crate::signature;
+use crate::unix::{Group, Passwd};
+use helios_build_utils::compress::{self, Codec, Stream};
+use helios_build_utils::copyq::{CopyQueue, CopyStats};
+use helios_build_utils::defaults::{DefaultsFile, BRAND_DEFAULTS};
 use helios_build_utils::{metadata, tree};
+use metadata::{EntryKind, Whiteout};
+
+/**
+ * The metadata file must be small enough to hold in memory.  Even the manifest
+ * for a full baseline archive is only a few megabytes.
+ */
+const MAX_METADATA_SIZE: u64 = 64 * 1024 * 1024;
+
+enum Source {
+    /**
+     * The metadata was found by scanning the archive, so we must start again
+     * from the beginning of the file to unpack it.
+     */
+    File(File),
+    /**
+     * The metadata was the first entry in the archive, and the decompressed
+     * stream is positioned at the entry that follows it.  The stream is
+     * consumed when the archive is unpacked.
+     */
+    Stream(Option<Stream>),
+}
 
 pub struct Unpack {
-    gz: Option<flate2::read::GzDecoder<std::fs::File>>,
+    source: Source,
+    codec: Codec,
     archive: PathBuf,
-    metadata: Option<metadata::Metadata>,
-    opened: bool,
+    metadata: metadata::Metadata,
+    threads: Option<usize>,
+    /**
+     * The directory within the archive that holds the contents of the zone
+     * root; "root" for our own archives, or nothing for an OCI image layer.
+     */
+    prefix: PathBuf,
+    /**
+     * For a layer of an OCI image layout, the layout it belongs to.
+     */
+    image: Option<PathBuf>,
+}
+
+/**
+ * Regular files up to this size are read into memory and written by a worker
+ * thread.  Larger files are written directly as the archive is read, so that
+ * the memory we use stays bounded.
+ */
+const MAX_QUEUED_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+/**
+ * Statistics from unpacking an archive.  Each phase is timed: extraction is
+ * the time spent reading the archive and creating each entry, and draining is
+ * the time spent waiting for the worker threads once the archive has been
+ * read.  Regular files are counted according to whether they were written
+ * inline or by a worker thread.
+ */
+#[derive(Default, Debug)]
+pub struct UnpackStats {
+    pub directories: u64,
+    pub links: u64,
+    pub fifos: u64,
+    pub removed: u64,
+    pub inline: CopyStats,
+    pub queued: CopyStats,
+    pub extract: Duration,
+    pub drain: Duration,
+}
+
+impl UnpackStats {
+    /**
+     * Accumulate the statistics from another archive, for images that are
+     * unpacked from several archives in turn.
+     */
+    pub fn add(&mut self, other: &UnpackStats) {
+        self.directories += other.directories;
+        self.links += other.links;
+        self.fifos += other.fifos;
+        self.removed += other.removed;
+        self.inline.files += other.inline.files;
+        self.inline.bytes += other.inline.bytes;
+        self.queued.files += other.queued.files;
+        self.queued.bytes += other.queued.bytes;
+        self.extract += other.extract;
+        self.drain += other.drain;
+    }
+}
+
+impl std::fmt::Display for UnpackStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} directories, {} files ({} in parallel), {} bytes, {} links, \
+            {} removed; extract {} msec, drain {} msec",
+            self.directories,
+            self.inline.files + self.queued.files,
+            self.queued.files,
+            self.inline.bytes + self.queued.bytes,
+            self.links,
+            self.removed,
+            self.extract.as_millis(),
+            self.drain.as_millis(),
+        )
+    }
+}
+
+/**
+ * Passes reads through from the underlying reader, while computing the SHA-256
+ * digest of the data that was read.
+ */
+struct DigestReader<R: Read> {
+    r: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> DigestReader<R> {
+    fn new(r: R) -> DigestReader<R> {
+        DigestReader {
+            r,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn finish(self) -> String {
+        metadata::hex_string(&self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/**
+ * An entry under the "root/" prefix of an archive, as presented to the visitor
+ * by Unpack::walk().  The path is relative to the zone root.  The link is the
+ * target of a symbolic link as it appears in the archive, or the path of the
+ * existing object for a hard link, also relative to the zone root.  For a
+ * whiteout marker, the path is that of the object to be removed, or of the
+ * directory to be emptied.
+ */
+struct Item {
+    path: PathBuf,
+    rel: PathBuf,
+    kind: EntryKind,
+    mode: u32,
+    attrs: Attrs,
+    link: Option<PathBuf>,
+    whiteout: Option<Whiteout>,
+    size: u64,
+}
+
+/**
+ * The ownership, extended attributes, permissions, and modification time to
+ * set on an object once it has been created.  These are set through a handle
+ * we already have open, rather than by path.  Changing the owner may clear
+ * set-id bits, so the mode is set last.
+ */
+struct Finish {
+    target: PathBuf,
+    owner: Option<(u32, u32)>,
+    xattrs: Vec<(String, Vec<u8>)>,
+    mode: Option<u32>,
+    mtime: Option<SystemTime>,
+}
+
+impl Finish {
+    fn apply(self, f: &File) -> Result<()> {
+        let target = &self.target;
+
+        if let Some((uid, gid)) = self.owner {
+            std::os::unix::fs::fchown(f, Some(uid), Some(gid))
+                .map_err(|e| anyhow!("chown({target:?}, {uid}, {gid}): {e}"))?;
+        }
+
+        for (name, value) in self.xattrs.iter() {
+            rootdir::set_xattr(f, name, value)
+                .map_err(|e| anyhow!("{target:?}: {e}"))?;
+        }
+
+        if let Some(mode) = self.mode {
+            f.set_permissions(std::fs::Permissions::from_mode(mode))
+                .map_err(|e| anyhow!("chmod({target:?}, {mode:o}): {e}"))?;
+        }
+
+        if let Some(mtime) = self.mtime {
+            f.set_modified(mtime)
+                .map_err(|e| anyhow!("setting mtime on {target:?}: {e}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+const PASSWD: &str = "etc/passwd";
+const GROUP: &str = "etc/group";
+
+/**
+ * The user and group databases of the zone root, against which the owner and
+ * group names recorded in archive entries are resolved, so that an archive
+ * built on a system with different ids still produces the right owners in the
+ * zone.  The databases are read from the zone root before we start, and
+ * replaced whenever the archive itself provides a new copy.  Entries without a
+ * name, or unpacked before there is any database to consult, keep their
+ * numeric ids.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Names {
+    passwd: Option<Passwd>,
+    group: Option<Group>,
+}
+
+impl Names {
+    fn load(root: &mut RootDir) -> Result<Names> {
+        let mut names = Names::default();
+        if !matches!(
+            root.lstat(Path::new("etc"))?,
+            Some(st) if st.kind == Kind::Directory
+        ) {
+            return Ok(names);
+        }
+
+        for db in [PASSWD, GROUP] {
+            let p = Path::new(db);
+            if !matches!(root.lstat(p)?, Some(st) if st.kind == Kind::File) {
+                continue;
+            }
+            let mut data = Vec::new();
+            root.open_file(p)?.read_to_end(&mut data)?;
+            names.update(p, &data)?;
+        }
+        Ok(names)
+    }
+
+    /**
+     * Take note of the contents of a regular file in the zone root, if it is
+     * one of the databases.
+     */
+    fn update(&mut self, rel: &Path, data: &[u8]) -> Result<()> {
+        let parse = || {
+            std::str::from_utf8(data)
+                .map_err(|_| anyhow!("{rel:?} in the zone root is not UTF-8"))
+        };
+        if rel == Path::new(PASSWD) {
+            self.passwd = Some(Passwd::parse(parse()?)?);
+        } else if rel == Path::new(GROUP) {
+            self.group = Some(Group::parse(parse()?)?);
+        }
+        Ok(())
+    }
+
+    fn is_database(rel: &Path) -> bool {
+        rel == Path::new(PASSWD) || rel == Path::new(GROUP)
+    }
+
+    fn resolve(&self, attrs: &Attrs) -> Result<(u32, u32)> {
+        let uid = match (&attrs.uname, &self.passwd) {
+            (Some(n), Some(pw)) => pw.lookup_by_name(n)?.try_into()?,
+            _ => attrs.uid,
+        };
+        let gid = match (&attrs.gname, &self.group) {
+            (Some(n), Some(gr)) => gr.lookup_by_name(n)?.try_into()?,
+            _ => attrs.gid,
+        };
+        Ok((uid, gid))
+    }
+}
+
+fn without_curdir(p: &Path) -> PathBuf {
+    p.components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .collect()
+}
+
+/**
+ * Determine whether a path, or anything beneath it, is in a set of paths.
+ */
+pub(crate) fn spared(keep: &BTreeSet<PathBuf>, p: &Path) -> bool {
+    keep.range::<Path, _>((Bound::Included(p), Bound::Unbounded))
+        .next()
+        .is_some_and(|k| k.starts_with(p))
+}
+
+/**
+ * Remove an object, and anything beneath it, on behalf of a whiteout marker.
+ * If "itself" is false, only the contents of a directory are removed.  A
+ * whiteout only applies to what was installed before the current archive, so
+ * any path we have already unpacked from this archive is spared, along with
+ * the directories that lead to it.
+ */
+fn prune(
+    root: &mut RootDir,
+    rel: &Path,
+    keep: &BTreeSet<PathBuf>,
+    itself: bool,
+) -> Result<()> {
+    let Some(st) = root.lstat(rel)? else {
+        return Ok(());
+    };
+
+    if itself && !spared(keep, rel) {
+        return root.remove_all(rel);
+    }
+    if st.kind != Kind::Directory {
+        return Ok(());
+    }
+
+    for name in root.read_dir(rel)? {
+        prune(root, &rel.join(name), keep, true)?;
+    }
+    Ok(())
+}
+
+/**
+ * Entry attributes that pax extended headers may override or supplement,
+ * either for a single entry or, with a global header, for every entry that
+ * follows.
+ */
+struct Attrs {
+    uid: u32,
+    gid: u32,
+    uname: Option<String>,
+    gname: Option<String>,
+    mtime: SystemTime,
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+const PAX_XATTR: &str = "SCHILY.xattr.";
+
+/**
+ * Parse a pax time value, which is a decimal number of seconds since the
+ * epoch that may be negative and may include a fractional part.
+ */
+fn parse_pax_time(v: &str) -> Result<SystemTime> {
+    let (neg, v) = match v.strip_prefix('-') {
+        Some(v) => (true, v),
+        None => (false, v),
+    };
+    let (secs, frac) = v.split_once('.').unwrap_or((v, ""));
+    if secs.is_empty() || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("invalid time {v:?}");
+    }
+
+    let secs: u64 = secs.parse()?;
+    let nanos: u32 = format!("{:0<9.9}", frac).parse()?;
+    let d = Duration::new(secs, nanos);
+
+    Ok(if neg { UNIX_EPOCH - d } else { UNIX_EPOCH + d })
+}
+
+fn entry_attrs(
+    h: &tar::Header,
+    global: &BTreeMap<String, Vec<u8>>,
+    local: &[(String, Vec<u8>)],
+) -> Result<Attrs> {
+    let mut a = Attrs {
+        uid: h.uid()?.try_into()?,
+        gid: h.gid()?.try_into()?,
+        uname: owner_name(h.username_bytes()),
+        gname: owner_name(h.groupname_bytes()),
+        mtime: UNIX_EPOCH + Duration::from_secs(h.mtime()?),
+        xattrs: Default::default(),
+    };
+
+    let mut xattrs: BTreeMap<&str, &[u8]> = Default::default();
+    for (k, v) in global.iter().chain(local.iter().map(|(k, v)| (k, v))) {
+        let value = || {
+            std::str::from_utf8(v)
+                .map_err(|_| anyhow!("pax header {k:?} is not UTF-8"))
+        };
+        match k.as_str() {
+            "uid" => a.uid = value()?.parse()?,
+            "gid" => a.gid = value()?.parse()?,
+            "uname" => a.uname = owner_name(Some(v)),
+            "gname" => a.gname = owner_name(Some(v)),
+            "mtime" => {
+                a.mtime = parse_pax_time(value()?)
+                    .map_err(|e| anyhow!("pax header {k:?}: {e}"))?;
+            }
+            k => {
+                if let Some(name) = k.strip_prefix(PAX_XATTR) {
+                    xattrs.insert(name, v);
+                }
+            }
+        }
+    }
+    a.xattrs = xattrs
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_vec()))
+        .collect();
+
+    Ok(a)
+}
+
+/**
+ * An empty name, or one that is not UTF-8, is treated as no name at all.
+ */
+fn owner_name(v: Option<&[u8]>) -> Option<String> {
+    std::str::from_utf8(v?)
+        .ok()
+        .filter(|n| !n.is_empty())
+        .map(str::to_string)
+}
+
+fn pax_records(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    tar::PaxExtensions::new(data)
+        .map(|ext| {
+            let ext = ext?;
+            Ok((ext.key()?.to_string(), ext.value_bytes().to_vec()))
+        })
+        .collect()
 }
 
-fn lstat<P: AsRef<Path>>(p: P) -> Result<Option<std::fs::Metadata>> {
-    let p = p.as_ref();
+/**
+ * Check that the header for an archive entry agrees with the manifest entry
+ * recorded for that path in the metadata.
+ */
+fn check_header(
+    me: &metadata::ManifestEntry,
+    h: &tar::Header,
+    attrs: &Attrs,
+    link: Option<&Path>,
+    target: &Path,
+) -> Result<()> {
+    let kind = metadata::EntryKind::from_tar(h.entry_type());
+    if kind != Some(me.kind) {
+        bail!(
+            "manifest mismatch: {target:?} is a {:?} in the archive, \
+            but a {:?} in the manifest",
+            h.entry_type(),
+            me.kind,
+        );
+    }
+
+    let (uid, gid) = (u64::from(attrs.uid), u64::from(attrs.gid));
+    if h.mode()? != me.mode || uid != me.uid || gid != me.gid {
+        bail!(
+            "manifest mismatch: {target:?} has mode {:o} owner {}:{} in \
+            the archive, but mode {:o} owner {}:{} in the manifest",
+            h.mode()?,
+            uid,
+            gid,
+            me.mode,
+            me.uid,
+            me.gid,
+        );
+    }
+
+    let link = link.and_then(|l| l.to_str());
+    if link != me.target.as_deref() {
+        bail!(
+            "manifest mismatch: {target:?} has link target {link:?} in the \
+            archive, but {:?} in the manifest",
+            me.target,
+        );
+    }
+
+    Ok(())
+}
+
+/**
+ * Read the first entry from a decompressed archive stream, which must be the
+ * "oxide.json" metadata file.  This is done without the help of the "tar"
+ * crate so that the stream is left positioned at the next entry, from where it
+ * can be unpacked without starting again.  Returns None if the first entry is
+ * something else.
+ */
+fn read_leading_metadata<R: Read>(r: &mut R) -> Result<Option<String>> {
+    let mut h = tar::Header::new_old();
+    r.read_exact(h.as_mut_bytes())?;
+
+    /*
+     * The checksum is the sum of the header bytes, with the checksum field
+     * itself treated as if it were filled with spaces.
+     */
+    let sum = h
+        .as_bytes()
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                *b as u32
+            }
+        })
+        .sum::<u32>();
+    if h.cksum()? != sum {
+        bail!("archive header checksum mismatch");
+    }
+
+    if !h.entry_type().is_file() || h.path()? != Path::new("oxide.json") {
+        return Ok(None);
+    }
+
+    let size = h.entry_size()?;
+    if size > MAX_METADATA_SIZE {
+        bail!("metadata file is too large ({size} bytes)");
+    }
+
+    let mut buf = vec![0u8; size.next_multiple_of(512).try_into()?];
+    r.read_exact(&mut buf)?;
+    buf.truncate(size.try_into()?);
 
-    Ok(match p.symlink_metadata() {
-        Ok(md) => Some(md),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
-        Err(e) => bail!("lstat({:?}): {:?}", p, e),
-    })
+    Ok(Some(String::from_utf8(buf)?))
 }
 
 impl Unpack {
+    /**
+     * Open an archive and load its metadata.  The signature on the archive is
+     * checked according to the policy in the brand defaults file.
+     */
     pub fn load<P: AsRef<Path>>(archive: P) -> Result<Unpack> {
+        Unpack::load_verified(
+            archive,
+            &signature::Verifier::from_defaults()?,
+            false,
+        )
+    }
+
+    /**
+     * Open an archive in strict mode, where "oxide.json" must be the first
+     * entry in the archive.
+     */
+    pub fn load_strict<P: AsRef<Path>>(archive: P) -> Result<Unpack> {
+        Unpack::load_verified(
+            archive,
+            &signature::Verifier::from_defaults()?,
+            true,
+        )
+    }
+
+    /**
+     * Open an archive and load its metadata.  If "oxide.json" is the first
+     * entry, as it should be, the archive is decompressed only once: the
+     * contents are unpacked from where the metadata ends.  Otherwise, unless
+     * in strict mode, we search the rest of the archive for the metadata and
+     * then start again from the beginning to unpack it.
+     */
+    pub fn load_verified<P: AsRef<Path>>(
+        archive: P,
+        verifier: &signature::Verifier,
+        strict: bool,
+    ) -> Result<Unpack> {
         let archive = archive.as_ref().to_path_buf();
 
-        let mut u = Unpack {
-            gz: None,
-            metadata: None,
+        /*
+         * We should only have to open the file once as we keep the file
+         * descriptor around as long as this object exists.  The signature is
+         * checked against the same open file that we will later unpack.
+         */
+        let mut f = File::open(&archive)
+            .map_err(|e| anyhow!("opening archive {archive:?}: {e}"))?;
+        verifier
+            .check(&archive, &mut f)
+            .map_err(|e| anyhow!("loading archive {archive:?}: {e}"))?;
+        f.rewind()?;
+
+        let mut magic = Vec::with_capacity(512);
+        (&mut f).take(512).read_to_end(&mut magic)?;
+        let codec = compress::detect(&magic)
+            .map_err(|e| anyhow!("loading archive {archive:?}: {e}"))?;
+        f.rewind()?;
+
+        let mut r = compress::decoder(codec, f.try_clone()?)?;
+        let (md, source) = match read_leading_metadata(&mut r) {
+            Ok(Some(md)) => (md, Source::Stream(Some(r))),
+            Ok(None) if !strict => {
+                let md = Unpack::find_metadata(&f, codec).map_err(|e| {
+                    anyhow!("loading archive {archive:?}: {e:?}")
+                })?;
+                (md, Source::File(f))
+            }
+            Ok(None) => bail!(
+                "loading archive {archive:?}: metadata file \"oxide.json\" \
+                must be the first entry in the archive"
+            ),
+            Err(e) => bail!("loading archive {archive:?}: {e:?}"),
+        };
+
+        Ok(Unpack {
+            source,
+            codec,
+            metadata: metadata::parse(&md)
+                .map_err(|e| anyhow!("loading archive {archive:?}: {e:?}"))?,
             archive,
-            opened: false,
+            threads: None,
+            prefix: PathBuf::from("root"),
+            image: None,
+        })
+    }
+
+    /**
+     * Load an archive from a stream that cannot be rewound, such as a pipe.
+     * The archive is read in strict mode.  Detached signatures cannot be
+     * checked for a stream, so this fails if the verifier would require one.
+     */
+    pub fn from_reader<R: Read + Send + 'static>(
+        label: &str,
+        r: R,
+        verifier: &signature::Verifier,
+    ) -> Result<Unpack> {
+        let archive = PathBuf::from(label);
+
+        match verifier.policy() {
+            signature::Policy::Off => {}
+            signature::Policy::Warn => {
+                println!(
+                    "WARNING: archive {archive:?}: cannot check the \
+                    signature of an archive read from a stream"
+                );
+            }
+            signature::Policy::Enforce => {
+                bail!(
+                    "loading archive {archive:?}: cannot check the signature \
+                    of an archive read from a stream"
+                );
+            }
+        }
+
+        Unpack::from_stream(archive, r)
+    }
+
+    /**
+     * Load an archive from a stream without regard to signature policy.  This
+     * is for streams that we produce ourselves, such as from a directory
+     * tree.
+     */
+    pub(crate) fn from_stream<R: Read + Send + 'static>(
+        archive: PathBuf,
+        r: R,
+    ) -> Result<Unpack> {
+        let (codec, r) = compress::sniff(r)
+            .map_err(|e| anyhow!("loading archive {archive:?}: {e}"))?;
+        let mut r = compress::decoder(codec, r)?;
+        let md = match read_leading_metadata(&mut r) {
+            Ok(Some(md)) => md,
+            Ok(None) => bail!(
+                "loading archive {archive:?}: metadata file \"oxide.json\" \
+                must be the first entry in the archive"
+            ),
+            Err(e) => bail!("loading archive {archive:?}: {e:?}"),
         };
-        u.load_metadata()
-            .map_err(|e| anyhow!("loading archive {:?}: {e:?}", &u.archive))?;
 
-        Ok(u)
+        Ok(Unpack {
+            source: Source::Stream(Some(r)),
+            codec,
+            metadata: metadata::parse(&md)
+                .map_err(|e| anyhow!("loading archive {archive:?}: {e:?}"))?,
+            archive,
+            threads: None,
+            prefix: PathBuf::from("root"),
+            image: None,
+        })
+    }
+
+    /**
+     * Open a layer from an OCI image, which is a tar file of the contents of
+     * the root file system without any metadata of its own.  The caller is
+     * expected to have checked the digest of the open file, which is the one
+     * we read from, and provides the metadata, the name by which to report
+     * the layer, and the image layout it belongs to.
+     */
+    pub(crate) fn load_oci_layer(
+        mut f: File,
+        label: PathBuf,
+        image: &Path,
+        metadata: metadata::Metadata,
+    ) -> Result<Unpack> {
+        let mut magic = Vec::with_capacity(512);
+        (&mut f).take(512).read_to_end(&mut magic)?;
+        let codec = compress::detect(&magic)
+            .map_err(|e| anyhow!("loading layer {label:?}: {e}"))?;
+
+        Ok(Unpack {
+            source: Source::File(f),
+            codec,
+            archive: label,
+            metadata,
+            threads: None,
+            prefix: PathBuf::new(),
+            image: Some(image.to_path_buf()),
+        })
     }
 
     pub fn metadata(&self) -> &metadata::Metadata {
-        self.metadata.as_ref().unwrap()
+        &self.metadata
     }
 
-    fn open_tar(
-        &mut self,
-    ) -> Result<tar::Archive<&mut flate2::read::GzDecoder<std::fs::File>>> {
-        let mut f = if let Some(gz) = self.gz.take() {
-            gz.into_inner()
-        } else {
-            /*
-             * We should only have to open the file once as we keep the file
-             * descriptor around as long as this object exists.
-             */
-            assert!(!self.opened);
-            self.opened = true;
+    /**
+     * The compression format of the archive, as determined from its contents.
+     */
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
 
-            File::open(&self.archive)?
+    fn open_tar(&mut self) -> Result<tar::Archive<Stream>> {
+        let r: Stream = match &mut self.source {
+            Source::File(f) => {
+                f.rewind()?;
+                compress::decoder(self.codec, f.try_clone()?)?
+            }
+            Source::Stream(r) => match r.take() {
+                Some(r) => r,
+                None => bail!("archive {:?} already unpacked", self.archive),
+            },
         };
-        f.rewind()?;
-        self.gz = Some(flate2::read::GzDecoder::new(f));
-        Ok(tar::Archive::new(self.gz.as_mut().unwrap()))
+        Ok(tar::Archive::new(r))
     }
 
-    fn load_metadata(&mut self) -> Result<()> {
-        let mut tar = self.open_tar()?;
+    fn find_metadata(f: &File, codec: Codec) -> Result<String> {
+        let mut f = f.try_clone()?;
+        f.rewind()?;
+        let mut tar = tar::Archive::new(compress::decoder(codec, f)?);
 
         /*
          * Locate the primary metadata file within the archive:
@@ -94,58 +758,403 @@ impl Unpack {
             let mut s = String::new();
             ent.read_to_string(&mut s)?;
 
-            self.metadata = Some(metadata::parse(&s)?);
-
-            return Ok(());
+            return Ok(s);
         }
 
         bail!("could not find metadata file, \"oxide.json\", in archive");
     }
 
-    pub fn unpack<P: AsRef<Path>>(&mut self, outdir: P) -> Result<()> {
-        let outdir = outdir.as_ref();
+    /**
+     * Visit each entry under the "root/" prefix of the archive, in order.  The
+     * checks that do not depend on what is already on disk are made here, so
+     * that they are the same whether the archive is being unpacked or only
+     * planned: paths must stay within the zone root, and if the archive
+     * includes a manifest, every entry must be listed there exactly once with
+     * matching metadata.  The contents of a regular file are passed to the
+     * visitor, and their digest is checked once it returns.
+     */
+    fn walk<F>(&mut self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&Item, &mut dyn Read) -> Result<()>,
+    {
+        let manifest = self.metadata().manifest().cloned();
+        let is_layer = self.metadata().is_layer();
+        let mut seen: BTreeSet<String> = Default::default();
         let mut tar = self.open_tar()?;
 
-        if !outdir.exists() {
-            std::fs::create_dir(outdir)?;
-        }
+        let root_prefix = self.prefix.clone();
 
-        let root_prefix = PathBuf::from("root");
+        /*
+         * The tar crate folds GNU long names and per-entry pax headers into
+         * the entries they describe, but leaves global pax headers to us.
+         */
+        let mut global: BTreeMap<String, Vec<u8>> = Default::default();
 
         let mut ents = tar.entries()?;
         while let Some(mut ent) = ents.next().transpose()? {
-            let h = ent.header();
-            let p = ent.path()?;
+            if ent.header().entry_type() == tar::EntryType::XGlobalHeader {
+                let mut data = Vec::new();
+                ent.read_to_end(&mut data)?;
+                global.extend(pax_records(&data)?);
+                continue;
+            }
 
-            let mode = h.mode()?;
-            let uid = h.uid()? as u32;
-            let gid = h.gid()? as u32;
+            let local = match ent.pax_extensions()? {
+                Some(ext) => ext
+                    .map(|e| {
+                        let e = e?;
+                        Ok((e.key()?.to_string(), e.value_bytes().to_vec()))
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+                None => Vec::new(),
+            };
+
+            /*
+             * Some tools write paths with a leading "./", which we ignore.
+             */
+            let h = ent.header();
+            let p = without_curdir(&ent.path()?);
+            let link = ent.link_name()?.map(|l| without_curdir(&l));
 
             if !p.starts_with(&root_prefix) {
                 continue;
             }
 
-            let target = tree::reprefix(&root_prefix, &p, outdir)?;
-            let md = lstat(&target)?;
+            let attrs = entry_attrs(h, &global, &local)
+                .map_err(|e| anyhow!("archive entry {p:?}: {e}"))?;
+            let mode = h.mode()? & 0o7777;
+
+            let rel = tree::unprefix(&root_prefix, &p)?;
+            rootdir::check_relative(&rel)
+                .map_err(|e| anyhow!("archive entry {p:?}: {e}"))?;
 
-            match h.entry_type() {
-                tar::EntryType::Regular
-                | tar::EntryType::Symlink
-                | tar::EntryType::Link => {
+            let expect = if let Some(manifest) = &manifest {
+                let Some(rel) = rel.to_str().map(str::to_string) else {
+                    bail!("path {p:?} in archive is not UTF-8");
+                };
+
+                let Some(me) = manifest.get(&rel) else {
+                    bail!("manifest mismatch: {p:?} not listed in manifest");
+                };
+                check_header(me, h, &attrs, link.as_deref(), &p)?;
+
+                if !seen.insert(rel) {
+                    bail!("manifest mismatch: {p:?} appears more than once");
+                }
+
+                Some(me)
+            } else {
+                None
+            };
+
+            let Some(kind) = EntryKind::from_tar(h.entry_type()) else {
+                bail!(
+                    "archive entry {p:?} has unsupported type {:?}",
+                    h.entry_type(),
+                );
+            };
+
+            /*
+             * Whiteout markers remove paths installed by the baseline archive
+             * or an earlier layer, and are not themselves unpacked.
+             */
+            let whiteout = match rel.to_str() {
+                Some(r) => Whiteout::from_marker(r)
+                    .map_err(|e| anyhow!("archive entry {p:?}: {e}"))?,
+                None => None,
+            };
+            if whiteout.is_some() {
+                if !is_layer {
+                    bail!(
+                        "archive entry {p:?}: whiteout markers may only \
+                        appear in layer archives"
+                    );
+                }
+                if kind != EntryKind::File {
+                    bail!("whiteout marker {p:?} must be a regular file");
+                }
+            }
+            let rel = match &whiteout {
+                Some(Whiteout::Path(t) | Whiteout::Opaque(t)) => {
+                    PathBuf::from(t)
+                }
+                None => rel,
+            };
+
+            let link = match kind {
+                EntryKind::Symlink => {
+                    let Some(link) = link else {
+                        bail!("symbolic link {p:?} has no target");
+                    };
+                    Some(link)
+                }
+                EntryKind::Hardlink => {
+                    /*
+                     * The link must refer to something we have already
+                     * unpacked, so the target must be under the same prefix
+                     * and is resolved within the zone root like any other
+                     * path.
+                     */
+                    let Some(link) = link else {
+                        bail!("hard link {p:?} has no target");
+                    };
+                    let existing = tree::unprefix(&root_prefix, &link)
+                        .and_then(|t| {
+                            rootdir::check_relative(&t)?;
+                            Ok(t)
+                        })
+                        .map_err(|e| {
+                            anyhow!("hard link {p:?} target {link:?}: {e}")
+                        })?;
+                    Some(existing)
+                }
+                _ => None,
+            };
+
+            let item = Item {
+                path: p,
+                rel,
+                kind,
+                mode,
+                attrs,
+                link,
+                whiteout,
+                size: ent.size(),
+            };
+
+            let mut dr = DigestReader::new(&mut ent);
+            visit(&item, &mut dr)?;
+
+            if let Some(me) = expect.filter(|_| kind == EntryKind::File) {
+                std::io::copy(&mut dr, &mut std::io::sink())?;
+                let digest = dr.finish();
+                if me.sha256.as_deref() != Some(digest.as_str()) {
+                    bail!(
+                        "manifest mismatch: {:?} has digest {digest}, but {} \
+                        in the manifest",
+                        item.path,
+                        me.sha256.as_deref().unwrap_or("none"),
+                    );
+                }
+            }
+        }
+
+        /*
+         * Entries that appear in the manifest but not in the archive suggest
+         * that the archive has been truncated.
+         */
+        if let Some(manifest) = &manifest {
+            let missing = manifest
+                .keys()
+                .filter(|k| !seen.contains(*k))
+                .collect::<Vec<_>>();
+            if let Some(first) = missing.first() {
+                bail!(
+                    "manifest mismatch: {} entries missing from archive, \
+                    including {first:?}",
+                    missing.len(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn begin_plan(&self, plan: &mut Plan) {
+        plan.begin(
+            &self.archive.to_string_lossy(),
+            self.metadata().archive_type(),
+        );
+        if let Some(image) = &self.image {
+            plan.set_image(&image.to_string_lossy());
+        }
+    }
+
+    /**
+     * Work out what unpacking this archive would do to the zone root modelled
+     * by the plan, without touching the disk.  Like unpacking, this consumes
+     * an archive that was read from a stream.
+     */
+    pub fn plan(&mut self, plan: &mut Plan) -> Result<()> {
+        self.begin_plan(plan);
+
+        let res = self.walk(|item, r| {
+            if let Some(w) = &item.whiteout {
+                plan.remove(&item.rel, matches!(w, Whiteout::Opaque(_)));
+                return Ok(());
+            }
+
+            /*
+             * Owners are resolved as the unpack would resolve them, against
+             * the user and group databases provided by earlier entries and
+             * archives.
+             */
+            let owner = plan
+                .names()
+                .resolve(&item.attrs)
+                .map_err(|e| anyhow!("archive entry {:?}: {e}", item.path))?;
+            if item.kind == EntryKind::File && Names::is_database(&item.rel) {
+                let mut db = Vec::new();
+                r.read_to_end(&mut db)?;
+                plan.names().update(&item.rel, &db)?;
+            }
+
+            plan.entry(
+                &item.rel,
+                item.kind,
+                item.mode,
+                owner,
+                item.link.as_deref(),
+            );
+            Ok(())
+        });
+        if let Err(e) = res {
+            plan.fail(&format!("{e:?}"));
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Set the number of worker threads used to write regular files.  With no
+     * threads, everything is written by the thread that reads the archive.
+     * The default is taken from the brand defaults file.
+     */
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = Some(threads);
+    }
+
+    /**
+     * Unpack the archive into "outdir".  If a plan is provided, each entry is
+     * also applied to it as it is unpacked, so that once every archive has
+     * been unpacked the plan knows which archive provided each path.
+     */
+    pub fn unpack<P: AsRef<Path>>(
+        &mut self,
+        outdir: P,
+        mut plan: Option<&mut Plan>,
+    ) -> Result<UnpackStats> {
+        let outdir = outdir.as_ref();
+
+        if !outdir.exists() {
+            std::fs::create_dir(outdir)?;
+        }
+
+        /*
+         * Everything we extract is created relative to the output directory,
+         * without following symbolic links, so that neither a hostile archive
+         * nor links left behind by an earlier layer can cause us to write
+         * outside the zone root.
+         */
+        let mut root = RootDir::open(outdir)?;
+        let mut names = Names::load(&mut root)?;
+
+        /*
+         * Directories, links, and the files themselves are all created in
+         * archive order by this thread, so that every entry finds its parent
+         * directory, and every hard link its target, already in place.  The
+         * contents of smaller regular files, and their ownership and
+         * permissions, are then handed to a pool of worker threads while we
+         * continue to decompress the archive.
+         */
+        let df = DefaultsFile::from_path(BRAND_DEFAULTS)?;
+        let threads = self
+            .threads
+            .unwrap_or_else(|| df.get_usize("UNPACK_THREADS").unwrap_or(8));
+        let mut cq = if threads > 0 {
+            Some(CopyQueue::new(
+                threads,
+                df.get_usize("COPY_BATCH").unwrap_or(128),
+            )?)
+        } else {
+            None
+        };
+
+        /*
+         * Creating an object in a directory updates its modification time, so
+         * directory times are restored once everything has been unpacked.
+         */
+        let mut dir_mtimes: Vec<(PathBuf, SystemTime)> = Vec::new();
+
+        /*
+         * The paths unpacked so far from this archive, which whiteout markers
+         * must not remove.
+         */
+        let mut unpacked: BTreeSet<PathBuf> = Default::default();
+
+        if let Some(plan) = plan.as_deref_mut() {
+            self.begin_plan(plan);
+        }
+
+        let mut stats = UnpackStats::default();
+        let start = Instant::now();
+
+        let res = self.walk(|item, r| {
+            if let Some(w) = &item.whiteout {
+                if let Some(plan) = plan.as_deref_mut() {
+                    plan.remove(&item.rel, matches!(w, Whiteout::Opaque(_)));
+                }
+
+                /*
+                 * If the path does not lead through directories that exist,
+                 * there is nothing to remove.  In particular, a whiteout does
+                 * not follow a symbolic link.
+                 */
+                let mut parents =
+                    item.rel.ancestors().skip(1).collect::<Vec<_>>();
+                parents.reverse();
+                for dir in parents {
+                    if !matches!(
+                        root.lstat(dir)?,
+                        Some(st) if st.kind == Kind::Directory
+                    ) {
+                        return Ok(());
+                    }
+                }
+
+                stats.removed += 1;
+                let itself = matches!(w, Whiteout::Path(_));
+                return prune(&mut root, &item.rel, &unpacked, itself);
+            }
+            unpacked.insert(item.rel.clone());
+
+            let Item {
+                rel,
+                kind,
+                mode,
+                attrs,
+                ..
+            } = item;
+            let (kind, mode) = (*kind, *mode);
+            let (uid, gid) = names
+                .resolve(attrs)
+                .map_err(|e| anyhow!("archive entry {:?}: {e}", item.path))?;
+            if let Some(plan) = plan.as_deref_mut() {
+                plan.entry(rel, kind, mode, (uid, gid), item.link.as_deref());
+            }
+            let target = outdir.join(rel);
+            let md = root.lstat(rel)?;
+
+            match kind {
+                EntryKind::File
+                | EntryKind::Symlink
+                | EntryKind::Hardlink
+                | EntryKind::Fifo => {
                     if let Some(md) = &md {
                         /*
                          * We are trying to create a regular file or a symlink,
                          * but the target path exists already.  Make sure it is
                          * already either a regular file or a symlink.
                          */
-                        if !md.file_type().is_file()
-                            && !md.file_type().is_symlink()
-                        {
+                        if !matches!(
+                            md.kind,
+                            Kind::File | Kind::Symlink | Kind::Fifo
+                        ) {
                             bail!(
                                 "conflict: path {:?} is a {:?}, \
                                 not a file or symlink",
                                 target,
-                                md.file_type(),
+                                md.kind,
                             );
                         }
 
@@ -153,24 +1162,36 @@ impl Unpack {
                          * Unlink the existing file or symlink so that we can
                          * replace it with the contents from the archive.
                          */
-                        std::fs::remove_file(&target)?;
+                        root.remove_file(rel)?;
                     }
                 }
-                _ => {}
+                EntryKind::Directory => {}
             }
 
-            let (chmod, chown) = match h.entry_type() {
-                tar::EntryType::Directory => {
+            let mut fin = Finish {
+                target: target.clone(),
+                owner: Some((uid, gid)),
+                xattrs: attrs.xattrs.clone(),
+                mode: Some(mode),
+                mtime: Some(attrs.mtime),
+            };
+
+            let f = match kind {
+                EntryKind::Directory => {
+                    stats.directories += 1;
+                    fin.mtime = None;
+                    dir_mtimes.push((rel.to_path_buf(), attrs.mtime));
+
                     if let Some(md) = &md {
                         /*
                          * The path exists already.  Check to make sure it is a
                          * directory.
                          */
-                        if !md.file_type().is_dir() {
+                        if md.kind != Kind::Directory {
                             bail!(
                                 "conflict: path {:?} is a {:?}, not a dir",
                                 target,
-                                md.file_type(),
+                                md.kind,
                             );
                         }
 
@@ -178,59 +1199,561 @@ impl Unpack {
                          * We need to update the metadata if it is not already
                          * correct:
                          */
-                        (md.mode() != mode, md.uid() != uid || md.gid() != gid)
+                        if md.mode == mode {
+                            fin.mode = None;
+                        }
+                        if md.uid == uid && md.gid == gid {
+                            fin.owner = None;
+                        }
                     } else {
-                        std::fs::create_dir(&target)?;
-                        (true, true)
+                        root.create_dir(rel)?;
                     }
+                    root.open_dir(rel)?
                 }
-                tar::EntryType::Regular => {
-                    let mut f = std::fs::OpenOptions::new()
-                        .create_new(true)
-                        .write(true)
-                        .open(&target)?;
-                    std::io::copy(&mut ent, &mut f)?;
-                    (true, true)
+                EntryKind::File => {
+                    let mut f = root.create_file(rel)?;
+
+                    /*
+                     * Entries that follow a new user or group database in
+                     * the archive are resolved against the new contents.
+                     */
+                    let mut db = Vec::new();
+                    let r: &mut dyn Read = if Names::is_database(rel) {
+                        r.read_to_end(&mut db)?;
+                        names.update(rel, &db)?;
+                        &mut db.as_slice()
+                    } else {
+                        r
+                    };
+
+                    match &mut cq {
+                        Some(cq) if item.size <= MAX_QUEUED_FILE_SIZE => {
+                            if cq.failed() {
+                                bail!("a worker thread failed");
+                            }
+
+                            let mut data =
+                                Vec::with_capacity(item.size.try_into()?);
+                            r.read_to_end(&mut data)?;
+                            cq.push_write(
+                                target,
+                                f,
+                                data,
+                                Box::new(move |f| fin.apply(f)),
+                            );
+                            return Ok(());
+                        }
+                        _ => {
+                            stats.inline.files += 1;
+                            stats.inline.bytes += std::io::copy(r, &mut f)?;
+                            f
+                        }
+                    }
+                }
+                EntryKind::Fifo => {
+                    stats.fifos += 1;
+                    root.create_fifo(rel)?
                 }
-                tar::EntryType::Symlink => {
-                    let linktarget = ent.link_name()?.unwrap();
+                EntryKind::Symlink => {
+                    stats.links += 1;
+                    let linktarget = item.link.as_deref().unwrap();
 
-                    std::os::unix::fs::symlink(&linktarget, &target)?;
+                    root.symlink(linktarget, rel)?;
+                    root.set_mtime(rel, attrs.mtime)?;
 
                     /*
                      * Symbolic links do not have permissions, and the default
                      * ownership of "root" is generally acceptable.
                      */
-                    (false, false)
+                    return Ok(());
                 }
-                tar::EntryType::Link => {
-                    let linktarget = tree::reprefix(
-                        &root_prefix,
-                        &ent.link_name()?.unwrap(),
-                        outdir,
-                    )?;
+                EntryKind::Hardlink => {
+                    stats.links += 1;
+                    let existing = item.link.as_deref().unwrap();
 
-                    std::fs::hard_link(&linktarget, &target)?;
+                    root.hard_link(existing, rel)?;
 
                     /*
                      * Permissions are per-inode, not per path, so we assume
                      * they were correctly set on the original file and leave
                      * them alone here.
                      */
-                    (false, false)
+                    return Ok(());
                 }
-                x => bail!("unsupported entry type {:?}: {:?}", x, h),
             };
 
-            if chmod {
-                crate::unix::lchmod(&target, mode)?;
+            fin.apply(&f)
+        });
+        stats.extract = start.elapsed();
+
+        /*
+         * Wait for the workers to finish, even if we are about to fail, so
+         * that nothing is still writing into the tree when we return.  If a
+         * worker failed, its error explains why we stopped.
+         */
+        let start = Instant::now();
+        let queued = cq.map(CopyQueue::join).transpose();
+        stats.drain = start.elapsed();
+        match (res, queued) {
+            (_, Err(e)) | (Err(e), _) => return Err(e),
+            (Ok(()), Ok(queued)) => stats.queued = queued.unwrap_or_default(),
+        }
+
+        for (rel, mtime) in dir_mtimes {
+            root.set_mtime(&rel, mtime)?;
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn append(a: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let mut h = tar::Header::new_ustar();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_path(path).unwrap();
+        h.set_mode(0o444);
+        h.set_size(data.len().try_into().unwrap());
+        h.set_cksum();
+        a.append(&h, data).unwrap();
+    }
+
+    #[test]
+    fn leading_metadata() {
+        let md = b"{\"v\":\"1\",\"t\":\"layer\"}\n";
+        let mut a = tar::Builder::new(Vec::new());
+        append(&mut a, "oxide.json", md);
+        append(&mut a, "root/motd", b"hello\n");
+        let b = a.into_inner().unwrap();
+
+        /*
+         * The stream must be left at the start of the second entry, where the
+         * tar crate can pick it up.
+         */
+        let mut r = b.as_slice();
+        let s = read_leading_metadata(&mut r).unwrap().unwrap();
+        assert_eq!(s.as_bytes(), md);
+        let mut rest = tar::Archive::new(r);
+        let ent = rest.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(ent.path().unwrap(), Path::new("root/motd"));
+
+        let mut a = tar::Builder::new(Vec::new());
+        append(&mut a, "root/motd", b"hello\n");
+        append(&mut a, "oxide.json", md);
+        let b = a.into_inner().unwrap();
+        assert!(read_leading_metadata(&mut b.as_slice()).unwrap().is_none());
+
+        let mut b = b;
+        b[0] ^= 1;
+        assert!(read_leading_metadata(&mut b.as_slice()).is_err());
+    }
+
+    #[test]
+    fn pax_attributes() {
+        let t = |s| parse_pax_time(s).unwrap();
+        assert_eq!(t("10"), UNIX_EPOCH + Duration::from_secs(10));
+        assert_eq!(t("10.5"), UNIX_EPOCH + Duration::from_millis(10500));
+        assert_eq!(t("-1.25"), UNIX_EPOCH - Duration::from_millis(1250));
+        assert!(parse_pax_time("1e9").is_err());
+        assert!(parse_pax_time(".5").is_err());
+
+        let mut h = tar::Header::new_ustar();
+        h.set_uid(1);
+        h.set_gid(2);
+        h.set_mtime(3);
+
+        let rec = |k: &str, v: &str| (k.to_string(), v.as_bytes().to_vec());
+        let global = [rec("gid", "20"), rec("SCHILY.xattr.user.a", "g")]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let local = [
+            rec("uid", "3000000"),
+            rec("mtime", "1700000000.000000001"),
+            rec("SCHILY.xattr.user.a", "l"),
+            rec("SCHILY.xattr.user.b", "b"),
+            rec("comment", "ignored"),
+        ];
+
+        let a = entry_attrs(&h, &global, &local).unwrap();
+        assert_eq!((a.uid, a.gid), (3000000, 20));
+        assert_eq!(a.mtime, UNIX_EPOCH + Duration::new(1700000000, 1));
+        assert_eq!(
+            a.xattrs,
+            vec![
+                ("user.a".to_string(), b"l".to_vec()),
+                ("user.b".to_string(), b"b".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn owner_names() {
+        let mut names = Names::default();
+        let attrs = |user: &str, group: &str| {
+            let mut h = tar::Header::new_ustar();
+            h.set_uid(999);
+            h.set_gid(999);
+            h.set_username(user).unwrap();
+            h.set_groupname(group).unwrap();
+            entry_attrs(&h, &Default::default(), &[]).unwrap()
+        };
+
+        /*
+         * Until there is a database to consult, names are ignored.
+         */
+        assert_eq!(
+            names.resolve(&attrs("oxide", "staff")).unwrap(),
+            (999, 999)
+        );
+
+        names
+            .update(
+                Path::new(PASSWD),
+                b"root:x:0:0::/root:/bin/sh\noxide:x:1000:10::/:/bin/sh\n",
+            )
+            .unwrap();
+        names
+            .update(Path::new(GROUP), b"root::0:\nstaff::10:\n")
+            .unwrap();
+        assert_eq!(
+            names.resolve(&attrs("oxide", "staff")).unwrap(),
+            (1000, 10)
+        );
+        assert_eq!(names.resolve(&attrs("", "")).unwrap(), (999, 999));
+        assert!(names.resolve(&attrs("nobody", "")).is_err());
+        assert!(names.resolve(&attrs("", "other")).is_err());
+    }
+
+    #[test]
+    fn plan_owner_names() {
+        let archive = |ents: &[(&str, &str, &[u8])]| {
+            let mut a = tar::Builder::new(Vec::new());
+            append(&mut a, "oxide.json", b"{\"v\":\"1\",\"t\":\"layer\"}\n");
+            for (path, owner, data) in ents {
+                let mut h = tar::Header::new_ustar();
+                h.set_entry_type(tar::EntryType::Regular);
+                h.set_path(path).unwrap();
+                h.set_mode(0o644);
+                h.set_uid(999);
+                h.set_gid(999);
+                h.set_username(owner).unwrap();
+                h.set_groupname(owner).unwrap();
+                h.set_size(data.len().try_into().unwrap());
+                h.set_cksum();
+                a.append(&h, *data).unwrap();
             }
+            a.into_inner().unwrap()
+        };
+        const PASSWD_DB: &[u8] =
+            b"root:x:0:0::/root:/bin/sh\noxide:x:1000:10::/:/bin/sh\n";
+        let verifier =
+            signature::Verifier::new(signature::Policy::Off, Vec::new());
+        let mut plan = Plan::new();
+        let mut apply = |name: &str, ar: Vec<u8>| {
+            Unpack::from_reader(name, std::io::Cursor::new(ar), &verifier)
+                .unwrap()
+                .plan(&mut plan)
+                .unwrap();
+        };
+
+        /*
+         * Names are resolved against the databases from earlier archives, and
+         * from earlier in the same archive, as the unpack would resolve them.
+         */
+        apply(
+            "first",
+            archive(&[
+                ("root/early", "oxide", b""),
+                ("root/etc/passwd", "root", PASSWD_DB),
+                ("root/etc/group", "root", b"oxide::10:\n"),
+                ("root/late", "oxide", b""),
+            ]),
+        );
+        apply("second", archive(&[("root/other", "oxide", b"")]));
+        apply("third", archive(&[("root/missing", "nobody", b"")]));
+
+        let owner = |p: &str| {
+            let o = &plan.objects()[Path::new(p)];
+            (o.uid, o.gid)
+        };
+        assert_eq!(owner("early"), (999, 999));
+        assert_eq!(owner("late"), (1000, 10));
+        assert_eq!(owner("other"), (1000, 10));
+        assert!(!plan.objects().contains_key(Path::new("missing")));
+        let e = plan.archives()[2].error.as_deref().unwrap();
+        assert!(e.contains("could not find user"), "{e}");
+    }
+
+    /*
+     * The tar crate refuses to construct hostile entries, so we fill in the
+     * header fields ourselves.
+     */
+    enum Ent<'a> {
+        Dir(&'a str),
+        File(&'a str, &'a str),
+        Symlink(&'a str, &'a str),
+        Hardlink(&'a str, &'a str),
+    }
+
+    fn hostile(ents: &[Ent]) -> Vec<u8> {
+        let mut a = tar::Builder::new(Vec::new());
+        append(&mut a, "oxide.json", b"{\"v\":\"1\",\"t\":\"layer\"}\n");
+
+        for e in ents {
+            let (et, path, link, data) = match e {
+                Ent::Dir(p) => (tar::EntryType::Directory, p, "", ""),
+                Ent::File(p, d) => (tar::EntryType::Regular, p, "", *d),
+                Ent::Symlink(p, l) => (tar::EntryType::Symlink, p, *l, ""),
+                Ent::Hardlink(p, l) => (tar::EntryType::Link, p, *l, ""),
+            };
 
-            if chown {
-                crate::unix::lchown(&target, uid, gid)?;
+            let mut h = tar::Header::new_ustar();
+            h.set_entry_type(et);
+            let old = h.as_old_mut();
+            old.name[..path.len()].copy_from_slice(path.as_bytes());
+            old.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            h.set_mode(0o755);
+            h.set_uid(unsafe { libc::geteuid() }.into());
+            h.set_gid(unsafe { libc::getegid() }.into());
+            h.set_size(data.len().try_into().unwrap());
+            h.set_cksum();
+            a.append(&h, data.as_bytes()).unwrap();
+        }
+
+        a.into_inner().unwrap()
+    }
+
+    struct Fixture {
+        _dir: tempfile::TempDir,
+        root: PathBuf,
+        outside: PathBuf,
+    }
+
+    impl Fixture {
+        /**
+         * Create a zone root, and next to it a directory that should never be
+         * touched, which contains a file named "secret".
+         */
+        fn new() -> Fixture {
+            let dir = tempfile::TempDir::new().unwrap();
+            let root = dir.path().join("root");
+            let outside = dir.path().join("outside");
+            std::fs::create_dir(&root).unwrap();
+            std::fs::create_dir(&outside).unwrap();
+            std::fs::write(outside.join("secret"), b"secret").unwrap();
+            Fixture {
+                _dir: dir,
+                root,
+                outside,
             }
         }
 
-        Ok(())
+        fn unpack(&self, ents: &[Ent]) -> Result<()> {
+            let verifier =
+                signature::Verifier::new(signature::Policy::Off, Vec::new());
+            let mut u = Unpack::from_reader(
+                "test",
+                std::io::Cursor::new(hostile(ents)),
+                &verifier,
+            )?;
+            u.unpack(&self.root, None).map(|_| ())
+        }
+
+        fn outside(&self) -> &str {
+            self.outside.to_str().unwrap()
+        }
+
+        /**
+         * Check that the directory outside the root still contains only the
+         * original secret, which has no other links.
+         */
+        fn assert_untouched(&self) {
+            let names = std::fs::read_dir(&self.outside)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec![std::ffi::OsString::from("secret")]);
+            let secret = self.outside.join("secret");
+            assert_eq!(std::fs::read(&secret).unwrap(), b"secret");
+            assert_eq!(std::fs::metadata(&secret).unwrap().nlink(), 1);
+        }
+    }
+
+    #[test]
+    fn benign_archive() {
+        let f = Fixture::new();
+        f.unpack(&[
+            Ent::Dir("root/etc"),
+            Ent::File("root/etc/motd", "hello\n"),
+            Ent::Hardlink("root/etc/motd.1", "root/etc/motd"),
+            Ent::Symlink("root/etc/motd.2", "motd"),
+        ])
+        .expect("unpack");
+
+        let motd = f.root.join("etc/motd");
+        assert_eq!(std::fs::read(&motd).unwrap(), b"hello\n");
+        assert_eq!(std::fs::metadata(&motd).unwrap().nlink(), 2);
+        assert_eq!(std::fs::metadata(&motd).unwrap().mode() & 0o7777, 0o755);
+        assert_eq!(
+            std::fs::read_link(f.root.join("etc/motd.2")).unwrap(),
+            Path::new("motd")
+        );
+    }
+
+    #[test]
+    fn parent_directory_components() {
+        let f = Fixture::new();
+        assert!(f.unpack(&[Ent::File("root/../outside/evil", "x")]).is_err());
+        assert!(f
+            .unpack(&[
+                Ent::Dir("root/a"),
+                Ent::File("root/a/../../outside/evil", "x"),
+            ])
+            .is_err());
+        f.assert_untouched();
+    }
+
+    #[test]
+    fn write_through_symlink() {
+        let f = Fixture::new();
+
+        /*
+         * Both absolute and relative links to the outside directory, whether
+         * placed by the same archive or by an earlier layer, must not be
+         * followed.
+         */
+        assert!(f
+            .unpack(&[
+                Ent::Symlink("root/abs", f.outside()),
+                Ent::File("root/abs/evil", "x"),
+            ])
+            .is_err());
+
+        f.unpack(&[Ent::Symlink("root/etc", "../outside")])
+            .expect("symlink layer");
+        assert!(f.unpack(&[Ent::File("root/etc/shadow", "x")]).is_err());
+        assert!(f.unpack(&[Ent::Dir("root/etc")]).is_err());
+        assert!(f.unpack(&[Ent::Dir("root/etc/sub")]).is_err());
+        assert!(f
+            .unpack(&[Ent::Symlink("root/etc/secret", "/etc/passwd")])
+            .is_err());
+
+        /*
+         * Replacing a symlink to an outside file with a regular file must
+         * replace the link, not the file it points at.
+         */
+        let secret = format!("{}/secret", f.outside());
+        f.unpack(&[Ent::Symlink("root/motd", &secret)])
+            .expect("symlink layer");
+        f.unpack(&[Ent::File("root/motd", "mine")])
+            .expect("file layer");
+        assert_eq!(std::fs::read(f.root.join("motd")).unwrap(), b"mine");
+
+        f.assert_untouched();
+    }
+
+    #[test]
+    fn hardlink_escape() {
+        let f = Fixture::new();
+        let secret = format!("{}/secret", f.outside());
+
+        assert!(f
+            .unpack(&[Ent::Hardlink("root/h", "root/../outside/secret")])
+            .is_err());
+        assert!(f.unpack(&[Ent::Hardlink("root/h", &secret)]).is_err());
+        assert!(f.unpack(&[Ent::Hardlink("root/h", &secret[1..])]).is_err());
+        assert!(f
+            .unpack(&[
+                Ent::Symlink("root/d", f.outside()),
+                Ent::Hardlink("root/h", "root/d/secret"),
+            ])
+            .is_err());
+        assert!(!f.root.join("h").exists());
+
+        f.assert_untouched();
+    }
+
+    #[test]
+    fn whiteouts() {
+        let f = Fixture::new();
+        f.unpack(&[
+            Ent::Dir("root/etc"),
+            Ent::File("root/etc/motd", "hello\n"),
+            Ent::File("root/etc/issue", "hello\n"),
+            Ent::Dir("root/svc"),
+            Ent::Dir("root/svc/old"),
+            Ent::File("root/svc/old/a.xml", "a"),
+            Ent::File("root/svc/b.xml", "b"),
+            Ent::Symlink("root/out", f.outside()),
+        ])
+        .expect("lower layer");
+
+        /*
+         * An opaque marker empties the directory of what earlier archives
+         * put there, but not of what this archive has already unpacked.
+         * Markers never follow symbolic links.
+         */
+        f.unpack(&[
+            Ent::File("root/etc/.wh.motd", ""),
+            Ent::File("root/svc/c.xml", "c"),
+            Ent::File("root/svc/.wh..wh..opq", ""),
+            Ent::File("root/out/.wh.secret", ""),
+            Ent::File("root/out/.wh..wh..opq", ""),
+            Ent::File("root/.wh.out", ""),
+            Ent::File("root/.wh.missing", ""),
+        ])
+        .expect("whiteout layer");
+
+        assert!(!f.root.join("etc/motd").exists());
+        assert!(f.root.join("etc/issue").exists());
+        let names = std::fs::read_dir(f.root.join("svc"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![std::ffi::OsString::from("c.xml")]);
+        assert!(std::fs::symlink_metadata(f.root.join("out")).is_err());
+        assert!(!f.root.join(".wh.missing").exists());
+
+        f.assert_untouched();
+    }
+
+    #[test]
+    fn worker_threads() {
+        let names = (0..50).map(|i| format!("root/d/f{i}")).collect::<Vec<_>>();
+        let mut ents = vec![Ent::Dir("root/d")];
+        ents.extend(names.iter().map(|n| Ent::File(n, "contents")));
+        ents.push(Ent::Hardlink("root/d/link", "root/d/f0"));
+        let ar = hostile(&ents);
+
+        for threads in [0, 3] {
+            let f = Fixture::new();
+            let verifier =
+                signature::Verifier::new(signature::Policy::Off, Vec::new());
+            let mut u = Unpack::from_reader(
+                "test",
+                std::io::Cursor::new(ar.clone()),
+                &verifier,
+            )
+            .unwrap();
+            u.set_threads(threads);
+            let stats = u.unpack(&f.root, None).expect("unpack");
+
+            assert_eq!(stats.inline.files + stats.queued.files, 50);
+            assert_eq!(stats.queued.files, if threads > 0 { 50 } else { 0 });
+            assert_eq!(stats.inline.bytes + stats.queued.bytes, 50 * 8);
+            assert_eq!((stats.directories, stats.links), (1, 1));
+
+            for n in names.iter() {
+                let p = f.root.join(n.strip_prefix("root/").unwrap());
+                assert_eq!(std::fs::read(&p).unwrap(), b"contents");
+                let md = std::fs::metadata(&p).unwrap();
+                assert_eq!(md.mode() & 0o7777, 0o755);
+            }
+            let link = std::fs::metadata(f.root.join("d/link")).unwrap();
+            assert_eq!(link.nlink(), 2);
+        }
     }
 }
@@ -0,0 +1,133 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * Measure how quickly the copy queue copies large files, with in-kernel and
+ * buffered copying and a range of thread counts and batch sizes, as a guide
+ * to choosing COPY_THREADS and COPY_BATCH.  Run with:
+ *
+ *      cargo bench -p helios-build-utils
+ *
+ * The files are created in a temporary directory within BENCH_DIR, or the
+ * system temporary directory if that is not set.  BENCH_FILES and
+ * BENCH_SIZE_MB set how many files are copied in each run, and the size of
+ * each one.  Sparse files hold one megabyte of data in every sixteen.
+ */
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Result;
+use helios_build_utils::copyq::CopyQueue;
+
+const MB: u64 = 1024 * 1024;
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn make(path: &Path, size: u64, sparse: bool) -> Result<()> {
+    let f = File::create(path)?;
+    let chunk = (0..MB).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+    f.set_len(size)?;
+    let mut pos = 0;
+    while pos < size {
+        let n = chunk.len().min((size - pos) as usize);
+        f.write_all_at(&chunk[..n], pos)?;
+        pos += if sparse { 16 * MB } else { MB };
+    }
+    f.sync_all()?;
+    Ok(())
+}
+
+fn run(
+    files: &[PathBuf],
+    out: &Path,
+    buffered: bool,
+    threads: usize,
+    batch: usize,
+) -> Result<f64> {
+    std::fs::create_dir(out)?;
+
+    let start = Instant::now();
+    let mut cq = CopyQueue::new(threads, batch)?;
+    cq.set_buffered(buffered);
+    for (i, f) in files.iter().enumerate() {
+        cq.push_copy(f.clone(), out.join(i.to_string()));
+    }
+    cq.join()?;
+    let secs = start.elapsed().as_secs_f64();
+
+    std::fs::remove_dir_all(out)?;
+    Ok(secs)
+}
+
+fn main() -> Result<()> {
+    /*
+     * "cargo bench" passes "--bench", and "cargo test" runs benchmarks built
+     * without the test harness only to check that they start.
+     */
+    if !std::env::args().any(|a| a == "--bench") {
+        return Ok(());
+    }
+
+    let nfiles = env_u64("BENCH_FILES", 8);
+    let size = env_u64("BENCH_SIZE_MB", 64) * MB;
+    let base = std::env::var_os("BENCH_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let dir = tempfile::tempdir_in(base)?;
+
+    println!("{nfiles} files of {} MiB in {:?}", size / MB, dir.path());
+    println!(
+        "{:<8} {:<10} {:>7} {:>7} {:>10} {:>8}",
+        "FILES", "METHOD", "THREADS", "BATCH", "MiB/s", "MSEC",
+    );
+
+    for sparse in [false, true] {
+        let files = (0..nfiles)
+            .map(|i| {
+                let p = dir.path().join(format!("src.{i}"));
+                make(&p, size, sparse)?;
+                Ok(p)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for buffered in [false, true] {
+            for threads in [1, 4, 8, 16] {
+                for batch in [1, 4, 16] {
+                    let secs = run(
+                        &files,
+                        &dir.path().join("out"),
+                        buffered,
+                        threads,
+                        batch,
+                    )?;
+                    let mib = (nfiles * size / MB) as f64;
+                    println!(
+                        "{:<8} {:<10} {:>7} {:>7} {:>10.1} {:>8}",
+                        if sparse { "sparse" } else { "dense" },
+                        if buffered { "buffered" } else { "kernel" },
+                        threads,
+                        batch,
+                        mib / secs,
+                        (secs * 1000.0) as u64,
+                    );
+                }
+            }
+        }
+
+        for f in files {
+            std::fs::remove_file(f)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,296 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * Image archives are tar files, optionally compressed with gzip, zstd, or xz.
+ * The compression format is determined from the leading bytes of the archive
+ * rather than from the file name.
+ */
+
+use std::{
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+
+pub type Stream = Box<dyn Read + Send>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Codec {
+    /**
+     * The conventional file name extension for a tar file compressed with
+     * this codec; e.g., ".gz" for "files.tar.gz".
+     */
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Gzip => ".gz",
+            Codec::Zstd => ".zst",
+            Codec::Xz => ".xz",
+        }
+    }
+
+    pub fn all() -> &'static [Codec] {
+        &[Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::None]
+    }
+
+    fn levels(&self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Codec::None => 0..=0,
+            Codec::Gzip => 0..=9,
+            Codec::Zstd => 1..=22,
+            Codec::Xz => 0..=9,
+        }
+    }
+
+    fn default_level(&self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Gzip => 9,
+            Codec::Zstd => 3,
+            Codec::Xz => 6,
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Codec::None => "none",
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+        })
+    }
+}
+
+/**
+ * Determine the compression format from the first bytes of an archive.  At
+ * least 512 bytes should be provided, if available, so that an uncompressed
+ * tar header can be recognised.
+ */
+pub fn detect(magic: &[u8]) -> Result<Codec> {
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        Codec::Gzip
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Codec::Zstd
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Codec::Xz
+    } else if magic.get(257..262) == Some(b"ustar") {
+        Codec::None
+    } else {
+        bail!(
+            "archive is not a tar file, or is compressed in a format we do \
+            not support"
+        );
+    })
+}
+
+/**
+ * Read enough of a stream to determine the compression format.  Returns the
+ * codec and a stream that yields the complete archive, including the bytes we
+ * had to consume.
+ */
+pub fn sniff<R: Read + Send + 'static>(mut r: R) -> Result<(Codec, Stream)> {
+    let mut magic = Vec::with_capacity(512);
+    r.by_ref().take(512).read_to_end(&mut magic)?;
+    let codec = detect(&magic)?;
+    Ok((codec, Box::new(std::io::Cursor::new(magic).chain(r))))
+}
+
+/**
+ * Wrap a compressed stream in the decoder for the specified codec.
+ */
+pub fn decoder<R: Read + Send + 'static>(codec: Codec, r: R) -> Result<Stream> {
+    Ok(match codec {
+        Codec::None => Box::new(r),
+        Codec::Gzip => Box::new(flate2::read::GzDecoder::new(r)),
+        Codec::Zstd => Box::new(zstd::stream::read::Decoder::new(r)?),
+        Codec::Xz => Box::new(xz2::read::XzDecoder::new(r)),
+    })
+}
+
+/**
+ * A codec and compression level for writing an archive.  The string form is
+ * the codec name, optionally followed by a colon and the level; e.g., "gzip",
+ * "zstd:19", or "none".
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    codec: Codec,
+    level: u32,
+}
+
+impl Compression {
+    pub fn new(codec: Codec, level: Option<u32>) -> Result<Compression> {
+        let level = level.unwrap_or_else(|| codec.default_level());
+        if !codec.levels().contains(&level) {
+            bail!(
+                "{codec} compression level must be between {} and {}",
+                codec.levels().start(),
+                codec.levels().end(),
+            );
+        }
+        Ok(Compression { codec, level })
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /**
+     * Wrap a writer in an encoder.  The output depends only on the data
+     * written, so that the same archive contents always compress to the same
+     * bytes; e.g., the gzip header carries no timestamp.
+     */
+    pub fn encoder<W: Write>(&self, w: W) -> Result<Encoder<W>> {
+        Ok(match self.codec {
+            Codec::None => Encoder::None(w),
+            Codec::Gzip => Encoder::Gzip(
+                flate2::GzBuilder::new()
+                    .mtime(0)
+                    .write(w, flate2::Compression::new(self.level)),
+            ),
+            Codec::Zstd => {
+                let mut z =
+                    zstd::stream::write::Encoder::new(w, self.level as i32)?;
+                z.include_checksum(true)?;
+                Encoder::Zstd(z)
+            }
+            Codec::Xz => Encoder::Xz(xz2::write::XzEncoder::new(w, self.level)),
+        })
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new(Codec::Gzip, None).unwrap()
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => match level.parse::<u32>() {
+                Ok(level) => (name, Some(level)),
+                Err(_) => bail!("invalid compression level {level:?}"),
+            },
+            None => (s, None),
+        };
+
+        let codec = match name {
+            "none" => Codec::None,
+            "gzip" | "gz" => Codec::Gzip,
+            "zstd" | "zst" => Codec::Zstd,
+            "xz" => Codec::Xz,
+            other => bail!("unknown compression {other:?}"),
+        };
+
+        if codec == Codec::None && level.is_some() {
+            bail!("no compression level may be specified with \"none\"");
+        }
+
+        Compression::new(codec, level)
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.codec == Codec::None {
+            write!(f, "{}", self.codec)
+        } else {
+            write!(f, "{}:{}", self.codec, self.level)
+        }
+    }
+}
+
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /**
+     * Write out any buffered data and the trailer for the compressed stream,
+     * and return the underlying writer.
+     */
+    pub fn finish(self) -> Result<W> {
+        Ok(match self {
+            Encoder::None(w) => w,
+            Encoder::Gzip(e) => e.finish()?,
+            Encoder::Zstd(e) => e.finish()?,
+            Encoder::Xz(e) => e.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut a = tar::Builder::new(Vec::new());
+        let mut h = tar::Header::new_ustar();
+        h.set_path("oxide.json").unwrap();
+        h.set_size(3);
+        h.set_cksum();
+        a.append(&h, b"{}\n".as_slice()).unwrap();
+        let tarball = a.into_inner().unwrap();
+
+        for &codec in Codec::all() {
+            let c = Compression::new(codec, None).unwrap();
+            let mut e = c.encoder(Vec::new()).unwrap();
+            e.write_all(&tarball).unwrap();
+            let out = e.finish().unwrap();
+
+            let (found, r) = sniff(std::io::Cursor::new(out)).unwrap();
+            assert_eq!(found, codec);
+            let mut back = Vec::new();
+            decoder(found, r).unwrap().read_to_end(&mut back).unwrap();
+            assert!(back == tarball, "{codec} round trip");
+        }
+
+        assert!(detect(b"not an archive").is_err());
+        assert_eq!("zstd:19".parse::<Compression>().unwrap().level(), 19);
+        assert!("gzip:10".parse::<Compression>().is_err());
+        assert!("none:1".parse::<Compression>().is_err());
+    }
+}
@@ -2,39 +2,67 @@
  * Copyright 2024 Oxide Computer Company
  */
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 
 use std::{
+    fs::File,
+    io::Write,
+    os::fd::AsRawFd,
+    os::unix::ffi::OsStrExt,
+    os::unix::fs::{FileExt, PermissionsExt},
     os::unix::prelude::{MetadataExt, OpenOptionsExt},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Condvar, Mutex},
     thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/**
+ * A batch is dispatched to the worker threads once it holds this many bytes
+ * of file contents, even if it has fewer entries than the batch size.
+ */
+const BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/**
+ * Work to be done once the contents of a file have been written, such as
+ * setting the ownership and permissions through the open file.
+ */
+pub type Finish = Box<dyn FnOnce(&File) -> Result<()> + Send>;
+
 pub struct CopyQueue {
     inner: Arc<CopyQueueInner>,
     threads: Vec<thread::JoinHandle<std::result::Result<CopyStats, String>>>,
     pending: Vec<CopyEntry>,
+    pending_bytes: usize,
     batch: usize,
+    limit: usize,
 }
 
 #[derive(Default)]
 struct CopyQueueInner {
     cv: Condvar,
+    space: Condvar,
     locked: Mutex<CopyQueueLocked>,
 }
 
 #[derive(Default)]
 struct CopyQueueLocked {
     fin: bool,
+    failed: bool,
+    buffered: bool,
     q: Vec<Vec<CopyEntry>>,
 }
 
 impl CopyQueue {
     /**
-     * Create a thread pool and work queue for copying files.
+     * Create a thread pool and work queue for copying files.  There must be
+     * at least one thread, or nothing would ever take work from the queue.
      */
     pub fn new(threads: usize, batch: usize) -> Result<CopyQueue> {
+        if threads == 0 {
+            bail!("a copy queue needs at least one thread");
+        }
+
         let cqi = Arc::new(CopyQueueInner::default());
 
         let threads = (0..threads)
@@ -48,22 +76,52 @@ impl CopyQueue {
 
         Ok(CopyQueue {
             inner: cqi,
+            limit: threads.len(),
             threads,
             pending: vec![],
+            pending_bytes: 0,
             batch,
         })
     }
 
+    /**
+     * Hand the pending batch to the worker threads.  The number of batches
+     * waiting for a worker is bounded, so that a producer that is faster than
+     * the workers, such as one holding file contents in memory, will wait
+     * here for them to catch up.
+     */
     pub fn dispatch(&mut self) {
-        let pending = std::mem::replace(&mut self.pending, Vec::new());
+        let pending = std::mem::take(&mut self.pending);
+        self.pending_bytes = 0;
         let mut locked = self.inner.locked.lock().unwrap();
+        while locked.q.len() >= self.limit && !locked.failed {
+            locked = self.inner.space.wait(locked).unwrap();
+        }
         locked.q.push(pending);
         self.inner.cv.notify_one();
     }
 
+    /**
+     * Copy file contents through a buffer in this process, rather than within
+     * the kernel, even where the system supports doing so.  This is mostly
+     * useful for comparing the two.
+     */
+    pub fn set_buffered(&mut self, buffered: bool) {
+        self.inner.locked.lock().unwrap().buffered = buffered;
+    }
+
+    /**
+     * Returns true if a worker thread has stopped because of an error, in
+     * which case the producer should stop and call join() to find out why.
+     */
+    pub fn failed(&self) -> bool {
+        self.inner.locked.lock().unwrap().failed
+    }
+
     /**
      * Schedules a file copy operation in the thread pool and returns
-     * immediately.
+     * immediately.  The copy is given the ownership, permissions, and
+     * modification time of the original.
      */
     pub fn push_copy(&mut self, src: PathBuf, dst: PathBuf) {
         self.pending.push(CopyEntry::Copy { src, dst });
@@ -73,6 +131,24 @@ impl CopyQueue {
         }
     }
 
+    /**
+     * Schedules a copy into a file that has already been created, such as one
+     * created safely within a zone root.  The file is given the ownership,
+     * permissions, and modification time of the source file.  The destination
+     * path is used only in error messages.
+     */
+    pub fn push_copy_to(&mut self, src: PathBuf, dst: PathBuf, file: File) {
+        self.pending.push(CopyEntry::CopyTo { src, dst, file });
+
+        if self.pending.len() == self.batch {
+            self.dispatch();
+        }
+    }
+
+    /**
+     * Schedules the creation of a symbolic link with the same target,
+     * ownership, and modification time as the link at "src".
+     */
     pub fn push_relative_link(&mut self, src: PathBuf, dst: PathBuf) {
         self.pending.push(CopyEntry::RelativeLink { src, dst });
 
@@ -81,18 +157,56 @@ impl CopyQueue {
         }
     }
 
-    pub fn push_absolute_link(&mut self, src: String, dst: PathBuf) {
-        self.pending.push(CopyEntry::AbsoluteLink { src, dst });
+    /**
+     * Schedules the creation of a symbolic link with the given target.  The
+     * link is given the ownership and modification time in "attrs", if any.
+     */
+    pub fn push_absolute_link(
+        &mut self,
+        src: String,
+        dst: PathBuf,
+        attrs: Option<Attrs>,
+    ) {
+        self.pending
+            .push(CopyEntry::AbsoluteLink { src, dst, attrs });
 
         if self.pending.len() == self.batch {
             self.dispatch();
         }
     }
 
+    /**
+     * Schedules the contents of a file, which has already been created, to be
+     * written in the thread pool, followed by any finishing work.  The path
+     * is used only in error messages.
+     */
+    pub fn push_write(
+        &mut self,
+        dst: PathBuf,
+        file: File,
+        data: Vec<u8>,
+        finish: Finish,
+    ) {
+        self.pending_bytes += data.len();
+        self.pending.push(CopyEntry::Write {
+            dst,
+            file,
+            data,
+            finish,
+        });
+
+        if self.pending.len() == self.batch || self.pending_bytes >= BATCH_BYTES
+        {
+            self.dispatch();
+        }
+    }
+
     /**
      * Waits for all enqueued file copies to complete and all of the threads in
      * the thread pool to exit.  Returns statistics about the copied files,
-     * aggregated from all worker threads.
+     * aggregated from all worker threads.  If a worker failed, the other
+     * workers stop taking work and we still wait for every one of them, so
+     * that nothing is writing files once we return the first error.
      */
     pub fn join(mut self) -> Result<CopyStats> {
         self.dispatch();
@@ -104,44 +218,174 @@ impl CopyQueue {
         self.inner.cv.notify_all();
 
         let mut tcs = CopyStats::default();
+        let mut err = None;
 
         for t in self.threads {
-            let cs = t
-                .join()
-                .unwrap()
-                .map_err(|e| anyhow!("copy thread: {e:?}"))?;
-
-            tcs.files += cs.files;
-            tcs.bytes += cs.bytes;
+            match t.join().unwrap() {
+                Ok(cs) => {
+                    tcs.files += cs.files;
+                    tcs.bytes += cs.bytes;
+                    tcs.links += cs.links;
+                    tcs.saved += cs.saved;
+                    tcs.pruned += cs.pruned;
+                }
+                Err(e) => {
+                    err.get_or_insert(e);
+                }
+            }
         }
 
-        Ok(tcs)
+        match err {
+            Some(e) => Err(anyhow!("copy thread: {e:?}")),
+            None => Ok(tcs),
+        }
     }
 }
 
 enum CopyEntry {
-    Copy { src: PathBuf, dst: PathBuf },
-    RelativeLink { src: PathBuf, dst: PathBuf },
-    AbsoluteLink { src: String, dst: PathBuf },
+    Copy {
+        src: PathBuf,
+        dst: PathBuf,
+    },
+    CopyTo {
+        src: PathBuf,
+        dst: PathBuf,
+        file: File,
+    },
+    RelativeLink {
+        src: PathBuf,
+        dst: PathBuf,
+    },
+    AbsoluteLink {
+        src: String,
+        dst: PathBuf,
+        attrs: Option<Attrs>,
+    },
+    Write {
+        dst: PathBuf,
+        file: File,
+        data: Vec<u8>,
+        finish: Finish,
+    },
+}
+
+/**
+ * The ownership, permissions, and modification time of an object in a source
+ * tree, to be given to its replica.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Attrs {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+impl From<&std::fs::Metadata> for Attrs {
+    fn from(md: &std::fs::Metadata) -> Self {
+        Attrs {
+            uid: md.uid(),
+            gid: md.gid(),
+            mode: md.mode() & 0o7777,
+            mtime: md.mtime(),
+            mtime_nsec: md.mtime_nsec(),
+        }
+    }
+}
+
+impl Attrs {
+    pub fn modified(&self) -> SystemTime {
+        let nsec = Duration::from_nanos(self.mtime_nsec as u64);
+        if self.mtime >= 0 {
+            UNIX_EPOCH + Duration::from_secs(self.mtime as u64) + nsec
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.mtime.unsigned_abs()) + nsec
+        }
+    }
+
+    /**
+     * Apply the attributes to an object through a handle we already have
+     * open.  Changing the owner may clear set-id bits, so the mode is set
+     * afterwards.
+     */
+    pub fn apply_to(&self, f: &File) -> std::io::Result<()> {
+        std::os::unix::fs::fchown(f, Some(self.uid), Some(self.gid))?;
+        f.set_permissions(std::fs::Permissions::from_mode(self.mode))?;
+        f.set_modified(self.modified())
+    }
+
+    /**
+     * Apply the attributes to a symbolic link, which has no permissions of
+     * its own, without following it.
+     */
+    pub fn apply(&self, path: &Path) -> std::io::Result<()> {
+        std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid))?;
+
+        let name = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT as _,
+            },
+            libc::timespec {
+                tv_sec: self.mtime as libc::time_t,
+                tv_nsec: self.mtime_nsec as _,
+            },
+        ];
+        if unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                name.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        } != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct CopyStats {
     pub files: u64,
     pub bytes: u64,
+    /**
+     * Files that were made hard links to an earlier copy of the same file,
+     * rather than copied again, and the bytes that were not copied as a
+     * result.
+     */
+    pub links: u64,
+    pub saved: u64,
+    /**
+     * Objects in the source tree that were left out because they were
+     * excluded.
+     */
+    pub pruned: u64,
 }
 
 fn copy_thread(
     cqi: Arc<CopyQueueInner>,
 ) -> std::result::Result<CopyStats, String> {
-    let mut cs = CopyStats { files: 0, bytes: 0 };
+    let mut cs = CopyStats::default();
 
     loop {
-        let cv = {
+        let (cv, buffered) = {
             let mut locked = cqi.locked.lock().unwrap();
             loop {
+                /*
+                 * Once any worker has failed, the producer is going to give
+                 * up, so there is no point in doing any more of the work.
+                 */
+                if locked.failed {
+                    return Ok(cs);
+                }
+
                 if let Some(cv) = locked.q.pop() {
-                    break cv;
+                    cqi.space.notify_one();
+                    break (cv, locked.buffered);
                 } else {
                     if locked.fin {
                         return Ok(cs);
@@ -154,66 +398,374 @@ fn copy_thread(
         };
 
         for work in cv {
-            match work {
-                CopyEntry::Copy { src, dst } => {
-                    let mkerror = |e| format!("copy {src:?} -> {dst:?}: {e}");
-
-                    cs.files += 1;
-                    std::fs::remove_file(&dst).ok();
-
-                    let fsrc = std::fs::OpenOptions::new()
-                        .read(true)
-                        .open(&src)
-                        .map_err(mkerror)?;
-                    let md = fsrc.metadata().map_err(mkerror)?;
-                    assert!(md.is_file());
-
-                    /*
-                     * Create the target file with the correct mode.  We made
-                     * sure to remove it earlier, so we should make sure we are
-                     * creating it anew here.
-                     */
-                    let fdst = std::fs::OpenOptions::new()
-                        .mode(md.mode())
-                        .create_new(true)
-                        .write(true)
-                        .open(&dst)
-                        .map_err(mkerror)?;
-
-                    /*
-                     * The easiest way to copy a file, std::fs::copy(), appears
-                     * to use a regrettably microscopic buffer for reads and
-                     * writes.  To make things go quite a lot faster, create a
-                     * buffered reader and writer with a large buffer and use
-                     * std::io::copy() instead, which will size read and write
-                     * calls based on that buffer size.
-                     */
-                    let cap = 1024 * 1024;
-                    let mut bsrc = std::io::BufReader::with_capacity(cap, fsrc);
-                    let mut bdst = std::io::BufWriter::with_capacity(cap, fdst);
-
-                    cs.bytes +=
-                        std::io::copy(&mut bsrc, &mut bdst).map_err(mkerror)?;
-                }
+            if let Err(e) = copy_one(&mut cs, work, buffered) {
+                /*
+                 * Make sure the producer does not wait forever for us to make
+                 * room in the queue.
+                 */
+                cqi.locked.lock().unwrap().failed = true;
+                cqi.space.notify_all();
+                cqi.cv.notify_all();
+                return Err(e);
+            }
+        }
+    }
+}
 
-                CopyEntry::RelativeLink { src, dst } => {
-                    let mke = |e| format!("rel link {src:?} -> {dst:?}: {e}");
+fn copy_one(
+    cs: &mut CopyStats,
+    work: CopyEntry,
+    buffered: bool,
+) -> std::result::Result<(), String> {
+    match work {
+        CopyEntry::Copy { src, dst } => {
+            let mkerror = |e| format!("copy {src:?} -> {dst:?}: {e}");
+
+            cs.files += 1;
+            std::fs::remove_file(&dst).ok();
+
+            let fsrc = std::fs::OpenOptions::new()
+                .read(true)
+                .open(&src)
+                .map_err(mkerror)?;
+            let md = fsrc.metadata().map_err(mkerror)?;
+            assert!(md.is_file());
+
+            /*
+             * Create the target file with the correct mode.  We made
+             * sure to remove it earlier, so we should make sure we are
+             * creating it anew here.
+             */
+            let fdst = std::fs::OpenOptions::new()
+                .mode(md.mode())
+                .create_new(true)
+                .write(true)
+                .open(&dst)
+                .map_err(mkerror)?;
+
+            cs.bytes +=
+                copy_data(&fsrc, &fdst, md.len(), buffered).map_err(mkerror)?;
+            Attrs::from(&md).apply_to(&fdst).map_err(mkerror)?;
+        }
 
-                    let linktarget = std::fs::read_link(&src).map_err(mke)?;
+        CopyEntry::CopyTo { src, dst, file } => {
+            let mkerror = |e| format!("copy {src:?} -> {dst:?}: {e}");
 
-                    /*
-                     * XXX remove first...
-                     */
-                    std::os::unix::fs::symlink(&linktarget, &dst)
-                        .map_err(mke)?;
-                }
+            cs.files += 1;
 
-                CopyEntry::AbsoluteLink { src, dst } => {
-                    let mke = |e| format!("abs link {src:?} -> {dst:?}: {e}");
+            let fsrc = std::fs::File::open(&src).map_err(mkerror)?;
+            let md = fsrc.metadata().map_err(mkerror)?;
+            assert!(md.is_file());
 
-                    std::os::unix::fs::symlink(&src, &dst).map_err(mke)?;
-                }
+            cs.bytes +=
+                copy_data(&fsrc, &file, md.len(), buffered).map_err(mkerror)?;
+            Attrs::from(&md).apply_to(&file).map_err(mkerror)?;
+        }
+
+        CopyEntry::RelativeLink { src, dst } => {
+            let mke = |e| format!("rel link {src:?} -> {dst:?}: {e}");
+
+            let linktarget = std::fs::read_link(&src).map_err(mke)?;
+            let md = std::fs::symlink_metadata(&src).map_err(mke)?;
+
+            /*
+             * XXX remove first...
+             */
+            std::os::unix::fs::symlink(&linktarget, &dst).map_err(mke)?;
+            Attrs::from(&md).apply(&dst).map_err(mke)?;
+        }
+
+        CopyEntry::AbsoluteLink { src, dst, attrs } => {
+            let mke = |e| format!("abs link {src:?} -> {dst:?}: {e}");
+
+            std::os::unix::fs::symlink(&src, &dst).map_err(mke)?;
+            if let Some(attrs) = attrs {
+                attrs.apply(&dst).map_err(mke)?;
             }
         }
+
+        CopyEntry::Write {
+            dst,
+            mut file,
+            data,
+            finish,
+        } => {
+            let mkerror = |e| format!("write {dst:?}: {e}");
+
+            file.write_all(&data).map_err(mkerror)?;
+            finish(&file).map_err(|e| format!("{dst:?}: {e}"))?;
+
+            cs.files += 1;
+            cs.bytes += data.len() as u64;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Copy the contents of a file into another, empty, file.  If the file system
+ * can share blocks between the two files, we do that.  Otherwise we copy each
+ * region of the source file that holds data, leaving the holes in a sparse
+ * file as holes, and copy within the kernel where we can.  Returns the number
+ * of bytes of data copied.
+ */
+pub fn copy_data(
+    src: &File,
+    dst: &File,
+    len: u64,
+    buffered: bool,
+) -> std::io::Result<u64> {
+    if !buffered && clone_file(src, dst)? {
+        return Ok(len);
+    }
+
+    let mut fast = !buffered;
+    let mut buf = Vec::new();
+    let mut copied = 0;
+    let mut pos = 0;
+    while pos < len {
+        let Some((start, end)) = next_data(src, pos, len)? else {
+            break;
+        };
+        copied += copy_range(src, dst, start, end, &mut fast, &mut buf)?;
+        pos = end;
+    }
+
+    /*
+     * Extend the copy over any hole at the end of the source file.
+     */
+    dst.set_len(len)?;
+    Ok(copied)
+}
+
+/**
+ * Errors from the in-kernel copy and clone operations that mean the
+ * operation is not available for these files, rather than that something has
+ * gone wrong.
+ */
+fn unsupported(e: &std::io::Error) -> bool {
+    /*
+     * ENOTSUP and EOPNOTSUPP are the same on some systems, but not all.
+     */
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::ENOTTY)
+    ) || matches!(
+        e.raw_os_error(),
+        Some(n) if n == libc::ENOTSUP || n == libc::EOPNOTSUPP
+    )
+}
+
+/**
+ * Make "dst" share the blocks of "src", if the file system allows it.
+ * Returns false if it does not.
+ */
+#[cfg(target_os = "linux")]
+fn clone_file(src: &File, dst: &File) -> std::io::Result<bool> {
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) }
+        == 0
+    {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    if unsupported(&e) {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+/*
+ * XXX reflink(3C) can clone files on illumos, but only by path; for now, we
+ * rely on copy_file_range(3C) instead.
+ */
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_src: &File, _dst: &File) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/**
+ * Find the first region at or after "pos" that holds data, returning its
+ * start and end offsets, or None if there is only a hole between "pos" and
+ * "len".  If the file system does not report holes, the rest of the file is
+ * treated as data.
+ */
+#[cfg(any(target_os = "linux", target_os = "illumos"))]
+fn next_data(
+    f: &File,
+    pos: u64,
+    len: u64,
+) -> std::io::Result<Option<(u64, u64)>> {
+    let fd = f.as_raw_fd();
+
+    let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+    if start < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ if unsupported(&e) => Ok(Some((pos, len))),
+            _ => Err(e),
+        };
+    }
+
+    let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+    if end < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let start = (start as u64).min(len);
+    Ok((start < len).then_some((start, (end as u64).min(len))))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "illumos")))]
+fn next_data(
+    _f: &File,
+    pos: u64,
+    len: u64,
+) -> std::io::Result<Option<(u64, u64)>> {
+    Ok(Some((pos, len)))
+}
+
+#[cfg(target_os = "linux")]
+use libc::copy_file_range;
+
+#[cfg(target_os = "illumos")]
+extern "C" {
+    /*
+     * See copy_file_range(3C).
+     */
+    fn copy_file_range(
+        infd: libc::c_int,
+        inoff: *mut libc::off_t,
+        outfd: libc::c_int,
+        outoff: *mut libc::off_t,
+        len: libc::size_t,
+        flags: libc::c_uint,
+    ) -> libc::ssize_t;
+}
+
+/**
+ * Copy the region between "start" and "end" to the same offsets in "dst",
+ * within the kernel while "fast" is true.  If the system cannot do that for
+ * these files, we clear "fast" and copy through "buf" instead.  Returns the
+ * number of bytes copied, which may be short if the source file shrinks.
+ */
+fn copy_range(
+    src: &File,
+    dst: &File,
+    start: u64,
+    end: u64,
+    fast: &mut bool,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<u64> {
+    let mut pos = start;
+
+    #[cfg(any(target_os = "linux", target_os = "illumos"))]
+    while *fast && pos < end {
+        let mut inoff = pos as libc::off_t;
+        let mut outoff = pos as libc::off_t;
+        let want = (end - pos).min(1 << 30) as libc::size_t;
+        let n = unsafe {
+            copy_file_range(
+                src.as_raw_fd(),
+                &mut inoff,
+                dst.as_raw_fd(),
+                &mut outoff,
+                want,
+                0,
+            )
+        };
+        if n < 0 {
+            let e = std::io::Error::last_os_error();
+            if !unsupported(&e) {
+                return Err(e);
+            }
+            *fast = false;
+        } else if n == 0 {
+            return Ok(pos - start);
+        } else {
+            pos += n as u64;
+        }
+    }
+
+    /*
+     * The easiest way to copy a file, std::fs::copy(), appears to use a
+     * regrettably microscopic buffer for reads and writes.  We use a large
+     * buffer instead, which makes things go quite a lot faster.
+     */
+    while pos < end {
+        if buf.is_empty() {
+            buf.resize(1024 * 1024, 0);
+        }
+        let want = (end - pos).min(buf.len() as u64) as usize;
+        let n = src.read_at(&mut buf[..want], pos)?;
+        if n == 0 {
+            break;
+        }
+        dst.write_all_at(&buf[..n], pos)?;
+        pos += n as u64;
+    }
+
+    Ok(pos - start)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn copy_sparse() {
+        const LEN: u64 = 64 * 1024 * 1024;
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let f = File::create(&src).unwrap();
+        f.set_len(LEN).unwrap();
+        f.write_all_at(b"start", 0).unwrap();
+        f.write_all_at(b"middle", LEN / 2).unwrap();
+        drop(f);
+        let sparse = std::fs::metadata(&src).unwrap().blocks() * 512 < LEN;
+
+        for buffered in [false, true] {
+            let dst = dir.path().join(format!("dst.{buffered}"));
+
+            let mut cq = CopyQueue::new(2, 4).unwrap();
+            cq.set_buffered(buffered);
+            cq.push_copy(src.clone(), dst.clone());
+            let cs = cq.join().unwrap();
+            assert_eq!(cs.files, 1);
+
+            let md = std::fs::metadata(&dst).unwrap();
+            assert_eq!(md.len(), LEN);
+            if sparse {
+                assert!(md.blocks() * 512 < LEN / 4);
+            }
+            assert!(
+                std::fs::read(&src).unwrap() == std::fs::read(&dst).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn copy_failure() {
+        assert!(CopyQueue::new(0, 4).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::write(&src, "data").unwrap();
+
+        /*
+         * The first batch fails; once all of the workers have been joined,
+         * nothing after it has been copied.
+         */
+        let mut cq = CopyQueue::new(1, 1).unwrap();
+        cq.push_copy(dir.path().join("missing"), dir.path().join("dst.0"));
+        while !cq.failed() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        cq.push_copy(src.clone(), dir.path().join("dst.1"));
+        assert!(cq.join().is_err());
+        assert!(!dir.path().join("dst.1").exists());
     }
 }
@@ -4,7 +4,36 @@
 
 use std::{collections::HashMap, mem, path::Path, str::FromStr};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+
+/**
+ * The defaults file that holds tuning settings for the brand and its tools.
+ */
+pub const BRAND_DEFAULTS: &str = "/etc/default/helios-omicron1";
+
+/**
+ * Some checks can be configured to be skipped, to produce a warning, or to
+ * cause the operation to fail.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    Off,
+    Warn,
+    Enforce,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "off" => Policy::Off,
+            "warn" => Policy::Warn,
+            "enforce" => Policy::Enforce,
+            other => bail!("unknown policy {other:?}"),
+        })
+    }
+}
 
 /**
  * An extremely minimal parser for a subset of defaults files, as potentially
@@ -20,6 +49,21 @@ impl DefaultsFile {
         self.values.get(name.as_ref()).and_then(|v| v.parse().ok())
     }
 
+    pub fn get_str<S: AsRef<str>>(&self, name: S) -> Option<&str> {
+        self.values.get(name.as_ref()).map(String::as_str)
+    }
+
+    /**
+     * Unlike the other accessors, an unrecognised policy value is an error
+     * rather than being treated as if it were not set.
+     */
+    pub fn get_policy<S: AsRef<str>>(&self, name: S) -> Result<Option<Policy>> {
+        let name = name.as_ref();
+        self.get_str(name)
+            .map(|v| v.parse().map_err(|e| anyhow!("{name}: {e}")))
+            .transpose()
+    }
+
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<DefaultsFile> {
         let path = path.as_ref();
 
@@ -141,7 +185,7 @@ mod test {
             "\n",
         );
 
-        let df = DefaultsFile::from_str(&input).expect("parsed output");
+        let df = DefaultsFile::from_str(input).expect("parsed output");
         println!("df = {df:#?}");
 
         assert_eq!(df.get_usize("COPY_THREADS"), Some(16));
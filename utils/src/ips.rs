@@ -266,7 +266,7 @@ impl Action {
         for a in alls {
             if !*image_facets
                 .get(a)
-                .expect(&format!("facet {a:?} missing from image?"))
+                .unwrap_or_else(|| panic!("facet {a:?} missing from image?"))
             {
                 return false;
             }
@@ -279,7 +279,7 @@ impl Action {
         for t in trues {
             if *image_facets
                 .get(t)
-                .expect(&format!("facet {t:?} missing from image?"))
+                .unwrap_or_else(|| panic!("facet {t:?} missing from image?"))
             {
                 /*
                  * Only one matching facet is required amongst those listed
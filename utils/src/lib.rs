@@ -2,9 +2,12 @@
  * Copyright 2024 Oxide Computer Company
  */
 
+pub mod compress;
 pub mod copyq;
 pub mod defaults;
 #[allow(clippy::many_single_char_names)]
 pub mod ips;
 pub mod metadata;
+pub mod replicate;
+pub mod store;
 pub mod tree;
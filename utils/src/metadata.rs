@@ -3,12 +3,14 @@
  */
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    io::Read,
     time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -18,35 +20,366 @@ pub enum ArchiveType {
     Os,
 }
 
+/**
+ * The type of a file system object described by a manifest entry.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    Directory,
+    File,
+    Symlink,
+    Hardlink,
+    Fifo,
+}
+
+impl EntryKind {
+    pub fn from_tar(et: tar::EntryType) -> Option<EntryKind> {
+        Some(match et {
+            tar::EntryType::Directory => EntryKind::Directory,
+            tar::EntryType::Regular
+            | tar::EntryType::Continuous
+            | tar::EntryType::GNUSparse => EntryKind::File,
+            tar::EntryType::Symlink => EntryKind::Symlink,
+            tar::EntryType::Link => EntryKind::Hardlink,
+            tar::EntryType::Fifo => EntryKind::Fifo,
+            _ => return None,
+        })
+    }
+}
+
+/**
+ * A manifest entry describes one object stored under the "root/" prefix of an
+ * archive.  Regular files carry the SHA-256 digest of their contents, and both
+ * kinds of link carry their target as it appears in the archive.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl ManifestEntry {
+    /**
+     * Construct a manifest entry from a tar header that is about to be
+     * appended to an archive.  The caller must provide the content digest for
+     * regular files; see sha256_digest().
+     */
+    pub fn from_header(
+        h: &tar::Header,
+        sha256: Option<String>,
+    ) -> Result<ManifestEntry> {
+        let Some(kind) = EntryKind::from_tar(h.entry_type()) else {
+            bail!("unsupported entry type {:?}", h.entry_type());
+        };
+
+        if (kind == EntryKind::File) != sha256.is_some() {
+            bail!("a digest must be provided for, and only for, regular files");
+        }
+
+        let target = match kind {
+            EntryKind::Symlink | EntryKind::Hardlink => {
+                let Some(target) = h.link_name()? else {
+                    bail!("link entry without a target");
+                };
+                let Some(target) = target.to_str() else {
+                    bail!("link target {target:?} is not UTF-8");
+                };
+                Some(target.to_string())
+            }
+            _ => None,
+        };
+
+        Ok(ManifestEntry {
+            kind,
+            mode: h.mode()?,
+            uid: h.uid()?,
+            gid: h.gid()?,
+            sha256,
+            target,
+        })
+    }
+}
+
+/**
+ * Compute the SHA-256 digest of everything that can be read from "r", as a
+ * lower-case hexadecimal string.
+ */
+pub fn sha256_digest<R: Read>(mut r: R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut r, &mut hasher)?;
+    Ok(hex_string(&hasher.finalize()))
+}
+
+pub fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/**
+ * A whiteout marker in a layer archive removes a path that was installed by
+ * the baseline archive or by an earlier layer.  The markers follow the OCI
+ * image layer convention: an empty regular file named ".wh.<name>" removes
+ * "<name>" from the same directory, along with anything beneath it, and one
+ * named ".wh..wh..opq" removes everything within its directory but leaves the
+ * directory itself.  Paths are relative to the "root/" prefix in the archive.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Whiteout {
+    Path(String),
+    Opaque(String),
+}
+
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+pub const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+impl Whiteout {
+    /**
+     * Determine whether a path in an archive is a whiteout marker, and if so,
+     * what it removes.  Other names that begin with ".wh..wh." are reserved
+     * and are rejected.
+     */
+    pub fn from_marker(path: &str) -> Result<Option<Whiteout>> {
+        let (dir, name) = match path.trim_end_matches('/').rsplit_once('/') {
+            Some((dir, name)) => (dir, name),
+            None => ("", path.trim_end_matches('/')),
+        };
+        let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) else {
+            return Ok(None);
+        };
+
+        let join = |name: &str| {
+            if dir.is_empty() {
+                name.to_string()
+            } else {
+                format!("{dir}/{name}")
+            }
+        };
+
+        if name == WHITEOUT_OPAQUE {
+            Ok(Some(Whiteout::Opaque(dir.to_string())))
+        } else if target.starts_with(WHITEOUT_PREFIX) {
+            bail!("unsupported whiteout marker {path:?}");
+        } else if target.is_empty() || target == "." || target == ".." {
+            bail!("invalid whiteout marker {path:?}");
+        } else {
+            Ok(Some(Whiteout::Path(join(target))))
+        }
+    }
+
+    /**
+     * The path of the marker entry, relative to the "root/" prefix.
+     */
+    pub fn marker(&self) -> String {
+        let (dir, name) = match self {
+            Whiteout::Path(p) => match p.rsplit_once('/') {
+                Some((dir, name)) => (dir, format!("{WHITEOUT_PREFIX}{name}")),
+                None => ("", format!("{WHITEOUT_PREFIX}{p}")),
+            },
+            Whiteout::Opaque(dir) => (dir.as_str(), WHITEOUT_OPAQUE.into()),
+        };
+        if dir.is_empty() {
+            name
+        } else {
+            format!("{dir}/{name}")
+        }
+    }
+
+    /**
+     * Construct the header for the marker entry, under the "root/" prefix.
+     * The marker is an empty file, so the manifest entry for it, if needed,
+     * can be made with the digest of no data.
+     */
+    pub fn header(&self, mtime: u64) -> Result<tar::Header> {
+        let mut h = tar::Header::new_ustar();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_username("root")?;
+        h.set_uid(0);
+        h.set_groupname("root")?;
+        h.set_gid(0);
+        h.set_path(format!("root/{}", self.marker()))?;
+        h.set_mode(0o444);
+        h.set_size(0);
+        h.set_mtime(mtime);
+        h.set_cksum();
+        Ok(h)
+    }
+
+    pub fn append_to_tar<T: std::io::Write>(
+        &self,
+        a: &mut tar::Builder<T>,
+        mtime: u64,
+    ) -> Result<()> {
+        a.append(&self.header(mtime)?, std::io::empty())?;
+        Ok(())
+    }
+}
+
+/**
+ * Layers may declare a name and version, and the names of other layers that
+ * they require or conflict with.  The brand uses these declarations to order
+ * the layers supplied at install time.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LayerInfo {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+}
+
+impl std::fmt::Display for LayerInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(version) = &self.version {
+            write!(f, "{}@{}", self.name, version)
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
+}
+
+/**
+ * A publisher configured in the image from which an archive was generated.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Publisher {
+    pub name: String,
+    pub origin: String,
+}
+
+/**
+ * Baseline and OS archives record details of the package image from which
+ * they were generated, so that the brand can detect an archive that does not
+ * match the running system.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Provenance {
+    /**
+     * The FMRI of the OS incorporation installed in the image.
+     */
+    pub incorporation: String,
+    /**
+     * The FMRI of every package installed in the image.
+     */
+    pub packages: Vec<String>,
+    pub publishers: Vec<Publisher>,
+    /**
+     * When the archive was generated, in seconds since the UNIX epoch.
+     */
+    pub generated: u64,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Metadata {
     v: String,
     t: ArchiveType,
-    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "sorted"
+    )]
     i: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    m: Option<BTreeMap<String, ManifestEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    l: Option<LayerInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    p: Option<Provenance>,
+}
+
+/**
+ * Serialise the informational properties in order of their names, so that the
+ * same properties always produce the same metadata record.
+ */
+fn sorted<S: serde::Serializer>(
+    i: &HashMap<String, String>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    i.iter().collect::<BTreeMap<_, _>>().serialize(s)
 }
 
 pub fn parse(s: &str) -> Result<Metadata> {
     let m: Metadata = serde_json::from_str(s)?;
-    if m.v != "1" {
-        bail!("unexpected metadata version {}", m.v);
+    match m.v.as_str() {
+        "1" => {
+            if m.m.is_some() {
+                bail!("version 1 metadata must not include a manifest");
+            }
+        }
+        "2" => {
+            if m.m.is_none() {
+                bail!("version 2 metadata must include a manifest");
+            }
+        }
+        _ => bail!("unexpected metadata version {}", m.v),
+    }
+    if m.l.is_some() && !m.is_layer() {
+        bail!("only layer archives may include layer information");
+    }
+    if m.p.is_some() && m.is_layer() {
+        bail!("layer archives may not include provenance information");
     }
     Ok(m)
 }
 
+/**
+ * If the SOURCE_DATE_EPOCH environment variable is set, return the timestamp
+ * it contains.  Archive producers use this timestamp in place of the current
+ * time and file modification times, so that the same inputs produce the same
+ * archive; see https://reproducible-builds.org/specs/source-date-epoch/.
+ */
+pub fn source_date_epoch() -> Result<Option<u64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(v) => match v.trim().parse::<u64>() {
+            Ok(t) => Ok(Some(t)),
+            Err(_) => bail!("invalid SOURCE_DATE_EPOCH {v:?}"),
+        },
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => bail!("SOURCE_DATE_EPOCH: {e}"),
+    }
+}
+
 impl Metadata {
+    /**
+     * Append the metadata record to an archive, with a modification time taken
+     * from SOURCE_DATE_EPOCH if it is set, or the current time otherwise.
+     */
     pub fn append_to_tar<T: std::io::Write>(
         &self,
         a: &mut tar::Builder<T>,
+    ) -> Result<()> {
+        let mtime = match source_date_epoch()? {
+            Some(t) => t,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        };
+
+        self.append_to_tar_with_mtime(a, mtime)
+    }
+
+    /**
+     * Append the metadata record to an archive with a specific modification
+     * time.  The record is otherwise a function only of the metadata, so that
+     * reproducible archives can be produced.
+     */
+    pub fn append_to_tar_with_mtime<T: std::io::Write>(
+        &self,
+        a: &mut tar::Builder<T>,
+        mtime: u64,
     ) -> Result<()> {
         let mut b = serde_json::to_vec(self)?;
         b.push(b'\n');
 
-        let mtime = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
         let mut h = tar::Header::new_ustar();
         h.set_entry_type(tar::EntryType::Regular);
         h.set_username("root")?;
@@ -82,11 +415,110 @@ impl Metadata {
     pub fn info(&self) -> &HashMap<String, String> {
         &self.i
     }
+
+    /**
+     * Version 2 archives include a manifest of every entry under "root/",
+     * keyed by the path relative to that prefix.  Version 1 archives have no
+     * manifest and cannot be verified.
+     */
+    pub fn manifest(&self) -> Option<&BTreeMap<String, ManifestEntry>> {
+        self.m.as_ref()
+    }
+
+    pub fn layer_info(&self) -> Option<&LayerInfo> {
+        self.l.as_ref()
+    }
+
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.p.as_ref()
+    }
+}
+
+/**
+ * Determine the order in which to apply a set of layers.  Each layer is
+ * identified by a label (e.g., the archive path) for use in error messages.
+ * Layers are applied after any layers they require, and otherwise in the
+ * order provided.  Returns the indices of the layers in application order,
+ * or an error naming the layers involved if a required layer is missing, two
+ * layers conflict, or the requirements form a cycle.
+ */
+pub fn layer_order(layers: &[(&str, &Metadata)]) -> Result<Vec<usize>> {
+    let describe = |i: usize| {
+        let (label, md) = layers[i];
+        match md.layer_info() {
+            Some(li) => format!("layer {li} ({label})"),
+            None => format!("unnamed layer ({label})"),
+        }
+    };
+
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    for (i, (_, md)) in layers.iter().enumerate() {
+        if let Some(li) = md.layer_info() {
+            if let Some(j) = names.insert(li.name.as_str(), i) {
+                bail!("{} and {} have the same name", describe(j), describe(i));
+            }
+        }
+    }
+
+    /*
+     * Check all requirements and conflicts before we attempt to order
+     * anything, so that the error names the layers at fault.
+     */
+    let mut deps: Vec<Vec<usize>> = vec![Vec::new(); layers.len()];
+    for (i, (_, md)) in layers.iter().enumerate() {
+        let Some(li) = md.layer_info() else {
+            continue;
+        };
+
+        for c in li.conflicts.iter() {
+            if let Some(&j) = names.get(c.as_str()) {
+                bail!("{} conflicts with {}", describe(i), describe(j));
+            }
+        }
+
+        for r in li.requires.iter() {
+            let Some(&j) = names.get(r.as_str()) else {
+                bail!(
+                    "{} requires layer {r:?}, which was not provided",
+                    describe(i),
+                );
+            };
+            deps[i].push(j);
+        }
+    }
+
+    /*
+     * Repeatedly select the earliest layer whose requirements have all been
+     * placed already.  This preserves the original order wherever the
+     * declarations do not demand otherwise.
+     */
+    let mut order = Vec::with_capacity(layers.len());
+    let mut placed = vec![false; layers.len()];
+    while order.len() < layers.len() {
+        let next = (0..layers.len())
+            .find(|&i| !placed[i] && deps[i].iter().all(|&j| placed[j]));
+
+        let Some(next) = next else {
+            let cycle = (0..layers.len())
+                .filter(|&i| !placed[i])
+                .map(describe)
+                .collect::<Vec<_>>();
+            bail!("requirements cycle among {}", cycle.join(", "));
+        };
+
+        placed[next] = true;
+        order.push(next);
+    }
+
+    Ok(order)
 }
 
 pub struct MetadataBuilder {
     archive_type: ArchiveType,
     info: HashMap<String, String>,
+    manifest: Option<BTreeMap<String, ManifestEntry>>,
+    layer: Option<LayerInfo>,
+    provenance: Option<Provenance>,
 }
 
 impl MetadataBuilder {
@@ -94,9 +526,66 @@ impl MetadataBuilder {
         MetadataBuilder {
             archive_type,
             info: Default::default(),
+            manifest: None,
+            layer: None,
+            provenance: None,
         }
     }
 
+    pub fn provenance(
+        &mut self,
+        provenance: Provenance,
+    ) -> Result<&mut MetadataBuilder> {
+        if matches!(self.archive_type, ArchiveType::Layer) {
+            bail!("layer archives may not include provenance information");
+        }
+        self.provenance = Some(provenance);
+        Ok(self)
+    }
+
+    /**
+     * Set the name, and optionally the version, of a layer archive.  A name
+     * must be set before requirements or conflicts can be declared.
+     */
+    pub fn layer_name(
+        &mut self,
+        name: &str,
+        version: Option<&str>,
+    ) -> Result<&mut MetadataBuilder> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            bail!("invalid layer name {name:?}");
+        }
+        let li = self.layer.get_or_insert_with(Default::default);
+        li.name = name.to_string();
+        li.version = version.map(str::to_string);
+        Ok(self)
+    }
+
+    fn layer_info_mut(&mut self) -> Result<&mut LayerInfo> {
+        match self.layer.as_mut() {
+            Some(li) => Ok(li),
+            None => bail!("a layer name must be set first"),
+        }
+    }
+
+    pub fn requires(&mut self, name: &str) -> Result<&mut MetadataBuilder> {
+        let li = self.layer_info_mut()?;
+        if name == li.name {
+            bail!("layer {name:?} cannot require itself");
+        }
+        li.requires.push(name.to_string());
+        Ok(self)
+    }
+
+    pub fn conflicts(&mut self, name: &str) -> Result<&mut MetadataBuilder> {
+        let li = self.layer_info_mut()?;
+        if name == li.name {
+            bail!("layer {name:?} cannot conflict with itself");
+        }
+        li.conflicts.push(name.to_string());
+        Ok(self)
+    }
+
     pub fn info(
         &mut self,
         name: &str,
@@ -109,11 +598,195 @@ impl MetadataBuilder {
         Ok(self)
     }
 
+    /**
+     * Record a manifest entry for a path relative to the "root/" prefix in the
+     * archive.  Adding any entry produces version 2 metadata, and the archive
+     * must then contain exactly the set of entries listed in the manifest.
+     */
+    pub fn entry(
+        &mut self,
+        path: &str,
+        entry: ManifestEntry,
+    ) -> Result<&mut MetadataBuilder> {
+        if path.starts_with('/') {
+            bail!("manifest path {path:?} must not be absolute");
+        }
+        let manifest = self.manifest.get_or_insert_with(Default::default);
+        if manifest.insert(path.to_string(), entry).is_some() {
+            bail!("duplicate manifest entry for {path:?}");
+        }
+        Ok(self)
+    }
+
+    /**
+     * Request an empty manifest, producing version 2 metadata even if no
+     * entries are added; e.g., for an archive with no files.
+     */
+    pub fn with_manifest(&mut self) -> &mut MetadataBuilder {
+        self.manifest.get_or_insert_with(Default::default);
+        self
+    }
+
     pub fn build(&mut self) -> Result<Metadata> {
+        if self.layer.is_some()
+            && !matches!(self.archive_type, ArchiveType::Layer)
+        {
+            bail!("only layer archives may include layer information");
+        }
+
         Ok(Metadata {
-            v: "1".into(),
+            v: if self.manifest.is_some() { "2" } else { "1" }.into(),
             t: self.archive_type,
             i: self.info.clone(),
+            m: self.manifest.clone(),
+            l: self.layer.clone(),
+            p: self.provenance.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn whiteout_markers() {
+        for (marker, w) in [
+            (".wh.motd", Whiteout::Path("motd".into())),
+            ("etc/.wh.motd", Whiteout::Path("etc/motd".into())),
+            ("etc/.wh..wh..opq", Whiteout::Opaque("etc".into())),
+            (".wh..wh..opq", Whiteout::Opaque("".into())),
+        ] {
+            assert_eq!(Whiteout::from_marker(marker).unwrap(), Some(w.clone()));
+            assert_eq!(w.marker(), marker);
+        }
+
+        assert_eq!(Whiteout::from_marker("etc/motd").unwrap(), None);
+        assert!(Whiteout::from_marker("etc/.wh.").is_err());
+        assert!(Whiteout::from_marker("etc/.wh...").is_err());
+        assert!(Whiteout::from_marker("etc/.wh..wh.plnk").is_err());
+    }
+
+    #[test]
+    fn parse_v1() {
+        let m = parse("{\"v\":\"1\",\"t\":\"layer\"}\n").expect("parse");
+
+        assert!(m.is_layer());
+        assert!(m.manifest().is_none());
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let mut h = tar::Header::new_ustar();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_mode(0o644);
+        h.set_uid(0);
+        h.set_gid(3);
+        h.set_size(0);
+
+        let digest = sha256_digest(std::io::empty()).unwrap();
+        assert_eq!(
+            digest,
+            "e3b0c44298fc1c149afbf4c8996fb924\
+            27ae41e4649b934ca495991b7852b855"
+        );
+
+        let m = MetadataBuilder::new(ArchiveType::Baseline)
+            .entry(
+                "etc/motd",
+                ManifestEntry::from_header(&h, Some(digest)).unwrap(),
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let s = serde_json::to_string(&m).unwrap();
+        let m = parse(&s).expect("parse");
+        let e = &m.manifest().unwrap()["etc/motd"];
+        assert_eq!(e.kind, EntryKind::File);
+        assert_eq!(e.mode, 0o644);
+        assert_eq!(e.gid, 3);
+    }
+
+    fn layer(name: &str, requires: &[&str], conflicts: &[&str]) -> Metadata {
+        let mut mb = MetadataBuilder::new(ArchiveType::Layer);
+        mb.layer_name(name, Some("1.0")).unwrap();
+        requires.iter().for_each(|r| {
+            mb.requires(r).unwrap();
+        });
+        conflicts.iter().for_each(|c| {
+            mb.conflicts(c).unwrap();
+        });
+        mb.build().unwrap()
+    }
+
+    #[test]
+    fn order_layers() {
+        let app = layer("app", &["runtime", "base"], &[]);
+        let runtime = layer("runtime", &["base"], &[]);
+        let base = layer("base", &[], &["legacy"]);
+        let anon = MetadataBuilder::new(ArchiveType::Layer).build().unwrap();
+
+        let order = layer_order(&[
+            ("app.tar.gz", &app),
+            ("anon.tar.gz", &anon),
+            ("runtime.tar.gz", &runtime),
+            ("base.tar.gz", &base),
+        ])
+        .unwrap();
+        assert_eq!(order, vec![1, 3, 2, 0]);
+
+        let e = layer_order(&[("app.tar.gz", &app), ("base.tar.gz", &base)])
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("app@1.0 (app.tar.gz)"), "{e}");
+        assert!(e.contains("\"runtime\""), "{e}");
+
+        let legacy = layer("legacy", &[], &[]);
+        let e = layer_order(&[("b", &base), ("l", &legacy)])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "layer base@1.0 (b) conflicts with layer legacy@1.0 (l)");
+
+        let x = layer("x", &["y"], &[]);
+        let y = layer("y", &["x"], &[]);
+        let e = layer_order(&[("x", &x), ("y", &y)])
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("requirements cycle"), "{e}");
+    }
+
+    #[test]
+    fn reproducible_record() {
+        let record = |info: &[(&str, &str)]| {
+            let mut mb = MetadataBuilder::new(ArchiveType::Layer);
+            for (n, v) in info {
+                mb.info(n, v).unwrap();
+            }
+            let mut a = tar::Builder::new(Vec::new());
+            mb.build()
+                .unwrap()
+                .append_to_tar_with_mtime(&mut a, 1700000000)
+                .unwrap();
+            a.into_inner().unwrap()
+        };
+
+        let info = [
+            ("name", "a"),
+            ("version", "1"),
+            ("branch", "main"),
+            ("commit", "0123abc"),
+            ("builder", "ci"),
+            ("zzz", "z"),
+        ];
+        let a = record(&info);
+        let b = record(&info.iter().rev().copied().collect::<Vec<_>>());
+        assert!(a == b, "metadata records differ");
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        assert!(parse("{\"v\":\"3\",\"t\":\"layer\"}").is_err());
+        assert!(parse("{\"v\":\"2\",\"t\":\"layer\"}").is_err());
+    }
+}
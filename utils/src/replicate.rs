@@ -0,0 +1,289 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * When a zone root is populated from the global zone, each file in the
+ * replicated trees is either linked to the read-only loopback mount of the
+ * same tree, copied into the zone, or skipped altogether.  The choice is made
+ * by an ordered list of rules, each of which maps a glob pattern to one of
+ * those actions; the first rule that matches a path applies.
+ *
+ * Rules are written one per line as an action followed by a pattern; e.g.,
+ *
+ *      link    *.so.1
+ *      copy    /usr/bin/pfexec
+ *
+ * A pattern that contains a slash must be absolute, and is matched against the
+ * full path of the file in the global zone.  A pattern without a slash is
+ * matched against the file name alone.  In a pattern, "*" matches any sequence
+ * of characters other than a slash, "?" matches any one character other than a
+ * slash, and "**" matches any sequence of characters including slashes.  When
+ * followed by a slash, "**" matches zero or more whole directories.
+ *
+ * The link and copy actions apply to regular files; directories and symbolic
+ * links are always recreated unless a skip rule matches them.  Skipping a
+ * directory skips everything beneath it.
+ */
+
+use anyhow::{anyhow, bail, Result};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::defaults::{DefaultsFile, BRAND_DEFAULTS};
+
+/**
+ * A file of rules that take precedence over the built-in rules.
+ */
+pub const REPLICATE_POLICY: &str = "/etc/default/helios-omicron1.replicate";
+
+/**
+ * The built-in rules link shared objects and the contents of the directories
+ * that hold programs and their documentation, and copy everything else.
+ */
+const DEFAULT_RULES: &str = "\
+    link    *.so
+    link    *.so.*
+    link    /usr/bin/**
+    link    /usr/libexec/**
+    link    /usr/share/man/**
+    link    /usr/share/locale/**
+    link    /**/sbin/**
+    copy    /**
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Link,
+    Copy,
+    Skip,
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "link" => Action::Link,
+            "copy" => Action::Copy,
+            "skip" => Action::Skip,
+            other => bail!("unknown action {other:?}"),
+        })
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Action::Link => "link",
+            Action::Copy => "copy",
+            Action::Skip => "skip",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub action: Action,
+    pub pattern: String,
+    /**
+     * Where the rule was defined, for reporting; e.g., "built-in rules line 2".
+     */
+    pub origin: String,
+}
+
+impl Rule {
+    pub fn matches(&self, path: &Path) -> bool {
+        let target = if self.pattern.contains('/') {
+            path.as_os_str()
+        } else {
+            match path.file_name() {
+                Some(name) => name,
+                None => return false,
+            }
+        };
+
+        glob(self.pattern.as_bytes(), target.as_encoded_bytes())
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} ({})", self.action, self.pattern, self.origin)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /**
+     * Parse a list of rules, one per line.  Blank lines, and anything after a
+     * "#", are ignored.  Each rule is labelled with the origin and its line
+     * number.
+     */
+    pub fn parse(origin: &str, input: &str) -> Result<Rules> {
+        Rules::parse_lines(origin, "line", input.lines())
+    }
+
+    fn parse_lines<'a>(
+        origin: &str,
+        unit: &str,
+        lines: impl Iterator<Item = &'a str>,
+    ) -> Result<Rules> {
+        let mut rules = Vec::new();
+
+        for (i, l) in lines.enumerate() {
+            let l = l.split('#').next().unwrap().trim();
+            if l.is_empty() {
+                continue;
+            }
+
+            let origin = format!("{origin} {unit} {}", i + 1);
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            let [action, pattern] = fields.as_slice() else {
+                bail!("{origin}: expected an action and a pattern: {l:?}");
+            };
+            let action =
+                action.parse().map_err(|e| anyhow!("{origin}: {e}"))?;
+            if pattern.contains('/') && !pattern.starts_with('/') {
+                bail!("{origin}: pattern {pattern:?} must be absolute");
+            }
+
+            rules.push(Rule {
+                action,
+                pattern: pattern.to_string(),
+                origin,
+            });
+        }
+
+        Ok(Rules { rules })
+    }
+
+    /**
+     * The built-in rules.
+     */
+    pub fn builtin() -> Rules {
+        Rules::parse("built-in rules", DEFAULT_RULES).unwrap()
+    }
+
+    /**
+     * Assemble the rules in effect: any rules set with REPLICATE_RULES in the
+     * brand defaults file, where rules are separated by semicolons, then any
+     * rules in the policy file, then the built-in rules.
+     */
+    pub fn from_defaults() -> Result<Rules> {
+        let df = DefaultsFile::from_path(BRAND_DEFAULTS)?;
+
+        let mut rules = Rules::default();
+        if let Some(inline) = df.get_str("REPLICATE_RULES") {
+            let origin = format!("REPLICATE_RULES in {BRAND_DEFAULTS}");
+            let inline =
+                Rules::parse_lines(&origin, "rule", inline.split(';'))?;
+            rules.rules.extend(inline.rules);
+        }
+
+        match std::fs::read_to_string(REPLICATE_POLICY) {
+            Ok(s) => {
+                rules
+                    .rules
+                    .extend(Rules::parse(REPLICATE_POLICY, &s)?.rules);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => bail!("reading {REPLICATE_POLICY}: {e}"),
+        }
+
+        rules.rules.extend(Rules::builtin().rules);
+        Ok(rules)
+    }
+
+    /**
+     * Find the first rule that matches a path.
+     */
+    pub fn find(&self, path: &Path) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.matches(path))
+    }
+
+    /**
+     * Determine what to do with a path.  Files that no rule matches are
+     * copied.
+     */
+    pub fn action(&self, path: &Path) -> Action {
+        self.find(path).map(|r| r.action).unwrap_or(Action::Copy)
+    }
+}
+
+fn glob(pat: &[u8], s: &[u8]) -> bool {
+    match pat {
+        [] => s.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            glob(rest, s)
+                || s.iter()
+                    .enumerate()
+                    .any(|(i, &c)| c == b'/' && glob(rest, &s[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=s.len()).any(|i| glob(rest, &s[i..])),
+        [b'*', rest @ ..] => (0..=s.len())
+            .take_while(|&i| i == 0 || s[i - 1] != b'/')
+            .any(|i| glob(rest, &s[i..])),
+        [b'?', rest @ ..] => {
+            matches!(s.first(), Some(c) if *c != b'/') && glob(rest, &s[1..])
+        }
+        [c, rest @ ..] => s.first() == Some(c) && glob(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builtin_rules() {
+        let rules = Rules::builtin();
+        let action = |p: &str| rules.action(Path::new(p));
+
+        assert_eq!(action("/usr/lib/libc.so.1"), Action::Link);
+        assert_eq!(action("/lib/amd64/ld.so"), Action::Link);
+        assert_eq!(action("/usr/bin/amd64/ls"), Action::Link);
+        assert_eq!(action("/sbin/init"), Action::Link);
+        assert_eq!(action("/usr/lib/inet/sbin/thing"), Action::Link);
+        assert_eq!(action("/usr/share/man/man1/ls.1"), Action::Link);
+
+        /*
+         * The substring checks these rules replaced would have linked these:
+         */
+        assert_eq!(action("/usr/lib/python3/site.sources"), Action::Copy);
+        assert_eq!(action("/usr/lib/sbinfo"), Action::Copy);
+        assert_eq!(action("/usr/lib/libfoo.solo"), Action::Copy);
+    }
+
+    #[test]
+    fn parse_and_order() {
+        let mut rules = Rules::parse(
+            "test",
+            "# comment\n\ncopy /usr/bin/pfexec\nskip /usr/share/doc/**\n",
+        )
+        .unwrap();
+        rules.rules.extend(Rules::builtin().rules);
+
+        let r = rules.find(Path::new("/usr/bin/pfexec")).unwrap();
+        assert_eq!(r.action, Action::Copy);
+        assert_eq!(r.origin, "test line 3");
+        assert_eq!(rules.action(Path::new("/usr/bin/ls")), Action::Link);
+        assert_eq!(
+            rules.action(Path::new("/usr/share/doc/a/b.txt")),
+            Action::Skip,
+        );
+
+        assert!(Rules::parse("test", "link usr/lib/*").is_err());
+        assert!(Rules::parse("test", "move /usr/lib/*").is_err());
+        assert!(Rules::parse("test", "link").is_err());
+
+        assert!(glob(b"/usr/*/x", b"/usr/lib/x"));
+        assert!(!glob(b"/usr/*/x", b"/usr/lib/amd64/x"));
+        assert!(glob(b"/usr/**/x", b"/usr/x"));
+        assert!(glob(b"lib?.so", b"libc.so"));
+    }
+}
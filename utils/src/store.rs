@@ -0,0 +1,415 @@
+/*
+ * Copyright 2025 Oxide Computer Company
+ */
+
+/*
+ * A store of files copied from the global zone, shared between zones.  Each
+ * file that replication would copy into a zone root is instead copied once
+ * into the store, and then hard linked into each zone root that needs it.
+ * The store must be on the same file system as the zone roots.
+ *
+ * Files in the store are named for the identity of the file in the global
+ * zone: its device, inode, size, ownership, permissions, and modification
+ * and change times.  When a file in the global zone changes, it gets a new
+ * name in the store, and the old copy remains in use by any zones that were
+ * installed before the change.  Resynchronising a zone copies and links files
+ * by way of the store in the same way.
+ *
+ * Every zone that links a file from the store shares the one copy, whatever
+ * its permissions.  A file that is writable within a zone, as most copied
+ * files are by their owner, and that is modified in place there, is thus
+ * modified in every zone that uses it, and in the store.  Only replacing the
+ * file, rather than writing to it, keeps the change within the one zone.
+ *
+ * The store records the files that each zone uses.  Files that no zone uses,
+ * and that are not linked anywhere outside the store, are removed when a zone
+ * is uninstalled or when the store is collected explicitly.
+ *
+ * A zone being installed or resynchronised finds files in the store well
+ * before it links them into its root and records that it uses them.
+ * Replication therefore holds a shared lock on the store while it is open,
+ * and collection an exclusive lock, so that files are not removed from under
+ * an install.
+ */
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::defaults::{DefaultsFile, BRAND_DEFAULTS};
+
+/**
+ * The list of files used by a zone.
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Refs {
+    zonepath: PathBuf,
+    objects: BTreeSet<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct GcStats {
+    pub removed: u64,
+    pub bytes: u64,
+    pub kept: u64,
+    pub stale_zones: u64,
+}
+
+impl std::fmt::Display for GcStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files removed ({} bytes), {} kept, {} stale zone records",
+            self.removed, self.bytes, self.kept, self.stale_zones,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lock {
+    /**
+     * For replicating into a zone root; any number of zones may be installed
+     * from the store at once.
+     */
+    Shared,
+    /**
+     * For collecting the store, which waits for every install to finish.
+     */
+    Exclusive,
+}
+
+pub struct Store {
+    dir: PathBuf,
+    /**
+     * The files that replication has used since the store was opened.
+     */
+    used: BTreeSet<String>,
+    /**
+     * The lock file, which holds our lock on the store until it is closed.
+     */
+    _lockf: File,
+    lock: Lock,
+}
+
+impl Store {
+    /**
+     * Open a store, creating it if it does not yet exist, and wait for the
+     * requested lock on it.  The lock is held until the store is dropped.
+     */
+    pub fn open<P: AsRef<Path>>(dir: P, lock: Lock) -> Result<Store> {
+        let dir = dir.as_ref().to_path_buf();
+        if !dir.is_absolute() {
+            bail!("store {dir:?} must be absolute");
+        }
+
+        for sub in ["objects", "refs", "tmp"] {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o755)
+                .create(dir.join(sub))
+                .map_err(|e| anyhow!("creating store {dir:?}: {e}"))?;
+        }
+
+        let path = dir.join("lock");
+        let lockf = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(&path)
+            .map_err(|e| anyhow!("opening {path:?}: {e}"))?;
+        lock_file(&lockf, lock)
+            .map_err(|e| anyhow!("locking store {dir:?}: {e}"))?;
+
+        Ok(Store {
+            dir,
+            used: Default::default(),
+            _lockf: lockf,
+            lock,
+        })
+    }
+
+    /**
+     * Open the store named by REPLICATE_STORE in the brand defaults file, if
+     * one is configured.
+     */
+    pub fn from_defaults(lock: Lock) -> Result<Option<Store>> {
+        let df = DefaultsFile::from_path(BRAND_DEFAULTS)?;
+        match df.get_str("REPLICATE_STORE") {
+            Some(dir) if !dir.is_empty() => Ok(Some(Store::open(dir, lock)?)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /**
+     * The device on which the store resides; files can only be linked into
+     * zone roots on the same device.
+     */
+    pub fn dev(&self) -> Result<u64> {
+        Ok(std::fs::metadata(&self.dir)?.dev())
+    }
+
+    /**
+     * Determine the name in the store of a file in the global zone.
+     */
+    pub fn key(md: &std::fs::Metadata) -> String {
+        let id = format!(
+            "{}:{}:{}:{}:{}:{:o}:{}.{}:{}.{}",
+            md.dev(),
+            md.ino(),
+            md.size(),
+            md.uid(),
+            md.gid(),
+            md.mode(),
+            md.mtime(),
+            md.mtime_nsec(),
+            md.ctime(),
+            md.ctime_nsec(),
+        );
+        Sha256::digest(id.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /**
+     * The path of a file in the store, which may not exist yet.  Files are
+     * spread over subdirectories by the first two characters of their name.
+     */
+    pub fn object(&self, key: &str) -> PathBuf {
+        self.dir.join("objects").join(&key[..2]).join(&key[2..])
+    }
+
+    /**
+     * Look up a file in the store, noting that it is in use.  Returns the
+     * path at which a new copy should be written if it is not yet present.
+     */
+    pub fn lookup(&mut self, key: &str) -> Result<Option<PathBuf>> {
+        self.used.insert(key.to_string());
+        match std::fs::symlink_metadata(self.object(key)) {
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Some(
+                self.dir
+                    .join("tmp")
+                    .join(format!("{key}.{}", std::process::id())),
+            )),
+            Err(e) => bail!("store object {key}: {e}"),
+        }
+    }
+
+    /**
+     * Note that a zone still uses a file that was linked into its root
+     * earlier, if that file is the one in the store under "key"; i.e., if it
+     * has the inode "ino".
+     */
+    pub fn retain(&mut self, key: &str, ino: u64) -> Result<()> {
+        match std::fs::symlink_metadata(self.object(key)) {
+            Ok(md) if md.ino() == ino => {
+                self.used.insert(key.to_string());
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => bail!("store object {key}: {e}"),
+        }
+        Ok(())
+    }
+
+    /**
+     * Move a new copy, written at the path returned by lookup(), into place.
+     * If another copy of the same file was put in place in the meantime, the
+     * new copy is discarded.  Returns the path of the file in the store.
+     */
+    pub fn commit(&self, key: &str, tmp: &Path) -> Result<PathBuf> {
+        let obj = self.object(key);
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(obj.parent().unwrap())?;
+
+        match std::fs::hard_link(tmp, &obj) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => bail!("storing {obj:?}: {e}"),
+        }
+        std::fs::remove_file(tmp)?;
+        Ok(obj)
+    }
+
+    fn refs(&self, zone: &str) -> Result<PathBuf> {
+        if zone.is_empty() || zone.contains('/') || zone.starts_with('.') {
+            bail!("invalid zone name {zone:?}");
+        }
+        Ok(self.dir.join("refs").join(zone))
+    }
+
+    /**
+     * Record that a zone uses the files that replication has used since the
+     * store was opened.
+     */
+    pub fn save_refs(&self, zone: &str, zonepath: &Path) -> Result<()> {
+        let path = self.refs(zone)?;
+        let refs = Refs {
+            zonepath: zonepath.to_path_buf(),
+            objects: self.used.clone(),
+        };
+
+        let tmp =
+            path.with_file_name(format!(".{zone}.{}", std::process::id()));
+        std::fs::write(&tmp, serde_json::to_vec(&refs)?)?;
+        std::fs::rename(&tmp, &path)
+            .map_err(|e| anyhow!("writing {path:?}: {e}"))?;
+        Ok(())
+    }
+
+    /**
+     * Forget the files used by a zone.  It is not an error if there is no
+     * record for the zone.
+     */
+    pub fn remove_refs(&self, zone: &str) -> Result<()> {
+        let path = self.refs(zone)?;
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => bail!("removing {path:?}: {e}"),
+        }
+    }
+
+    /**
+     * Remove files that no zone uses.  The record of a zone whose root no
+     * longer exists is discarded first.  A file that is still linked from
+     * outside the store is kept, whatever the records say, as it may belong
+     * to a zone that was installed without a record.  The store must be open
+     * with an exclusive lock, so that no install is under way.
+     */
+    pub fn gc(&self) -> Result<GcStats> {
+        if self.lock != Lock::Exclusive {
+            bail!("store {:?} must be locked exclusively to collect", self.dir);
+        }
+
+        let mut stats = GcStats::default();
+
+        let mut used = BTreeSet::new();
+        for ent in std::fs::read_dir(self.dir.join("refs"))? {
+            let ent = ent?;
+            if ent.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let refs: Refs =
+                serde_json::from_slice(&std::fs::read(ent.path())?)
+                    .map_err(|e| anyhow!("parsing {:?}: {e}", ent.path()))?;
+
+            let live = ["root", "root.staging"]
+                .iter()
+                .any(|r| refs.zonepath.join(r).symlink_metadata().is_ok());
+            if !live {
+                std::fs::remove_file(ent.path())?;
+                stats.stale_zones += 1;
+                continue;
+            }
+            used.extend(refs.objects);
+        }
+
+        for dir in std::fs::read_dir(self.dir.join("objects"))? {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().to_string();
+            for obj in std::fs::read_dir(dir.path())? {
+                let obj = obj?;
+                let key =
+                    format!("{prefix}{}", obj.file_name().to_string_lossy());
+                let md = obj.metadata()?;
+
+                if used.contains(&key) || md.nlink() > 1 {
+                    stats.kept += 1;
+                    continue;
+                }
+
+                std::fs::remove_file(obj.path())?;
+                stats.removed += 1;
+                stats.bytes += md.len();
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+/**
+ * Wait for a lock on the whole of an open file.
+ */
+fn lock_file(f: &File, lock: Lock) -> std::io::Result<()> {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = match lock {
+        Lock::Shared => libc::F_RDLCK,
+        Lock::Exclusive => libc::F_WRLCK,
+    } as _;
+    fl.l_whence = libc::SEEK_SET as _;
+
+    loop {
+        if unsafe { libc::fcntl(f.as_raw_fd(), libc::F_SETLKW, &fl) } == 0 {
+            return Ok(());
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn store_gc() {
+        let dir = tempfile::tempdir().unwrap();
+        let zonepath = dir.path().join("zone");
+        std::fs::create_dir_all(zonepath.join("root")).unwrap();
+        let src = dir.path().join("src");
+        std::fs::write(&src, "data").unwrap();
+        let key = Store::key(&std::fs::metadata(&src).unwrap());
+
+        let sdir = dir.path().join("store");
+        let mut store = Store::open(&sdir, Lock::Shared).unwrap();
+        let tmp = store.lookup(&key).unwrap().unwrap();
+        std::fs::copy(&src, &tmp).unwrap();
+        let obj = store.commit(&key, &tmp).unwrap();
+        assert_eq!(store.lookup(&key).unwrap(), None);
+        store.save_refs("z", &zonepath).unwrap();
+        assert!(store.gc().is_err());
+        drop(store);
+        let store = Store::open(&sdir, Lock::Exclusive).unwrap();
+
+        /*
+         * The object is kept while the zone refers to it, and then while the
+         * zone root still links to it, even once the zone is forgotten.
+         */
+        let zf = zonepath.join("root/file");
+        std::fs::hard_link(&obj, &zf).unwrap();
+        assert_eq!(store.gc().unwrap().kept, 1);
+        store.remove_refs("z").unwrap();
+        assert_eq!(store.gc().unwrap().kept, 1);
+        std::fs::remove_file(&zf).unwrap();
+        let stats = store.gc().unwrap();
+        assert_eq!((stats.removed, stats.bytes), (1, 4));
+        assert!(!obj.exists());
+
+        /*
+         * The record of a zone whose root is gone is discarded.
+         */
+        store.save_refs("z", &zonepath).unwrap();
+        std::fs::remove_dir(zonepath.join("root")).unwrap();
+        assert_eq!(store.gc().unwrap().stale_zones, 1);
+        assert!(store.refs("../z").is_err());
+    }
+}
@@ -3,10 +3,14 @@
  */
 
 use anyhow::{anyhow, bail, Context, Result};
+use std::collections::{hash_map, BTreeSet, HashMap};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
-use crate::copyq::{CopyQueue, CopyStats};
-use crate::defaults::DefaultsFile;
+use crate::copyq::{Attrs, CopyQueue, CopyStats};
+use crate::defaults::{DefaultsFile, BRAND_DEFAULTS};
+use crate::replicate::{Action, Rule, Rules};
+use crate::store::Store;
 
 pub fn unprefix(prefix: &Path, path: &Path) -> Result<PathBuf> {
     if prefix.is_absolute() != path.is_absolute() {
@@ -33,81 +37,139 @@ pub fn reprefix(prefix: &Path, path: &Path, target: &Path) -> Result<PathBuf> {
 }
 
 /**
- * Replicate "src" (e.g., "/usr") as a tree of symlinks rooted at "target"
- * (e.g., "/zone/root/usr") where each link will point at the lofs file system
- * pointed at "prefix" (e.g., "/system/usr").
+ * What replication makes of an object in the source tree.
  */
-pub fn replicate<S: AsRef<Path>, T: AsRef<Path>>(
-    src: S,
-    target: T,
-    prefix: &str,
-) -> Result<CopyStats> {
-    let src = src.as_ref();
-    let target = target.as_ref();
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Replica {
+    Directory,
+    /**
+     * A symbolic link, recreated with the same target as in the source tree.
+     */
+    Symlink,
+    /**
+     * A regular file, replaced by a symbolic link with this target, which
+     * leads to the same file in the prefix tree.
+     */
+    Link(String),
+    /**
+     * A regular file, copied.
+     */
+    Copy,
+}
 
-    if !src.is_absolute() || !src.exists() {
-        bail!("src {:?} must exist and be absolute", src);
+/**
+ * Objects in the source tree to leave out of a replicated tree, whatever the
+ * rules say, by their full path in the source tree.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Exclude {
+    subtrees: BTreeSet<PathBuf>,
+    objects: BTreeSet<PathBuf>,
+}
+
+impl Exclude {
+    /**
+     * Leave out a directory and everything beneath it.
+     */
+    pub fn subtree<P: AsRef<Path>>(&mut self, path: P) {
+        self.subtrees.insert(path.as_ref().to_path_buf());
     }
-    if !target.is_absolute() || !target.exists() {
-        bail!("target {:?} must exist and be absolute", target);
+
+    /**
+     * Leave out a file or link.  A directory is only left out if everything
+     * beneath it is excluded as well, as it would otherwise be left empty;
+     * if not, it is replicated as usual.
+     */
+    pub fn object<P: AsRef<Path>>(&mut self, path: P) {
+        self.objects.insert(path.as_ref().to_path_buf());
+    }
+
+    /**
+     * If the object at "path" is excluded, returns the number of objects
+     * left out as a result, including any beneath a directory.
+     */
+    fn excludes(&self, path: &Path, dir: bool) -> Result<Option<u64>> {
+        if self.subtrees.contains(path) {
+            let mut n = 0;
+            for ent in walkdir::WalkDir::new(path).same_file_system(true) {
+                ent?;
+                n += 1;
+            }
+            return Ok(Some(n));
+        }
+
+        if !self.objects.contains(path) {
+            return Ok(None);
+        }
+        if !dir {
+            return Ok(Some(1));
+        }
+
+        let mut n = 1;
+        for ent in std::fs::read_dir(path)? {
+            let ent = ent?;
+            let dir = ent.file_type()?.is_dir();
+            match self.excludes(&ent.path(), dir)? {
+                Some(m) => n += m,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(n))
     }
+}
+
+/**
+ * Walk "src", calling "visit" for each object with what replication should
+ * make of it according to the rules, starting with "src" itself.  Objects
+ * that the rules skip are not visited, nor is anything beneath a directory
+ * that is skipped or for which the visitor returns false.  Excluded objects
+ * are not visited either; returns the number of objects left out that way.
+ */
+pub fn replicas<F>(
+    src: &Path,
+    prefix: &str,
+    rules: &Rules,
+    exclude: &Exclude,
+    mut visit: F,
+) -> Result<u64>
+where
+    F: FnMut(&walkdir::DirEntry, Replica) -> Result<bool>,
+{
     if !prefix.starts_with('/') {
         bail!("prefix must be absolute");
     }
 
-    let df = DefaultsFile::from_path("/etc/default/helios-omicron1")?;
-
-    let mut cq = CopyQueue::new(
-        df.get_usize("COPY_THREADS").unwrap_or(8),
-        df.get_usize("COPY_BATCH").unwrap_or(128),
-    )?;
-
+    let mut pruned = 0;
     let walk = walkdir::WalkDir::new(src).same_file_system(true);
     let mut walk = walk.into_iter();
 
     while let Some(ent) = walk.next().transpose()? {
         let md = ent.metadata()?;
 
-        if md.file_type().is_symlink() {
-            /*
-             * We recreate relative symbolic links in the target tree with the
-             * same contents as in the source tree.  Both relative and absolute
-             * links will continue to point to the correct place when examined
-             * in the context of the zone, provided all of the replicated trees
-             * are laid out in the usual locations.
-             */
-
-            let target = reprefix(src, ent.path(), target)?;
-            cq.push_relative_link(ent.path().into(), target);
-        } else if md.file_type().is_dir() {
-            /*
-             * Just create directories with the same ownership and permissions
-             * as the original.
-             */
-            let target = reprefix(src, ent.path(), target)?;
-            if target.exists() && target.is_dir() {
+        if ent.depth() > 0 {
+            if let Some(n) = exclude.excludes(ent.path(), md.is_dir())? {
+                if md.is_dir() {
+                    walk.skip_current_dir();
+                }
+                pruned += n;
                 continue;
             }
-            std::fs::create_dir(&target)
-                .with_context(|| anyhow!("creating {:?}", &target))?;
-        } else if md.file_type().is_file() {
-            if ent.file_name().to_string_lossy().contains(".so")
-                || ent.path().to_string_lossy().contains("usr/bin")
-                || ent.path().to_string_lossy().contains("usr/libexec")
-                || ent.path().to_string_lossy().contains("usr/share/man")
-                || ent.path().to_string_lossy().contains("usr/share/locale")
-                || ent.path().to_string_lossy().contains("sbin")
-            {
-                /*
-                 * XXX Try the symlink thing with library or program files.
-                 */
+        }
 
-                /*
-                 * Create an absolute symbolic link to the analogous file in the
-                 * prefix tree.
-                 */
-                let target = reprefix(src, ent.path(), target)?;
+        let action = rules.action(ent.path());
+        if action == Action::Skip && ent.depth() > 0 {
+            if md.file_type().is_dir() {
+                walk.skip_current_dir();
+            }
+            continue;
+        }
 
+        let replica = if md.file_type().is_symlink() {
+            Replica::Symlink
+        } else if md.file_type().is_dir() {
+            Replica::Directory
+        } else if md.file_type().is_file() {
+            if action == Action::Link {
                 /*
                  * XXX This is rubbish:
                  */
@@ -123,12 +185,279 @@ pub fn replicate<S: AsRef<Path>, T: AsRef<Path>>(
                 linktarget
                     .push_str(unprefix(src, ent.path())?.to_str().unwrap());
 
-                cq.push_absolute_link(linktarget, target);
+                Replica::Link(linktarget)
             } else {
+                Replica::Copy
+            }
+        } else {
+            bail!("special file? {:?}", ent.path());
+        };
+
+        let dir = replica == Replica::Directory;
+        if !visit(&ent, replica)? && dir {
+            walk.skip_current_dir();
+        }
+    }
+
+    Ok(pruned)
+}
+
+/**
+ * How replication of a tree would treat a single path within it.
+ */
+#[derive(Debug)]
+pub enum Explanation<'a> {
+    /**
+     * The path is excluded, either itself or by way of the directory above it
+     * given here, if that directory is excluded.
+     */
+    Excluded(PathBuf),
+    /**
+     * A rule skips the directory above the path given here, and with it
+     * everything beneath.
+     */
+    Skipped(PathBuf, &'a Rule),
+    /**
+     * The path is reached, and the action is chosen by the first rule that
+     * matches it; if no rule matches, it is copied.
+     */
+    Action(Action, Option<&'a Rule>),
+}
+
+/**
+ * Determine what walking "src" with "replicas" would make of "path", by
+ * checking each directory between them, and then the path itself, against the
+ * exclusions and the rules in the same order as the walk does.  The path need
+ * not exist.
+ */
+pub fn explain<'a>(
+    src: &Path,
+    path: &Path,
+    rules: &'a Rules,
+    exclude: &Exclude,
+) -> Result<Explanation<'a>> {
+    if !path.starts_with(src) {
+        bail!("{path:?} is not within {src:?}");
+    }
+
+    let mut steps = path
+        .ancestors()
+        .take_while(|a| *a != src)
+        .collect::<Vec<_>>();
+    steps.reverse();
+
+    for a in steps {
+        let excluded = match std::fs::symlink_metadata(a) {
+            Ok(md) => exclude.excludes(a, md.is_dir())?.is_some(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                exclude.subtrees.contains(a) || exclude.objects.contains(a)
+            }
+            Err(e) => bail!("{a:?}: {e}"),
+        };
+        if excluded {
+            return Ok(Explanation::Excluded(a.to_path_buf()));
+        }
+
+        if a == path {
+            break;
+        }
+        if let Some(rule) = rules.find(a) {
+            if rule.action == Action::Skip {
+                return Ok(Explanation::Skipped(a.to_path_buf(), rule));
+            }
+        }
+    }
+
+    Ok(Explanation::Action(rules.action(path), rules.find(path)))
+}
+
+/**
+ * Files with more than one link in a source tree, by device and inode, so
+ * that each is copied once, at the first name at which it is replicated, and
+ * then hard linked at each of its other names.  The links can only be made
+ * once the copies are complete.
+ */
+#[derive(Debug, Default)]
+pub struct HardLinks {
+    firsts: HashMap<(u64, u64), PathBuf>,
+    links: Vec<(PathBuf, PathBuf, u64)>,
+}
+
+impl HardLinks {
+    /**
+     * Note that the source file described by "md" is to be replicated at
+     * "target".  Returns true if this is the first name for the file, which
+     * must be copied.  Otherwise, "target" will be linked to the first copy,
+     * and nothing else need be done with it.
+     */
+    pub fn first(&mut self, md: &std::fs::Metadata, target: &Path) -> bool {
+        if md.nlink() < 2 {
+            return true;
+        }
+
+        match self.firsts.entry((md.dev(), md.ino())) {
+            hash_map::Entry::Occupied(first) => {
+                self.links.push((
+                    first.get().clone(),
+                    target.to_path_buf(),
+                    md.len(),
+                ));
+                false
+            }
+            hash_map::Entry::Vacant(first) => {
+                first.insert(target.to_path_buf());
+                true
+            }
+        }
+    }
+
+    /**
+     * Note that the source file described by "md" has already been
+     * replicated at "target", so that any other names for it that are yet to
+     * be replicated can be linked there rather than copied.
+     */
+    pub fn existing(&mut self, md: &std::fs::Metadata, target: &Path) {
+        if md.nlink() > 1 {
+            self.firsts
+                .entry((md.dev(), md.ino()))
+                .or_insert_with(|| target.to_path_buf());
+        }
+    }
+
+    /**
+     * Arrange for "target" to be linked to some other existing file of "size"
+     * bytes once the copies are complete.
+     */
+    pub fn push(&mut self, existing: PathBuf, target: PathBuf, size: u64) {
+        self.links.push((existing, target, size));
+    }
+
+    /**
+     * Make each of the links, in the order they were arranged, by calling
+     * "link" with the existing file and the new name.  Each link is counted
+     * in the statistics, along with the bytes that were not copied.
+     */
+    pub fn link<F>(self, cs: &mut CopyStats, mut link: F) -> Result<()>
+    where
+        F: FnMut(&Path, &Path) -> Result<()>,
+    {
+        for (existing, target, size) in self.links {
+            link(&existing, &target)?;
+            cs.links += 1;
+            cs.saved += size;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Replicate "src" (e.g., "/usr") as a tree of symlinks rooted at "target"
+ * (e.g., "/zone/root/usr") where each link will point at the lofs file system
+ * pointed at "prefix" (e.g., "/system/usr").  The rules determine whether
+ * each file is linked, copied, or skipped.  A file with several hard links in
+ * "src" is copied once, and the copy is then hard linked at each of the other
+ * names.  Directories, copies, and links are given the ownership and
+ * modification time of the object they replicate, and directories and copies
+ * its permissions as well.  If a store is provided, files are copied into the
+ * store, if they are not there already, and linked into "target" from there.
+ * Excluded objects are never created; the number left out is reported in the
+ * statistics as pruned.
+ */
+pub fn replicate<S: AsRef<Path>, T: AsRef<Path>>(
+    src: S,
+    target: T,
+    prefix: &str,
+    rules: &Rules,
+    exclude: &Exclude,
+    mut store: Option<&mut Store>,
+) -> Result<CopyStats> {
+    let src = src.as_ref();
+    let target = target.as_ref();
+
+    if !src.is_absolute() || !src.exists() {
+        bail!("src {:?} must exist and be absolute", src);
+    }
+    if !target.is_absolute() || !target.exists() {
+        bail!("target {:?} must exist and be absolute", target);
+    }
+
+    let df = DefaultsFile::from_path(BRAND_DEFAULTS)?;
+
+    let mut cq = CopyQueue::new(
+        df.get_usize("COPY_THREADS").unwrap_or(8),
+        df.get_usize("COPY_BATCH").unwrap_or(128),
+    )?;
+
+    /*
+     * Files with more than one link are copied once and then linked, as are
+     * files that are already in the store.
+     */
+    let mut links = HardLinks::default();
+
+    /*
+     * New copies in the store, by name, and the path to which each must be
+     * linked once it is complete.
+     */
+    let mut stored: Vec<(String, PathBuf, PathBuf)> = Vec::new();
+
+    /*
+     * Creating an object in a directory changes its modification time, so
+     * directories are finished last.
+     */
+    let mut dirs: Vec<(PathBuf, Attrs)> = Vec::new();
+
+    let pruned = replicas(src, prefix, rules, exclude, |ent, replica| {
+        let target = reprefix(src, ent.path(), target)?;
+
+        match replica {
+            Replica::Symlink => {
+                /*
+                 * We recreate relative symbolic links in the target tree with
+                 * the same contents as in the source tree.  Both relative and
+                 * absolute links will continue to point to the correct place
+                 * when examined in the context of the zone, provided all of
+                 * the replicated trees are laid out in the usual locations.
+                 */
+                cq.push_relative_link(ent.path().into(), target);
+            }
+            Replica::Directory => {
+                /*
+                 * Just create directories with the same ownership and
+                 * permissions as the original.
+                 */
+                if !(target.exists() && target.is_dir()) {
+                    std::fs::create_dir(&target)
+                        .with_context(|| anyhow!("creating {:?}", &target))?;
+                }
+                dirs.push((target, Attrs::from(&ent.metadata()?)));
+            }
+            Replica::Link(linktarget) => {
                 /*
-                 * XXX Copy the analogous file to the prefix tree:
+                 * Create an absolute symbolic link to the analogous file in
+                 * the prefix tree.
                  */
-                let target = reprefix(src, ent.path(), target)?;
+                let attrs = Attrs::from(&ent.metadata()?);
+                cq.push_absolute_link(linktarget, target, Some(attrs));
+            }
+            Replica::Copy => {
+                let md = ent.metadata()?;
+                if !links.first(&md, &target) {
+                    return Ok(true);
+                }
+
+                if let Some(store) = store.as_deref_mut() {
+                    let key = Store::key(&md);
+                    match store.lookup(&key)? {
+                        Some(tmp) => {
+                            cq.push_copy(ent.path().into(), tmp.clone());
+                            stored.push((key, tmp, target));
+                        }
+                        None => {
+                            links.push(store.object(&key), target, md.len());
+                        }
+                    }
+                    return Ok(true);
+                }
 
                 /*
                  * Push the copy task onto the work queue and move on to the
@@ -136,10 +465,366 @@ pub fn replicate<S: AsRef<Path>, T: AsRef<Path>>(
                  */
                 cq.push_copy(ent.path().into(), target);
             }
-        } else {
-            bail!("special file? {:?}", ent.path());
+        }
+
+        Ok(true)
+    })?;
+
+    let mut cs = cq.join()?;
+    cs.pruned = pruned;
+
+    if let Some(store) = store.as_deref() {
+        for (key, tmp, target) in stored {
+            let obj = store.commit(&key, &tmp)?;
+            std::fs::hard_link(&obj, &target).with_context(|| {
+                anyhow!("linking {:?} to {:?}", &target, &obj)
+            })?;
         }
     }
 
-    Ok(cq.join()?)
+    links.link(&mut cs, |first, target| {
+        std::fs::remove_file(target).ok();
+        std::fs::hard_link(first, target)
+            .with_context(|| anyhow!("linking {:?} to {:?}", target, first))
+    })?;
+
+    for (target, attrs) in dirs.iter().rev() {
+        std::fs::File::open(target)
+            .and_then(|f| attrs.apply_to(&f))
+            .with_context(|| anyhow!("finishing {:?}", target))?;
+    }
+
+    Ok(cs)
+}
+
+/**
+ * An object in a replicated tree that does not match the object it
+ * replicates.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub problem: String,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.path, self.problem)
+    }
+}
+
+/**
+ * Check that each object that replicate() would create in "target" is
+ * present, is of the right type, and has the ownership, permissions, and
+ * modification time of the object it replicates.  Returns the objects that
+ * do not match, with a description of each problem.  Excluded objects are
+ * not checked.
+ */
+pub fn verify<S: AsRef<Path>, T: AsRef<Path>>(
+    src: S,
+    target: T,
+    prefix: &str,
+    rules: &Rules,
+    exclude: &Exclude,
+) -> Result<Vec<Mismatch>> {
+    let src = src.as_ref();
+    let target = target.as_ref();
+    let mut out = Vec::new();
+
+    replicas(src, prefix, rules, exclude, |ent, replica| {
+        let path = reprefix(src, ent.path(), target)?;
+        let mut problem = |problem: String| {
+            out.push(Mismatch {
+                path: path.clone(),
+                problem,
+            });
+        };
+
+        let md = match std::fs::symlink_metadata(&path) {
+            Ok(md) => md,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                problem("missing".into());
+                return Ok(replica != Replica::Directory);
+            }
+            Err(e) => bail!("{path:?}: {e}"),
+        };
+
+        let (want, ok) = match replica {
+            Replica::Directory => ("directory", md.is_dir()),
+            Replica::Symlink | Replica::Link(_) => {
+                ("symbolic link", md.file_type().is_symlink())
+            }
+            Replica::Copy => ("file", md.is_file()),
+        };
+        if !ok {
+            problem(format!("not a {want}"));
+            return Ok(false);
+        }
+
+        let expect = Attrs::from(&ent.metadata()?);
+        let found = Attrs::from(&md);
+        if (found.uid, found.gid) != (expect.uid, expect.gid) {
+            problem(format!(
+                "owner {}:{}, expected {}:{}",
+                found.uid, found.gid, expect.uid, expect.gid,
+            ));
+        }
+        if !md.file_type().is_symlink() && found.mode != expect.mode {
+            problem(format!(
+                "mode {:o}, expected {:o}",
+                found.mode, expect.mode,
+            ));
+        }
+        if (found.mtime, found.mtime_nsec) != (expect.mtime, expect.mtime_nsec)
+        {
+            problem(format!(
+                "modified at {}.{:09}, expected {}.{:09}",
+                found.mtime, found.mtime_nsec, expect.mtime, expect.mtime_nsec,
+            ));
+        }
+
+        Ok(true)
+    })?;
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::Lock;
+    use std::fs::File;
+
+    #[test]
+    fn replicate_hard_links() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "link *.so\ncopy /**\n").unwrap();
+
+        std::fs::create_dir(src.path().join("bin")).unwrap();
+        std::fs::write(src.path().join("bin/a"), "program").unwrap();
+        std::fs::hard_link(src.path().join("bin/a"), src.path().join("bin/b"))
+            .unwrap();
+        std::fs::hard_link(src.path().join("bin/a"), src.path().join("c"))
+            .unwrap();
+        std::fs::write(src.path().join("d"), "other").unwrap();
+
+        let cs = replicate(
+            src.path(),
+            dst.path(),
+            "/system/x",
+            &rules,
+            &Exclude::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(cs.files, 2);
+        assert_eq!(cs.links, 2);
+        assert_eq!(cs.saved, 14);
+
+        let ino =
+            |p: &str| std::fs::metadata(dst.path().join(p)).unwrap().ino();
+        assert_eq!(ino("bin/a"), ino("bin/b"));
+        assert_eq!(ino("bin/a"), ino("c"));
+        assert_ne!(ino("bin/a"), ino("d"));
+        assert_eq!(
+            std::fs::read_to_string(dst.path().join("c")).unwrap(),
+            "program",
+        );
+    }
+
+    #[test]
+    fn explain_paths() {
+        let src = tempfile::tempdir().unwrap();
+        let lib = src.path().join("lib");
+        let rules =
+            Rules::parse("test", "skip /**/cache\nlink *.so\ncopy /**\n")
+                .unwrap();
+        let mut exclude = Exclude::default();
+        exclude.subtree(lib.join("svc/manifest"));
+        exclude.object(lib.join("gz.so"));
+
+        std::fs::create_dir_all(lib.join("svc/manifest")).unwrap();
+        std::fs::create_dir_all(lib.join("cache")).unwrap();
+        std::fs::write(lib.join("gz.so"), "").unwrap();
+
+        let explain =
+            |p: &str| match explain(&lib, &lib.join(p), &rules, &exclude)
+                .unwrap()
+            {
+                Explanation::Excluded(a) => {
+                    format!("excluded at {:?}", unprefix(&lib, &a).unwrap())
+                }
+                Explanation::Skipped(a, rule) => format!(
+                    "skipped at {:?} by {}",
+                    unprefix(&lib, &a).unwrap(),
+                    rule.pattern,
+                ),
+                Explanation::Action(action, rule) => format!(
+                    "{action} by {}",
+                    rule.map(|r| r.pattern.as_str()).unwrap_or("none"),
+                ),
+            };
+
+        assert_eq!(
+            explain("svc/manifest/x.xml"),
+            "excluded at \"svc/manifest\""
+        );
+        assert_eq!(explain("svc/manifest"), "excluded at \"svc/manifest\"");
+        assert_eq!(explain("gz.so"), "excluded at \"gz.so\"");
+        assert_eq!(explain("cache/a.so"), "skipped at \"cache\" by /**/cache");
+        assert_eq!(explain("cache"), "skip by /**/cache");
+        assert_eq!(explain("svc/a.so"), "link by *.so");
+        assert_eq!(explain("svc/a.conf"), "copy by /**");
+    }
+
+    #[test]
+    fn replicate_attrs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "link *.so\ncopy /**\n").unwrap();
+        let then =
+            std::time::UNIX_EPOCH + std::time::Duration::new(1e9 as u64, 5);
+        let mode = |p: &Path, m: u32| {
+            std::fs::set_permissions(p, std::fs::Permissions::from_mode(m))
+                .unwrap()
+        };
+
+        let dir = src.path().join("lib");
+        std::fs::create_dir(&dir).unwrap();
+        let f = File::create(dir.join("a.conf")).unwrap();
+        f.set_modified(then).unwrap();
+        drop(f);
+        mode(&dir.join("a.conf"), 0o640);
+        File::create(dir.join("b.so"))
+            .unwrap()
+            .set_modified(then)
+            .unwrap();
+        std::os::unix::fs::symlink("b.so", dir.join("c.so")).unwrap();
+        mode(&dir, 0o750);
+        File::open(&dir).unwrap().set_modified(then).unwrap();
+
+        replicate(
+            src.path(),
+            dst.path(),
+            "/system/x",
+            &rules,
+            &Exclude::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            verify(
+                src.path(),
+                dst.path(),
+                "/system/x",
+                &rules,
+                &Exclude::default()
+            )
+            .unwrap(),
+            vec![]
+        );
+
+        let md = std::fs::metadata(dst.path().join("lib")).unwrap();
+        assert_eq!(md.mode() & 0o7777, 0o750);
+        assert_eq!(md.modified().unwrap(), then);
+        let md =
+            std::fs::symlink_metadata(dst.path().join("lib/b.so")).unwrap();
+        assert!(md.file_type().is_symlink());
+        assert_eq!(md.modified().unwrap(), then);
+
+        /*
+         * Removing the link also changes the modification time of the
+         * directory.
+         */
+        mode(&dst.path().join("lib/a.conf"), 0o644);
+        std::fs::remove_file(dst.path().join("lib/c.so")).unwrap();
+        let problems = verify(
+            src.path(),
+            dst.path(),
+            "/system/x",
+            &rules,
+            &Exclude::default(),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|m| m.problem)
+        .collect::<Vec<_>>();
+        assert_eq!(problems.len(), 3);
+        assert!(problems.contains(&"mode 644, expected 640".to_string()));
+        assert!(problems.contains(&"missing".to_string()));
+    }
+
+    #[test]
+    fn replicate_store() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "copy /**\n").unwrap();
+        let mut store =
+            Store::open(dst.path().join("store"), Lock::Shared).unwrap();
+
+        std::fs::write(src.path().join("a"), "data").unwrap();
+
+        for zone in ["z1", "z2"] {
+            let target = dst.path().join(zone);
+            std::fs::create_dir(&target).unwrap();
+            let cs = replicate(
+                src.path(),
+                &target,
+                "/x",
+                &rules,
+                &Exclude::default(),
+                Some(&mut store),
+            )
+            .unwrap();
+            assert_eq!(cs.links, if zone == "z1" { 0 } else { 1 });
+        }
+
+        let md = std::fs::metadata(dst.path().join("z1/a")).unwrap();
+        assert_eq!(md.nlink(), 3);
+        assert_eq!(
+            md.ino(),
+            std::fs::metadata(dst.path().join("z2/a")).unwrap().ino(),
+        );
+    }
+
+    #[test]
+    fn replicate_exclude() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let rules = Rules::parse("test", "link *.so\ncopy /**\n").unwrap();
+        let p = |rel: &str| src.path().join(rel);
+
+        for d in ["gz/sub", "mixed", "manifest/a"] {
+            std::fs::create_dir_all(p(d)).unwrap();
+        }
+        for f in ["gz/a.so", "gz/sub/b", "mixed/c", "mixed/d", "manifest/a/e"] {
+            std::fs::write(p(f), "data").unwrap();
+        }
+
+        let mut exclude = Exclude::default();
+        for o in ["gz", "gz/a.so", "gz/sub", "gz/sub/b", "mixed", "mixed/c"] {
+            exclude.object(p(o));
+        }
+        exclude.subtree(p("manifest"));
+
+        let cs =
+            replicate(src.path(), dst.path(), "/x", &rules, &exclude, None)
+                .unwrap();
+        assert_eq!(cs.pruned, 8);
+        assert_eq!(cs.files, 1);
+
+        /*
+         * A directory that is only partly excluded is still replicated.
+         */
+        let q = |rel: &str| dst.path().join(rel).symlink_metadata().is_ok();
+        assert!(!q("gz") && !q("manifest") && !q("mixed/c"));
+        assert!(q("mixed/d"));
+        assert_eq!(
+            verify(src.path(), dst.path(), "/x", &rules, &exclude).unwrap(),
+            vec![],
+        );
+    }
 }